@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE box_template (
+    id uuid NOT NULL PRIMARY KEY,
+    name text NOT NULL,
+    price int NOT NULL,
+    original_price int NOT NULL,
+    created_at timestamp NOT NULL
+);
+
+CREATE TABLE box_template_products (
+    id uuid NOT NULL PRIMARY KEY,
+    template_id uuid NOT NULL,
+    CONSTRAINT fk_template_id FOREIGN KEY (template_id) REFERENCES box_template(id) ON DELETE CASCADE,
+    title text NOT NULL,
+    description text NOT NULL,
+    level int NOT NULL,
+    amount int NOT NULL,
+    image text NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE listing ADD COLUMN status text NOT NULL DEFAULT 'live';
+CREATE INDEX listing_created_at_idx ON listing (created_at DESC, id);
+CREATE INDEX box_listing_id_idx ON box (listing_id);
+CREATE INDEX products_box_id_idx ON products (box_id);
@@ -0,0 +1,63 @@
+-- Add migration script here
+-- Search document of every listing: its title, category name, product titles and description
+CREATE TABLE listing_search (
+    listing_id uuid NOT NULL PRIMARY KEY,
+    CONSTRAINT fk_listing_search_listing_id FOREIGN KEY (listing_id) REFERENCES listing (id) ON DELETE CASCADE,
+    document tsvector NOT NULL
+);
+
+CREATE INDEX listing_search_document_idx ON listing_search USING GIN (document);
+
+CREATE FUNCTION refresh_listing_search(p_listing_id uuid) RETURNS void AS $$
+    INSERT INTO listing_search (listing_id, document)
+    SELECT l.id,
+        setweight(to_tsvector('simple', l.title), 'A') ||
+        setweight(to_tsvector('simple', coalesce(c.name, '')), 'B') ||
+        setweight(to_tsvector('simple', coalesce((
+            SELECT string_agg(p.title, ' ') FROM products p
+            INNER JOIN box b ON b.id = p.box_id
+            WHERE b.listing_id = l.id
+        ), '')), 'C') ||
+        setweight(to_tsvector('simple', l.description), 'D')
+    FROM listing l
+    LEFT JOIN category c ON c.id = l.category_id
+    WHERE l.id = p_listing_id
+    ON CONFLICT (listing_id) DO UPDATE SET document = EXCLUDED.document;
+$$ LANGUAGE sql;
+
+CREATE FUNCTION listing_search_listing_trigger() RETURNS trigger AS $$
+BEGIN
+    PERFORM refresh_listing_search(NEW.id);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER listing_search_listing AFTER INSERT OR UPDATE OF title, description, category_id ON listing
+    FOR EACH ROW EXECUTE FUNCTION listing_search_listing_trigger();
+
+CREATE FUNCTION listing_search_products_trigger() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        PERFORM refresh_listing_search(b.listing_id) FROM box b WHERE b.id = OLD.box_id;
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        PERFORM refresh_listing_search(b.listing_id) FROM box b WHERE b.id = NEW.box_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER listing_search_products AFTER INSERT OR DELETE OR UPDATE OF title, box_id ON products
+    FOR EACH ROW EXECUTE FUNCTION listing_search_products_trigger();
+
+CREATE FUNCTION listing_search_category_trigger() RETURNS trigger AS $$
+BEGIN
+    PERFORM refresh_listing_search(l.id) FROM listing l WHERE l.category_id = NEW.id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER listing_search_category AFTER UPDATE OF name ON category
+    FOR EACH ROW EXECUTE FUNCTION listing_search_category_trigger();
+
+SELECT refresh_listing_search(id) FROM listing;
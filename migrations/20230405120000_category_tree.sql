@@ -0,0 +1,17 @@
+-- Add migration script here
+ALTER TABLE category ADD COLUMN parent_id uuid;
+ALTER TABLE category ADD COLUMN slug text;
+ALTER TABLE category ADD COLUMN position int NOT NULL DEFAULT 0;
+ALTER TABLE category ADD CONSTRAINT fk_category_parent_id FOREIGN KEY (parent_id) REFERENCES category (id);
+
+-- Slugs of existing categories come from their names, duplicates get a part of the id
+UPDATE category SET slug = trim(both '-' from regexp_replace(lower(name), '[^a-z0-9]+', '-', 'g'));
+UPDATE category c SET slug = concat_ws('-', nullif(c.slug, ''), left(c.id::text, 8))
+    WHERE c.slug = '' OR EXISTS (SELECT 1 FROM category o WHERE o.slug = c.slug AND o.id < c.id);
+ALTER TABLE category ALTER COLUMN slug SET NOT NULL;
+ALTER TABLE category ADD CONSTRAINT category_slug_key UNIQUE (slug);
+
+UPDATE listing SET category_id = NULL
+    WHERE category_id IS NOT NULL AND category_id NOT IN (SELECT id FROM category);
+ALTER TABLE listing ADD CONSTRAINT fk_listing_category_id FOREIGN KEY (category_id) REFERENCES category (id) ON DELETE SET NULL;
+CREATE INDEX listing_category_id_idx ON listing (category_id);
@@ -0,0 +1,27 @@
+-- Add migration script here
+CREATE TABLE tag (
+    id uuid NOT NULL PRIMARY KEY,
+    name text NOT NULL,
+    slug text NOT NULL UNIQUE,
+    kind text NOT NULL,
+    created_at timestamp NOT NULL
+);
+
+CREATE TABLE listing_tags (
+    listing_id uuid NOT NULL,
+    tag_id uuid NOT NULL,
+    PRIMARY KEY (listing_id, tag_id),
+    CONSTRAINT fk_listing_tags_listing_id FOREIGN KEY (listing_id) REFERENCES listing (id) ON DELETE CASCADE,
+    CONSTRAINT fk_listing_tags_tag_id FOREIGN KEY (tag_id) REFERENCES tag (id) ON DELETE CASCADE
+);
+
+CREATE TABLE product_tags (
+    product_id uuid NOT NULL,
+    tag_id uuid NOT NULL,
+    PRIMARY KEY (product_id, tag_id),
+    CONSTRAINT fk_product_tags_product_id FOREIGN KEY (product_id) REFERENCES products (id) ON DELETE CASCADE,
+    CONSTRAINT fk_product_tags_tag_id FOREIGN KEY (tag_id) REFERENCES tag (id) ON DELETE CASCADE
+);
+
+CREATE INDEX listing_tags_tag_id_idx ON listing_tags (tag_id);
+CREATE INDEX product_tags_tag_id_idx ON product_tags (tag_id);
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- tty used to be free text, normalise it before it is constrained. Listings with
+-- a type we don't know were sold as ichiban kuji.
+UPDATE listing SET tty = upper(trim(tty));
+UPDATE listing SET tty = 'ICH' WHERE tty NOT IN ('ICH', 'HEX');
+ALTER TABLE listing ADD CONSTRAINT listing_tty_check CHECK (tty IN ('ICH', 'HEX'));
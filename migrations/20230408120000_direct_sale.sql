@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE products ADD COLUMN price int;
+
+ALTER TABLE listing DROP CONSTRAINT listing_tty_check;
+ALTER TABLE listing ADD CONSTRAINT listing_tty_check CHECK (tty IN ('ICH', 'HEX', 'DIRECT'));
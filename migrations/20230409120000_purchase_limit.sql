@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE purchase_limit (
+    id uuid NOT NULL PRIMARY KEY,
+    listing_id uuid UNIQUE,
+    box_id uuid UNIQUE,
+    max_per_box int,
+    max_per_day int,
+    cooldown_seconds int,
+    created_at timestamp NOT NULL,
+    CONSTRAINT fk_purchase_limit_listing_id FOREIGN KEY (listing_id) REFERENCES listing (id) ON DELETE CASCADE,
+    CONSTRAINT fk_purchase_limit_box_id FOREIGN KEY (box_id) REFERENCES box (id) ON DELETE CASCADE,
+    CONSTRAINT purchase_limit_target_check CHECK ((listing_id IS NULL) <> (box_id IS NULL))
+);
+
+CREATE INDEX products_owned_user_id_idx ON products_owned (user_id);
@@ -0,0 +1,16 @@
+-- Add migration script here
+ALTER TABLE box ADD COLUMN claim_seconds int;
+
+CREATE TABLE box_queue (
+    id uuid NOT NULL PRIMARY KEY,
+    box_id uuid NOT NULL,
+    user_id uuid NOT NULL,
+    joined_at timestamp NOT NULL,
+    claimed_at timestamp,
+    expires_at timestamp,
+    UNIQUE (box_id, user_id),
+    CONSTRAINT fk_box_queue_box_id FOREIGN KEY (box_id) REFERENCES box (id) ON DELETE CASCADE,
+    CONSTRAINT fk_box_queue_user_id FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX box_queue_box_id_idx ON box_queue (box_id, joined_at);
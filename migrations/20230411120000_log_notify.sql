@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE FUNCTION notify_log() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('logs', row_to_json(NEW)::text);
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER logs_notify AFTER INSERT ON logs
+FOR EACH ROW EXECUTE FUNCTION notify_log();
+
+CREATE INDEX logs_created_at_idx ON logs (created_at);
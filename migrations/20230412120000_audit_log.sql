@@ -0,0 +1,21 @@
+-- Add migration script here
+ALTER TABLE logs
+    ADD COLUMN event text,
+    ADD COLUMN entity_type text,
+    ADD COLUMN entity_id uuid,
+    ADD COLUMN before jsonb,
+    ADD COLUMN after jsonb,
+    ADD COLUMN request_id uuid,
+    ADD COLUMN ip text;
+
+CREATE INDEX logs_user_id_idx ON logs (user_id, created_at);
+CREATE INDEX logs_entity_idx ON logs (entity_type, entity_id, created_at);
+
+-- Snapshots can be larger than a notification payload may be, so only the id is
+-- sent and listeners load the log themselves
+CREATE OR REPLACE FUNCTION notify_log() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('logs', NEW.id::text);
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
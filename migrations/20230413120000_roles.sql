@@ -0,0 +1,50 @@
+-- Add migration script here
+CREATE TABLE role (
+    id uuid NOT NULL PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    created_at timestamp NOT NULL
+);
+
+CREATE TABLE role_permissions (
+    role_id uuid NOT NULL REFERENCES role(id) ON DELETE CASCADE,
+    permission text NOT NULL,
+    PRIMARY KEY (role_id, permission)
+);
+
+CREATE TABLE user_roles (
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id uuid NOT NULL REFERENCES role(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE INDEX user_roles_role_id_idx ON user_roles (role_id);
+
+INSERT INTO role (id, name, created_at) VALUES
+    (gen_random_uuid(), 'owner', now()),
+    (gen_random_uuid(), 'catalogue_editor', now()),
+    (gen_random_uuid(), 'fulfilment', now()),
+    (gen_random_uuid(), 'support', now());
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.permission FROM role r
+CROSS JOIN unnest(ARRAY[
+    'edit_catalogue', 'manage_orders', 'view_users', 'grant_points', 'grant_any_points',
+    'view_logs', 'manage_roles'
+]) AS p(permission)
+WHERE r.name = 'owner';
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT id, 'edit_catalogue' FROM role WHERE name = 'catalogue_editor';
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT id, 'manage_orders' FROM role WHERE name = 'fulfilment';
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.permission FROM role r
+CROSS JOIN unnest(ARRAY['view_users', 'grant_points']) AS p(permission)
+WHERE r.name = 'support';
+
+-- Superusers keep full access as owners
+INSERT INTO user_roles (user_id, role_id)
+SELECT u.id, r.id FROM users u CROSS JOIN role r
+WHERE u.is_superuser AND r.name = 'owner';
@@ -0,0 +1,24 @@
+-- Add migration script here
+ALTER TABLE users
+    ADD COLUMN status text NOT NULL DEFAULT 'active',
+    ADD COLUMN suspended_until timestamp,
+    ADD COLUMN status_reason text,
+    ADD CONSTRAINT users_status_check CHECK (status IN ('active', 'suspended', 'banned'));
+
+CREATE INDEX users_created_at_idx ON users (created_at, id);
+
+-- Every change of a user's points, `balance` is their points after the change
+CREATE TABLE points_ledger (
+    id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    delta int NOT NULL,
+    balance int NOT NULL,
+    reason text NOT NULL,
+    reference_id uuid,
+    created_at timestamp NOT NULL
+);
+
+CREATE INDEX points_ledger_user_id_idx ON points_ledger (user_id, created_at);
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT id, 'manage_users' FROM role WHERE name = 'owner';
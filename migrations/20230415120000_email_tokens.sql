@@ -0,0 +1,18 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN email_verified boolean NOT NULL DEFAULT false;
+
+-- Users who registered before emails were verified keep buying
+UPDATE users SET email_verified = true;
+
+-- Single-use tokens mailed to users, only their SHA-256 hash is stored
+CREATE TABLE user_tokens (
+    id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind text NOT NULL CHECK (kind IN ('verify_email', 'reset_password')),
+    token_hash text NOT NULL UNIQUE,
+    expires_at timestamp NOT NULL,
+    used_at timestamp,
+    created_at timestamp NOT NULL
+);
+
+CREATE INDEX user_tokens_user_id_idx ON user_tokens (user_id, kind);
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- Emails and usernames are unique ignoring case, new emails are stored lowercased
+
+-- Accounts whose email or username only differs by case from an older account's get the
+-- id appended, so the older account keeps it and the unique indexes can be built. The
+-- changed emails have to be verified again.
+UPDATE users u SET email = regexp_replace(u.email, '(@|$)', '+' || u.id || '\1'),
+    email_verified = false
+WHERE EXISTS (
+    SELECT 1 FROM users o WHERE lower(o.email) = lower(u.email)
+    AND (o.created_at, o.id) < (u.created_at, u.id)
+);
+UPDATE users u SET username = u.username || '_' || u.id
+WHERE EXISTS (
+    SELECT 1 FROM users o WHERE lower(o.username) = lower(u.username)
+    AND (o.created_at, o.id) < (u.created_at, u.id)
+);
+
+CREATE UNIQUE INDEX users_email_lower_idx ON users (lower(email));
+CREATE UNIQUE INDEX users_username_lower_idx ON users (lower(username));
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Failed sign-ins in a row, after enough of them the account is locked until `locked_until`
+ALTER TABLE users
+    ADD COLUMN failed_logins int NOT NULL DEFAULT 0,
+    ADD COLUMN locked_until timestamp;
@@ -0,0 +1,21 @@
+-- Add migration script here
+-- `totp_secret` is set when enrolment starts, `totp_enabled` once a code confirmed it.
+-- `totp_last_step` is the time step of the last code used, codes can't be used twice.
+ALTER TABLE users
+    ADD COLUMN totp_secret text,
+    ADD COLUMN totp_enabled boolean NOT NULL DEFAULT false,
+    ADD COLUMN totp_last_step bigint;
+
+-- Only the SHA-256 hash of the normalized code is stored
+CREATE TABLE backup_codes (
+    id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash text NOT NULL,
+    used_at timestamp,
+    created_at timestamp NOT NULL
+);
+
+CREATE INDEX backup_codes_user_id_idx ON backup_codes (user_id);
+
+-- The permissions of a role which requires two-factor only apply to users who enabled it
+ALTER TABLE role ADD COLUMN require_two_factor boolean NOT NULL DEFAULT false;
@@ -0,0 +1,22 @@
+-- Add migration script here
+-- Sign-ins started with a provider. Only the SHA-256 hash of the state is stored.
+CREATE TABLE oauth_states (
+    state_hash text NOT NULL PRIMARY KEY,
+    provider text NOT NULL,
+    nonce text NOT NULL,
+    verifier text NOT NULL,
+    expires_at timestamp NOT NULL,
+    created_at timestamp NOT NULL
+);
+
+-- Accounts at providers which sign in as a user. `subject` is the provider's id of the account.
+CREATE TABLE user_identities (
+    provider text NOT NULL,
+    subject text NOT NULL,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email text,
+    created_at timestamp NOT NULL,
+    PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
@@ -0,0 +1,17 @@
+-- Add migration script here
+-- Personal access tokens, sent as `Authorization: Bearer`. Only the SHA-256 hash of a
+-- token is stored, `prefix` is its start so users can tell their tokens apart.
+CREATE TABLE access_tokens (
+    id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name text NOT NULL,
+    scopes text[] NOT NULL,
+    token_hash text NOT NULL UNIQUE,
+    prefix text NOT NULL,
+    expires_at timestamp NOT NULL,
+    last_used_at timestamp,
+    revoked_at timestamp,
+    created_at timestamp NOT NULL
+);
+
+CREATE INDEX access_tokens_user_id_idx ON access_tokens (user_id);
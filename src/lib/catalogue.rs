@@ -0,0 +1,375 @@
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    database::actions::{DatabaseHand, Pool},
+    error::ApiError,
+    models::{
+        Box, CatalogueRow, ImportReport, Listing, ListingStatus, ListingType, Product, RowError,
+    },
+    web::ReqId,
+};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatalogueFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for CatalogueFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            other => Err(format!("Unknown catalogue format {other}")),
+        }
+    }
+}
+
+/// A validated listing ready to be inserted, with the row that declared it.
+#[derive(Debug, Clone)]
+pub struct ImportListing {
+    pub row: usize,
+    pub listing: Listing,
+    pub image: String,
+    pub tags: Vec<String>,
+    pub boxes: Vec<ImportBox>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportBox {
+    pub row: usize,
+    pub label: String,
+    pub bx: Box,
+    pub products: Vec<ImportProduct>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportProduct {
+    pub row: usize,
+    pub product: Product,
+    pub tags: Vec<String>,
+}
+
+fn row_error(row: usize, field: &str, message: impl Into<String>) -> RowError {
+    RowError {
+        row,
+        field: field.to_owned(),
+        message: message.into(),
+    }
+}
+
+/// What groups rows into one listing, the `listing_key` or else the title
+fn listing_key(row: &CatalogueRow) -> (bool, &str) {
+    match row.listing_key.as_deref() {
+        Some(key) if !key.is_empty() => (true, key),
+        _ => (false, &row.listing),
+    }
+}
+
+/// The slugs of a comma separated tag list, sorted so the same tags compare equal
+fn tag_slugs(tags: Option<&str>) -> Vec<String> {
+    let mut slugs = tags
+        .unwrap_or_default()
+        .split(',')
+        .map(|slug| slug.trim().to_lowercase())
+        .filter(|slug| !slug.is_empty())
+        .collect::<Vec<_>>();
+    slugs.sort();
+    slugs.dedup();
+    slugs
+}
+
+/// Parse the raw rows, every row which can't be parsed is reported instead of
+/// stopping at the first one.
+pub fn parse(format: CatalogueFormat, data: &str) -> Result<Vec<CatalogueRow>, Vec<RowError>> {
+    let mut rows = vec![];
+    let mut errors = vec![];
+    match format {
+        CatalogueFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(data.as_bytes());
+            let headers = reader.headers().cloned().unwrap_or_default();
+            for (i, record) in reader.deserialize::<CatalogueRow>().enumerate() {
+                match record {
+                    Ok(row) => rows.push(row),
+                    Err(e) => match e.kind() {
+                        // Point at the column instead of only the record when csv knows it
+                        csv::ErrorKind::Deserialize { err, .. } => {
+                            let field = err
+                                .field()
+                                .and_then(|f| headers.get(f as usize))
+                                .unwrap_or_default();
+                            errors.push(row_error(i + 1, field, err.kind().to_string()))
+                        }
+                        _ => errors.push(row_error(i + 1, "", e.to_string())),
+                    },
+                }
+            }
+        }
+        CatalogueFormat::Json => match serde_json::from_str::<Vec<serde_json::Value>>(data) {
+            Ok(values) => {
+                for (i, value) in values.into_iter().enumerate() {
+                    match serde_json::from_value::<CatalogueRow>(value) {
+                        Ok(row) => rows.push(row),
+                        Err(e) => errors.push(row_error(i + 1, "", e.to_string())),
+                    }
+                }
+            }
+            Err(e) => errors.push(row_error(0, "", e.to_string())),
+        },
+    }
+    match errors.is_empty() {
+        true => Ok(rows),
+        false => Err(errors),
+    }
+}
+
+/// Group the rows into listings, boxes and products and check that rows which
+/// describe the same listing or box agree with each other. Listings with a row in
+/// error are left out, the others are still returned so they can be counted.
+pub fn validate(rows: &[CatalogueRow]) -> (Vec<ImportListing>, Vec<RowError>) {
+    let mut listings: Vec<(CatalogueRow, ImportListing)> = vec![];
+    let mut errors = vec![];
+    let now = Utc::now().naive_utc();
+
+    for (i, row) in rows.iter().enumerate() {
+        let n = i + 1;
+        if row.listing.is_empty() {
+            errors.push(row_error(n, "listing", "Listing title is required."));
+            continue;
+        }
+        let tty = match ListingType::from_str(&row.tty) {
+            Ok(tty) => tty.as_str().to_owned(),
+            Err(_) => {
+                errors.push(row_error(n, "tty", format!("Unknown listing type {}.", row.tty)));
+                row.tty.clone()
+            }
+        };
+        let status = match row.status.as_deref() {
+            Some(status) if !status.is_empty() => match ListingStatus::from_str(status) {
+                Ok(status) => status,
+                Err(_) => {
+                    errors.push(row_error(
+                        n,
+                        "status",
+                        format!("Unknown listing status {status}."),
+                    ));
+                    ListingStatus::Live
+                }
+            },
+            _ => ListingStatus::Live,
+        };
+        let tags = tag_slugs(row.tags.as_deref());
+        if row.image.is_empty() {
+            errors.push(row_error(n, "image", "Listing image is required."));
+        }
+
+        let key = listing_key(row);
+        let listing = match listings
+            .iter_mut()
+            .find(|(first, _)| listing_key(first) == key)
+        {
+            Some((first, listing)) => {
+                if first.listing != row.listing
+                    || !first.tty.eq_ignore_ascii_case(&row.tty)
+                    || listing.listing.status != status.as_str()
+                    || first.description != row.description
+                    || first.category_id != row.category_id
+                    || first.image != row.image
+                    || listing.tags != tags
+                {
+                    errors.push(row_error(
+                        n,
+                        "listing",
+                        format!("Listing details conflict with row {}.", listing.row),
+                    ));
+                }
+                listing
+            }
+            None => {
+                let listing = ImportListing {
+                    row: n,
+                    listing: Listing {
+                        image: String::new(),
+                        boxes: vec![],
+                        id: Uuid::new_v4(),
+                        title: row.listing.clone(),
+                        description: row.description.clone(),
+                        category_id: row.category_id,
+                        created_at: now,
+                        box_count: 0,
+                        tty,
+                        status: status.as_str().to_owned(),
+                        tags: vec![],
+                    },
+                    image: row.image.clone(),
+                    tags,
+                    boxes: vec![],
+                };
+                listings.push((row.clone(), listing));
+                &mut listings.last_mut().unwrap().1
+            }
+        };
+
+        let label = match &row.box_label {
+            Some(label) if !label.is_empty() => label,
+            _ => {
+                if row.product.is_some() {
+                    errors.push(row_error(n, "box", "A product needs a box."));
+                }
+                continue;
+            }
+        };
+        let (price, original_price) = match (row.price, row.original_price) {
+            (Some(price), Some(original_price)) => (price, original_price),
+            (None, _) => {
+                errors.push(row_error(n, "price", "Box price is required."));
+                continue;
+            }
+            (_, None) => {
+                errors.push(row_error(n, "original_price", "Box original price is required."));
+                continue;
+            }
+        };
+        let listing_id = listing.listing.id;
+        let import_box = match listing.boxes.iter_mut().find(|b| &b.label == label) {
+            Some(import_box) => {
+                if import_box.bx.price != price || import_box.bx.original_price != original_price {
+                    errors.push(row_error(
+                        n,
+                        "price",
+                        format!("Box price conflicts with row {}.", import_box.row),
+                    ));
+                }
+                import_box
+            }
+            None => {
+                listing.boxes.push(ImportBox {
+                    row: n,
+                    label: label.clone(),
+                    bx: Box {
+                        id: Uuid::new_v4(),
+                        price,
+                        original_price,
+                        listing_id,
+                        created_at: now,
+                        products: vec![],
+                        total: 0,
+                        available_products: 0,
+                        claim_seconds: None,
+                    },
+                    products: vec![],
+                });
+                listing.boxes.last_mut().unwrap()
+            }
+        };
+
+        let title = match &row.product {
+            Some(title) if !title.is_empty() => title,
+            _ => continue,
+        };
+        let level = match row.level {
+            Some(level) => level,
+            None => {
+                errors.push(row_error(n, "level", "Product level is required."));
+                continue;
+            }
+        };
+        let amount = match row.amount {
+            Some(amount) if amount > 0 && amount <= i32::MAX as u32 => amount as i32,
+            _ => {
+                errors.push(row_error(n, "amount", "Product amount must be at least 1."));
+                continue;
+            }
+        };
+        if row.product_price.is_none() && row.tty.eq_ignore_ascii_case(ListingType::Direct.as_str()) {
+            errors.push(row_error(
+                n,
+                "product_price",
+                "Products of a direct-sale listing need a price.",
+            ));
+            continue;
+        }
+        import_box.products.push(ImportProduct {
+            row: n,
+            product: Product {
+                id: Uuid::new_v4(),
+                box_id: import_box.bx.id,
+                title: title.clone(),
+                description: row.product_description.clone().unwrap_or_default(),
+                level,
+                status: false,
+                created_at: now,
+                amount,
+                available: amount,
+                image: row.product_image.clone().unwrap_or_default(),
+                ini_amount: amount,
+                price: row.product_price,
+            },
+            tags: tag_slugs(row.product_tags.as_deref()),
+        });
+    }
+
+    let failed = errors
+        .iter()
+        .filter_map(|e| rows.get(e.row.wrapping_sub(1)))
+        .map(listing_key)
+        .collect::<Vec<_>>();
+    let listings = listings
+        .into_iter()
+        .filter(|(first, _)| !failed.contains(&listing_key(first)))
+        .map(|(_, listing)| listing)
+        .collect();
+    (listings, errors)
+}
+
+/// Serialize exported rows in the same shape `parse` reads them.
+pub fn write(format: CatalogueFormat, rows: &[CatalogueRow]) -> Result<String, String> {
+    match format {
+        CatalogueFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for row in rows {
+                writer.serialize(row).map_err(|e| e.to_string())?;
+            }
+            let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+            String::from_utf8(bytes).map_err(|e| e.to_string())
+        }
+        CatalogueFormat::Json => serde_json::to_string_pretty(rows).map_err(|e| e.to_string()),
+    }
+}
+
+/// Parse, validate and import a catalogue. Parsing and validation errors are
+/// returned in the report, only database failures are returned as `ApiError`.
+pub async fn import(
+    pool: &Pool,
+    format: CatalogueFormat,
+    data: &str,
+    commit: bool,
+    req_id: ReqId,
+) -> Result<ImportReport, ApiError> {
+    match parse(format, data) {
+        Ok(rows) => {
+            let (listings, errors) = validate(&rows);
+            DatabaseHand::import_catalogue(pool, (listings, errors, commit, req_id)).await
+        }
+        Err(errors) => Ok(ImportReport {
+            committed: false,
+            listings: 0,
+            boxes: 0,
+            products: 0,
+            errors,
+        }),
+    }
+}
+
+pub async fn export(pool: &Pool, format: CatalogueFormat) -> Result<String, ApiError> {
+    let rows = DatabaseHand::export_catalogue(pool).await?;
+    write(format, &rows).map_err(ApiError::ExportError)
+}
@@ -1,21 +1,47 @@
 use crate::{
+    catalogue::{ImportListing, ImportProduct},
     error::ApiError,
-    models::{Amount, Box, Listing, LogData, Order, Product, ProductIdent, ResponseUser, User, AddressData, Category},
-    web::{ImageData, ReqId, SignIn},
+    models::{
+        AccessToken, AddressData, Amount, AuditEvent, BackupCodes, Box, BoxStock, BoxTemplate,
+        CatalogueRow, Category, CategoryNode, ImportReport, LedgerEntry, LedgerReason, Listing,
+        ListingStatus, ListingSummary, ListingType, LogData, NewAccessToken, Order, OwnedPrize,
+        POINT_GRANT_LIMIT, Page, Permission, PrizeRemoval, Product, ProductIdent, ProfileUpdate,
+        PurchaseLimit, QueueStatus, ResponseUser, Role, RowError, SearchResult, Tag, TagKind,
+        TierStock, TokenKind, TwoFactorSetup, User, UserDetail, UserStatus, UserSummary,
+    },
+    oauth::{AuthRequest, Identity},
+    totp,
+    web::{
+        request, validation::Validator, ImageData, ListingQuery, LogQuery, LogStreamQuery, ReqId,
+        SearchQuery, SignIn, UserQuery,
+    },
 };
-use chrono::Utc;
-use futures::TryFutureExt;
+use chrono::{Duration, NaiveDateTime, Utc};
 use rand::Rng;
-use sqlx::pool;
-use std::sync::Arc;
-use tower_cookies::Cookies;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgExecutor};
+use std::{collections::HashMap, sync::OnceLock};
 use uuid::Uuid;
 pub type Pool = sqlx::Pool<sqlx::postgres::Postgres>;
 use crate::database::models::{
-    Box as DBox, Listing as DListing, Product as DProduct, User as DBUser,
+    AccessToken as DAccessToken, Box as DBox, BoxTemplate as DBoxTemplate,
+    LedgerEntry as DLedgerEntry, Listing as DListing, Log as DLog, Product as DProduct,
+    PurchaseLimit as DPurchaseLimit, Role as DRole, TemplateProduct as DTemplateProduct,
+    User as DBUser, UserSummary as DUserSummary,
 };
 
 const BASE_URL: &str = "http://localhost:3000";
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+/// Failed sign-ins in a row after which an account is locked
+const LOCKOUT_THRESHOLD: i32 = 5;
+/// The first lock lasts this long, every further failure doubles it up to `LOCKOUT_MAX`
+const LOCKOUT_BASE_SECONDS: i64 = 30;
+const LOCKOUT_MAX_SECONDS: i64 = 24 * 60 * 60;
+/// Access tokens start with this, so they are easy to spot in code and logs
+const ACCESS_TOKEN_PREFIX: &str = "ichi_";
+/// How long users have to come back from a provider
+pub const OAUTH_STATE_MINUTES: i64 = 10;
 
 /// This struct handles all the database queries.
 pub struct DatabaseHand;
@@ -24,12 +50,15 @@ pub struct DatabaseHand;
 type DResult<T> = Result<T, ApiError>;
 
 impl DatabaseHand {
-    pub async fn check_listing_tty(pool: &Pool, id: &Uuid) -> DResult<String> {
+    pub async fn check_listing_tty(pool: &Pool, id: &Uuid) -> DResult<ListingType> {
         let pool = pool.clone();
         let listing = sqlx::query!("SELECT tty FROM listing WHERE id = $1", id)
             .fetch_one(&pool)
             .await?;
-        Ok(listing.tty)
+        listing
+            .tty
+            .parse()
+            .map_err(|_| ApiError::UnknownListingType)
     }
     pub async fn get_user_from_private_key(
         pool: &Pool,
@@ -38,7 +67,8 @@ impl DatabaseHand {
         let pool = pool.clone();
         let user = sqlx::query_as!(
             DBUser,
-            "SELECT username, email, id, created_at, points, is_superuser, address  FROM users WHERE private_key = $1",
+            "SELECT username, email, id, created_at, points, is_superuser, address, email_verified,
+            totp_enabled AS two_factor_enabled FROM users WHERE private_key = $1",
             private_key
         )
         .fetch_one(&pool)
@@ -46,26 +76,48 @@ impl DatabaseHand {
         let mut user: ResponseUser = user.into();
         user.orders = DatabaseHand::get_orders(&pool, &user.id).await?;
         user.owned_products = DatabaseHand::get_owned_products(&pool, &user.id).await?;
+        user.permissions = DatabaseHand::get_user_permissions(&pool, &user.id).await?;
         Ok(user)
     }
     pub async fn create_user(pool: &Pool, user: &User) -> DResult<ResponseUser> {
         let pool = pool.clone();
-        let user = user.clone();
+        let User { profile, secrets } = user.clone();
+        let mut tx = pool.begin().await?;
+        DatabaseHand::check_user_unique(
+            &mut tx,
+            Some(&profile.email),
+            Some(&profile.username),
+            None,
+        )
+        .await?;
         sqlx::query!(
             "INSERT INTO users(username, email, password, id, created_at, points, is_superuser, private_key)
         VALUES($1, $2, $3, $4, $5, $6, $7, $8)",
-            user.username,
-            user.email,
-            user.password,
-            user.id,
-            user.created_at,
-            user.points as i32,
-            user.is_superuser,
-            user.private_key
+            profile.username,
+            profile.email,
+            secrets.password,
+            profile.id,
+            profile.created_at,
+            profile.points as i32,
+            profile.is_superuser,
+            secrets.private_key
         )
-        .execute(&pool)
+        .execute(&mut tx)
         .await?;
-        Ok(user.into())
+        let user: ResponseUser = profile.into();
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user.id,
+                AuditEvent::UserRegistered,
+                Some(user.id),
+                format!("User {} registered", user.id),
+            )
+            .after(&user),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(user)
     }
 
     pub async fn get_private_key(pool: &Pool, id: &Uuid) -> DResult<Uuid> {
@@ -78,19 +130,116 @@ impl DatabaseHand {
         Ok(private_key)
     }
 
+    // Unknown emails and wrong passwords give the same error, and take as long to check.
+    // Locked accounts are refused without checking the password.
     pub async fn sign_in(pool: &Pool, signin: &SignIn) -> DResult<ResponseUser> {
         let pool = pool.clone();
-        let password = sqlx::query!("SELECT password from users WHERE email = $1", signin.email)
-            .fetch_one(&pool)
+        let user = sqlx::query!(
+            "SELECT id, password, locked_until FROM users WHERE lower(email) = lower($1)",
+            signin.email.trim()
+        )
+        .fetch_optional(&pool)
+        .await?;
+        let (id, password, locked_until) = match user {
+            Some(user) => (Some(user.id), user.password, user.locked_until),
+            None => (None, dummy_password_hash().to_owned(), None),
+        };
+        // A locked account answers like a wrong password once the hash was checked, so
+        // neither the answer nor its timing tells it from an unknown email
+        let locked = matches!(locked_until, Some(until) if until > Utc::now().naive_utc());
+
+        match (id, bcrypt::verify(&signin.password, &password)) {
+            (Some(_), _) if locked => Err(ApiError::InvalidCredentials),
+            (Some(id), Ok(true)) => {
+                DatabaseHand::check_user_active(&pool, &id).await?;
+                if !DatabaseHand::check_sign_in_code(&pool, &id, signin.code.as_deref()).await? {
+                    DatabaseHand::record_failed_login(&pool, &id).await?;
+                    return Err(ApiError::InvalidTwoFactorCode);
+                }
+                sqlx::query!(
+                    "UPDATE users SET failed_logins = 0, locked_until = NULL WHERE id = $1",
+                    id
+                )
+                .execute(&pool)
+                .await?;
+                DatabaseHand::get_user(&pool, id).await
+            }
+            (Some(id), _) => {
+                DatabaseHand::record_failed_login(&pool, &id).await?;
+                Err(ApiError::InvalidCredentials)
+            }
+            (None, _) => Err(ApiError::InvalidCredentials),
+        }
+    }
+
+    // Whether the sign-in code is right, users without two-factor authentication need none
+    async fn check_sign_in_code(pool: &Pool, id: &Uuid, code: Option<&str>) -> DResult<bool> {
+        let enabled = sqlx::query!("SELECT totp_enabled FROM users WHERE id = $1", id)
+            .fetch_one(pool)
             .await?
-            .password;
+            .totp_enabled;
+        match (enabled, code) {
+            (false, _) => Ok(true),
+            (true, None) => Err(ApiError::TwoFactorRequired),
+            (true, Some(code)) => {
+                let mut tx = pool.begin().await?;
+                let valid = DatabaseHand::check_two_factor(&mut tx, id, code).await?;
+                tx.commit().await?;
+                Ok(valid)
+            }
+        }
+    }
 
-        match bcrypt::verify(signin.password.clone(), &password) {
-            Ok(true) => DatabaseHand::get_user_email(&pool, &signin.email).await,
-            Err(_) | Ok(false) => Err(ApiError::IncorrectPassword(
-                bcrypt::BcryptError::InvalidHash("Invalid Password".to_owned()),
-            )),
+    // Count a failed sign-in, locking the account once there were `LOCKOUT_THRESHOLD` in a
+    // row. Every failure after that locks it twice as long.
+    async fn record_failed_login(pool: &Pool, id: &Uuid) -> DResult<()> {
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let failed_logins = sqlx::query!(
+            "UPDATE users SET failed_logins = failed_logins + 1 WHERE id = $1 RETURNING failed_logins",
+            id
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .failed_logins;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                *id,
+                AuditEvent::LoginFailed,
+                Some(*id),
+                format!("Failed sign-in {failed_logins} in a row for user {id}"),
+            ),
+        )
+        .await?;
+
+        if failed_logins >= LOCKOUT_THRESHOLD {
+            let doublings = (failed_logins - LOCKOUT_THRESHOLD).min(32) as u32;
+            let seconds = LOCKOUT_BASE_SECONDS
+                .saturating_mul(2_i64.saturating_pow(doublings))
+                .min(LOCKOUT_MAX_SECONDS);
+            let locked_until = Utc::now().naive_utc() + Duration::seconds(seconds);
+            sqlx::query!(
+                "UPDATE users SET locked_until = $1 WHERE id = $2",
+                locked_until,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+            DatabaseHand::add_log(
+                &mut tx,
+                LogData::new(
+                    *id,
+                    AuditEvent::AccountLocked,
+                    Some(*id),
+                    format!("User {id} locked for {seconds} seconds"),
+                )
+                .after(&json!({ "failed_logins": failed_logins, "locked_until": locked_until })),
+            )
+            .await?;
         }
+        tx.commit().await?;
+        Ok(())
     }
 
     // Get all user's owned product's ids from owned_products table
@@ -113,7 +262,8 @@ impl DatabaseHand {
         let pool = pool.clone();
         let users = sqlx::query_as!(
             DBUser,
-            "SELECT username, email, id, created_at, points, is_superuser, address from users"
+            "SELECT username, email, id, created_at, points, is_superuser, address, email_verified,
+            totp_enabled AS two_factor_enabled from users"
         )
         .fetch_all(&pool)
         .await?;
@@ -123,6 +273,7 @@ impl DatabaseHand {
             let mut user: ResponseUser = user.into();
             user.points = points;
             user.owned_products = DatabaseHand::get_owned_products(&pool, &user.id).await?;
+            user.permissions = DatabaseHand::get_user_permissions(&pool, &user.id).await?;
             final_users.push(user);
         }
         Ok(final_users)
@@ -132,8 +283,9 @@ impl DatabaseHand {
         let pool = pool.clone();
         let mut user: ResponseUser = sqlx::query_as!(
             DBUser,
-            "SELECT username, email, id, created_at, points, is_superuser, address from users WHERE email = $1",
-            email.clone()
+            "SELECT username, email, id, created_at, points, is_superuser, address, email_verified,
+            totp_enabled AS two_factor_enabled from users WHERE email = $1",
+            email
         )
         .fetch_one(&pool)
         .await?
@@ -143,13 +295,15 @@ impl DatabaseHand {
         user.points = points;
         user.owned_products = DatabaseHand::get_owned_products(&pool, &user.id).await?;
         user.orders = DatabaseHand::get_orders(&pool, &user.id).await?;
+        user.permissions = DatabaseHand::get_user_permissions(&pool, &user.id).await?;
         Ok(user)
     }
     pub async fn get_user(pool: &Pool, id: Uuid) -> DResult<ResponseUser> {
         let pool = pool.clone();
         let mut user: ResponseUser = sqlx::query_as!(
             DBUser,
-            "SELECT username, email, id, created_at, points, is_superuser, address from users WHERE id = $1",
+            "SELECT username, email, id, created_at, points, is_superuser, address, email_verified,
+            totp_enabled AS two_factor_enabled from users WHERE id = $1",
             id.clone()
         )
         .fetch_one(&pool)
@@ -160,6 +314,7 @@ impl DatabaseHand {
         user.points = points;
         user.orders = DatabaseHand::get_orders(&pool, &user.id).await?;
         user.owned_products = DatabaseHand::get_owned_products(&pool, &user.id).await?;
+        user.permissions = DatabaseHand::get_user_permissions(&pool, &user.id).await?;
         Ok(user)
     }
 
@@ -173,708 +328,4223 @@ impl DatabaseHand {
         Ok(points as u32)
     }
 
-    pub async fn add_coins(pool: &Pool, amount: &Amount) -> DResult<ResponseUser> {
+    // Grant points to a user. Grants above `POINT_GRANT_LIMIT` need `GrantAnyPoints`.
+    pub async fn add_coins(pool: &Pool, data: (Amount, ReqId)) -> DResult<ResponseUser> {
+        let (amount, req_id) = data;
         let pool = pool.clone();
-        let existing_coins = DatabaseHand::get_user_points(&pool, &amount.user_id).await?;
+        let permission = match amount.points > POINT_GRANT_LIMIT {
+            true => Permission::GrantAnyPoints,
+            false => Permission::GrantPoints,
+        };
+        let allowed = DatabaseHand::confirm_permission(&pool, &req_id, permission).await?
+            || DatabaseHand::confirm_permission(&pool, &req_id, Permission::GrantAnyPoints).await?;
+        if !allowed {
+            return Err(ApiError::MissingPermission(permission));
+        }
+        let mut tx = pool.begin().await?;
+        let existing_coins = sqlx::query!(
+            "SELECT points FROM users WHERE id = $1 FOR UPDATE",
+            amount.user_id
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .points as u32;
         let total = existing_coins + amount.points;
         sqlx::query!(
             "UPDATE users SET points = $1 WHERE id = $2",
             total as i32,
             amount.user_id
         )
-        .execute(&pool)
+        .execute(&mut tx)
         .await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                req_id.id,
+                AuditEvent::PointsAdded,
+                Some(amount.user_id),
+                format!("{} points added to user {}", amount.points, amount.user_id),
+            )
+            .before(&json!({ "points": existing_coins }))
+            .after(&json!({ "points": total })),
+        )
+        .await?;
+        DatabaseHand::add_ledger_entry(
+            &mut tx,
+            LedgerEntry {
+                reference_id: Some(req_id.id),
+                ..LedgerEntry::new(amount.user_id, amount.points as i32, total, LedgerReason::Grant)
+            },
+        )
+        .await?;
+        tx.commit().await?;
 
         let user = DatabaseHand::get_user(&pool, amount.user_id).await?;
         Ok(user)
     }
 
-    pub async fn get_image(pool: &Pool, id: &Uuid) -> DResult<String> {
-        let pool = pool.clone();
-        let image = sqlx::query!("SELECT for_id FROM images WHERE for_id = $1", id.clone())
-            .fetch_one(&pool)
-            .await?;
-        Ok(image.for_id.to_string())
-    }
-    pub async fn get_listing(pool: &Pool) -> DResult<Vec<Listing>> {
-        let mut final_listings: Vec<Listing> = vec![];
-        let pool = pool.clone();
-        let listings = sqlx::query_as!(DListing, "SELECT * FROM listing")
-            .fetch_all(&pool)
-            .await?;
-        for listing in listings {
-            let mut listing: Listing = listing.into();
-            let listing_image = DatabaseHand::get_image(&pool, &listing.id).await?;
-            let ed_img = listing_image.split('/').collect::<Vec<_>>();
-            listing.image = BASE_URL.to_owned() + "/get/image/" + ed_img.last().unwrap();
-            // listing.image = listing_image;
-            let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing.id).await?;
-            listing.box_count = bxs.len() as u32;
-            listing.boxes = bxs;
-            final_listings.push(listing);
+    // Users can't sign in or buy while suspended or banned
+    pub async fn check_user_active<'e, E: PgExecutor<'e>>(executor: E, id: &Uuid) -> DResult<()> {
+        let user = sqlx::query!(
+            "SELECT status, suspended_until FROM users WHERE id = $1",
+            id
+        )
+        .fetch_one(executor)
+        .await?;
+        let now = Utc::now().naive_utc();
+        match user.status.parse() {
+            Ok(UserStatus::Banned) => Err(ApiError::UserBanned),
+            Ok(UserStatus::Suspended) => match user.suspended_until {
+                Some(until) if until <= now => Ok(()),
+                until => Err(ApiError::UserSuspended(until)),
+            },
+            _ => Ok(()),
         }
-        Ok(final_listings)
     }
 
-    pub async fn get_listing_ich(pool: &Pool) -> DResult<Vec<Listing>> {
-        let mut final_listings: Vec<Listing> = vec![];
-        let pool = pool.clone();
-        let listings = sqlx::query_as!(DListing, "SELECT * FROM listing WHERE tty = 'ICH'")
-            .fetch_all(&pool)
-            .await?;
-        for listing in listings {
-            let mut listing: Listing = listing.into();
-            let listing_image = DatabaseHand::get_image(&pool, &listing.id).await?;
-            let ed_img = listing_image.split('/').collect::<Vec<_>>();
-            listing.image = BASE_URL.to_owned() + "/get/image/" + ed_img.last().unwrap();
-            let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing.id).await?;
-            listing.box_count = bxs.len() as u32;
-            listing.boxes = bxs;
-            final_listings.push(listing);
-        }
-        Ok(final_listings)
+    pub async fn add_ledger_entry<'e, E: PgExecutor<'e>>(
+        executor: E,
+        entry: LedgerEntry,
+    ) -> DResult<()> {
+        sqlx::query!(
+            "INSERT INTO points_ledger(id, user_id, delta, balance, reason, reference_id, created_at)
+            VALUES($1, $2, $3, $4, $5, $6, $7)",
+            entry.id,
+            entry.user_id,
+            entry.delta,
+            entry.balance as i32,
+            entry.reason.as_str(),
+            entry.reference_id,
+            entry.created_at
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
     }
 
-    pub async fn get_listing_hex(pool: &Pool) -> DResult<Vec<Listing>> {
-        let mut final_listings: Vec<Listing> = vec![];
-        let pool = pool.clone();
-        let listings = sqlx::query_as!(DListing, "SELECT * FROM listing WHERE tty = 'HEX'")
-            .fetch_all(&pool)
-            .await?;
-        for listing in listings {
-            let mut listing: Listing = listing.into();
-            let listing_image = DatabaseHand::get_image(&pool, &listing.id).await?;
-            listing.image = listing_image;
-            let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing.id).await?;
-            listing.box_count = bxs.len() as u32;
-            listing.boxes = bxs;
-            final_listings.push(listing);
-        }
-        Ok(final_listings)
+    async fn load_user_summary<'e, E: PgExecutor<'e>>(
+        executor: E,
+        id: &Uuid,
+    ) -> DResult<UserSummary> {
+        let user = sqlx::query_as!(
+            DUserSummary,
+            "SELECT id, username, email, email_verified, created_at, points, address, status, suspended_until,
+            status_reason
+            FROM users WHERE id = $1",
+            id
+        )
+        .fetch_optional(executor)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        Ok(user.into())
     }
 
-    pub async fn get_boxes_of_listing(pool: &Pool, listing_id: &Uuid) -> DResult<Vec<Box>> {
-        let mut final_boxes = vec![];
+    // Get one page of users matching the query, newest first. The cursor is the creation
+    // time and id of the last user of the previous page.
+    pub async fn search_users(pool: &Pool, query: &UserQuery) -> DResult<Page<UserSummary>> {
         let pool = pool.clone();
-        let boxes = sqlx::query_as!(DBox, "SELECT * FROM box WHERE listing_id = $1", listing_id)
-            .fetch_all(&pool)
-            .await?;
+        let limit = query.limit.unwrap_or(50).clamp(1, 500) as i64;
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let (created_at, id) = cursor.split_once('_').ok_or(ApiError::InvalidCursor)?;
+                let created_at = NaiveDateTime::parse_from_str(created_at, CURSOR_TIME_FORMAT)
+                    .map_err(|_| ApiError::InvalidCursor)?;
+                let id = Uuid::parse_str(id).map_err(|_| ApiError::InvalidCursor)?;
+                Some((created_at, id))
+            }
+            None => None,
+        };
+        let q = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+        let users = sqlx::query_as!(
+            DUserSummary,
+            "SELECT id, username, email, email_verified, created_at, points, address, status, suspended_until,
+            status_reason
+            FROM users
+            WHERE ($1::text IS NULL
+                OR strpos(lower(email), lower($1)) > 0
+                OR strpos(lower(username), lower($1)) > 0
+                OR id::text = lower($1))
+            AND ($2::text IS NULL OR status = $2)
+            AND ($3::timestamp IS NULL OR (created_at, id) < ($3, $4::uuid))
+            ORDER BY created_at DESC, id DESC
+            LIMIT $5",
+            q,
+            query.status.map(|s| s.as_str()),
+            cursor.map(|(created_at, _)| created_at),
+            cursor.map(|(_, id)| id),
+            // One extra row tells us whether there is a next page
+            limit + 1
+        )
+        .fetch_all(&pool)
+        .await?;
 
-        for b in boxes {
-            let mut b: Box = b.into();
-            let products = DatabaseHand::get_products(&pool, &b.id).await?;
-            b.total = products.len() as u32;
-            let pro = products
-                .iter()
-                .filter(|p| !p.status)
-                .cloned()
-                .collect::<Vec<_>>();
-            b.available_products = pro.len() as u32;
-            b.products = pro;
-            final_boxes.push(b);
-        }
-        Ok(final_boxes)
+        let next_cursor = match users.len() as i64 > limit {
+            true => users.get(limit as usize - 1).map(|u| {
+                format!("{}_{}", u.created_at.format(CURSOR_TIME_FORMAT), u.id)
+            }),
+            false => None,
+        };
+        let items = users
+            .into_iter()
+            .take(limit as usize)
+            .map(|u| u.into())
+            .collect();
+        Ok(Page { items, next_cursor })
     }
 
-    pub async fn get_products(pool: &Pool, box_id: &Uuid) -> DResult<Vec<Product>> {
-        let mut final_products = vec![];
+    // Get a user with their roles, orders, prizes and points ledger, newest first
+    pub async fn get_user_detail(pool: &Pool, id: &Uuid) -> DResult<UserDetail> {
         let pool = pool.clone();
-        let products =
-            sqlx::query_as!(DProduct, "SELECT * FROM products WHERE box_id = $1", box_id)
-                .fetch_all(&pool)
-                .await?;
-
-        for pro in products {
-            let product: Product = pro.into();
-
-            final_products.push(product);
-        }
-
-        Ok(final_products)
+        let user = DatabaseHand::load_user_summary(&pool, id).await?;
+        let roles = DatabaseHand::get_user_roles(&pool, id).await?;
+        let orders = sqlx::query_as!(
+            Order,
+            "SELECT * FROM order_tracking WHERE user_id = $1 ORDER BY created_at DESC",
+            id
+        )
+        .fetch_all(&pool)
+        .await?;
+        let prizes = sqlx::query!(
+            "SELECT o.id, o.product_id, o.bought_at, p.title, p.level, p.image
+            FROM products_owned o INNER JOIN products p ON p.id = o.product_id
+            WHERE o.user_id = $1 ORDER BY o.bought_at DESC",
+            id
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|p| OwnedPrize {
+            id: p.id,
+            product_id: p.product_id,
+            title: p.title,
+            level: p.level as u32,
+            image: p.image,
+            bought_at: p.bought_at,
+        })
+        .collect();
+        let ledger = sqlx::query_as!(
+            DLedgerEntry,
+            "SELECT * FROM points_ledger WHERE user_id = $1 ORDER BY created_at DESC",
+            id
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|e| e.into())
+        .collect();
+        Ok(UserDetail {
+            user,
+            roles,
+            orders,
+            prizes,
+            ledger,
+        })
     }
 
-    async fn confirm_user_privilege(pool: &Pool, id: &ReqId) -> DResult<bool> {
-        let pool = pool.clone();
-        let is_superuser_rec = sqlx::query!("SELECT is_superuser FROM users WHERE id = $1", id.id)
-            .fetch_one(&pool)
-            .await?;
-        match is_superuser_rec.is_superuser {
-            true => Ok(true),
-            false => Err(ApiError::NotSuperuser),
-        }
-    }
-    pub async fn create_listing(
+    // Suspend, ban or reinstate a user. Suspending or banning also ends their session and
+    // revokes their access tokens, reinstating lifts a sign-in lockout. `granted` are the
+    // permissions of the actor's session or access token.
+    pub async fn set_user_status(
         pool: &Pool,
-        data: (Listing, ReqId, ImageData),
-    ) -> DResult<Vec<Listing>> {
+        data: (
+            Uuid,
+            UserStatus,
+            Option<NaiveDateTime>,
+            Option<String>,
+            ReqId,
+            Vec<Permission>,
+        ),
+    ) -> DResult<UserSummary> {
+        let (user_id, status, until, reason, req_id, granted) = data;
         let pool = pool.clone();
-        match DatabaseHand::confirm_user_privilege(&pool, &data.1).await {
-            Ok(true) => {
+        match granted.contains(&Permission::ManageUsers) {
+            true => {
+                DatabaseHand::check_not_outranked(&pool, &granted, &user_id).await?;
+                let mut tx = pool.begin().await?;
+                let before = DatabaseHand::load_user_summary(&mut tx, &user_id).await?;
                 sqlx::query!(
-                    "INSERT INTO listing (title, created_at, id, tty, description, category_id) VALUES($1, $2, $3, $4, $5, $6)",
-                    &data.0.title,
-                    &data.0.created_at,
-                    &data.0.id,
-                    &data.0.tty,
-                    &data.0.description,
-                    data.0.category_id
+                    "UPDATE users SET status = $1, suspended_until = $2, status_reason = $3,
+                    private_key = CASE WHEN $1 = 'active' THEN private_key ELSE $4 END,
+                    failed_logins = CASE WHEN $1 = 'active' THEN 0 ELSE failed_logins END,
+                    locked_until = CASE WHEN $1 = 'active' THEN NULL ELSE locked_until END
+                    WHERE id = $5",
+                    status.as_str(),
+                    until,
+                    reason,
+                    Uuid::new_v4(),
+                    user_id
                 )
-                .execute(&pool)
+                .execute(&mut tx)
                 .await?;
-                sqlx::query!(
-                    "INSERT INTO images (path, for_id, extension) VALUES($1, $2, $3)",
-                    data.2.path,
-                    data.2.id,
-                    data.2.ext
+                if status != UserStatus::Active {
+                    DatabaseHand::revoke_user_access_tokens(&mut tx, &user_id).await?;
+                }
+                let after = DatabaseHand::load_user_summary(&mut tx, &user_id).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::UserStatusChanged,
+                        Some(user_id),
+                        format!("User {user_id} is now {}", status.as_str()),
+                    )
+                    .before(&before)
+                    .after(&after),
                 )
-                .execute(&pool)
                 .await?;
-                let listings = DatabaseHand::get_listing(&pool).await?;
-                Ok(listings)
+                tx.commit().await?;
+                Ok(after)
             }
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
+            false => Err(ApiError::MissingPermission(Permission::ManageUsers)),
         }
     }
 
-    pub async fn create_box(pool: &Pool, data: (Box, Vec<Product>, ReqId)) -> DResult<Vec<Box>> {
-        let (bx, prods, req_id) = data;
+    // A user holding permissions the actor lacks can't be managed by them, unless the
+    // actor can manage roles and so could grant themselves those permissions anyway
+    async fn check_not_outranked(
+        pool: &Pool,
+        granted: &[Permission],
+        user_id: &Uuid,
+    ) -> DResult<()> {
+        if granted.contains(&Permission::ManageRoles) {
+            return Ok(());
+        }
+        // Roles count even while suspended, banned or waiting on two factor
+        let held = sqlx::query!(
+            "SELECT DISTINCT rp.permission FROM user_roles ur
+            INNER JOIN role_permissions rp ON rp.role_id = ur.role_id
+            WHERE ur.user_id = $1",
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+        match held
+            .iter()
+            .filter_map(|p| p.permission.parse().ok())
+            .find(|p| !granted.contains(p))
+        {
+            Some(missing) => Err(ApiError::MissingPermission(missing)),
+            None => Ok(()),
+        }
+    }
+
+    // Emails and usernames can only be used by one user, ignoring case. `except` is the user
+    // being edited.
+    async fn check_user_unique(
+        conn: &mut PgConnection,
+        email: Option<&str>,
+        username: Option<&str>,
+        except: Option<Uuid>,
+    ) -> DResult<()> {
+        let taken = sqlx::query!(
+            r#"SELECT
+            EXISTS(SELECT 1 FROM users WHERE lower(email) = lower($1) AND id IS DISTINCT FROM $3)
+                AS "email!",
+            EXISTS(SELECT 1 FROM users WHERE lower(username) = lower($2) AND id IS DISTINCT FROM $3)
+                AS "username!""#,
+            email,
+            username,
+            except
+        )
+        .fetch_one(conn)
+        .await?;
+        Validator::new()
+            .check("email", taken.email.then(|| "Email is already registered.".to_owned()))
+            .check("username", taken.username.then(|| "Username is already taken.".to_owned()))
+            .finish()
+    }
+
+    // Change the username, email or address of a user. `granted` are the permissions of
+    // the actor's session or access token.
+    pub async fn update_user(
+        pool: &Pool,
+        data: (Uuid, ProfileUpdate, ReqId, Vec<Permission>),
+    ) -> DResult<UserSummary> {
+        let (user_id, update, req_id, granted) = data;
         let pool = pool.clone();
-        match DatabaseHand::confirm_user_privilege(&pool, &req_id).await {
-            Ok(true) => {
+        match granted.contains(&Permission::ManageUsers) {
+            true => {
+                // A new email lets the account be taken over with a password reset, so
+                // it can't be changed on a user with permissions the actor lacks
+                if update.email.is_some() {
+                    DatabaseHand::check_not_outranked(&pool, &granted, &user_id).await?;
+                }
+                let mut tx = pool.begin().await?;
+                let before = DatabaseHand::load_user_summary(&mut tx, &user_id).await?;
+                DatabaseHand::check_user_unique(
+                    &mut tx,
+                    update.email.as_deref(),
+                    update.username.as_deref(),
+                    Some(user_id),
+                )
+                .await?;
+                // A new email has to be verified again
                 sqlx::query!(
-                    "INSERT INTO box (id, price, listing_id, created_at, original_price) VALUES ($1, $2, $3, $4, $5) ",
-                    bx.id,
-                    bx.price as i32,
-                    bx.listing_id,
-                    bx.created_at,
-                    bx.original_price as i32
+                    "UPDATE users SET username = COALESCE($1, username), email = COALESCE($2, email),
+                    address = COALESCE($3, address),
+                    email_verified = email_verified AND ($2::text IS NULL OR $2 = email)
+                    WHERE id = $4",
+                    update.username,
+                    update.email,
+                    update.address,
+                    user_id
                 )
-                .execute(&pool)
+                .execute(&mut tx)
                 .await?;
-                for prod in prods {
-                    sqlx::query!(
-                        "INSERT INTO products
-                    (box_id, title, id, description, level, status, created_at, amount, image, ini_amount)
-                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
-                        // Remember that prod.box_id is a temporary id so we have
-                        // to use `bx.id`
-                        bx.id,
-                        prod.title,
-                        prod.id,
-                        prod.description,
-                        prod.level as i32,
-                        prod.status,
-                        prod.created_at,
-                        prod.amount,
-                        prod.image,
-                        prod.ini_amount
+                let after = DatabaseHand::load_user_summary(&mut tx, &user_id).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::UserUpdated,
+                        Some(user_id),
+                        format!("User {user_id} edited"),
                     )
-                    .execute(&pool)
-                    .await?;
-                }
-
-                let bxs = DatabaseHand::get_boxes_of_listing(&pool, &bx.listing_id).await?;
-                Ok(bxs)
+                    .before(&before)
+                    .after(&after),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(after)
             }
-
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
+            false => Err(ApiError::MissingPermission(Permission::ManageUsers)),
         }
     }
 
-    // Deletion
-
-    pub async fn get_single_listing(pool: &Pool, listing_id: &Uuid) -> DResult<Listing> {
+    // Get a user as they see themselves, for support. Nothing is changed but the view
+    // is logged.
+    pub async fn view_as_user(pool: &Pool, data: (Uuid, Uuid)) -> DResult<ResponseUser> {
+        let (user_id, viewer_id) = data;
         let pool = pool.clone();
-
-        let mut listing: Listing =
-            sqlx::query_as!(DListing, "SELECT * FROM listing WHERE id = $1", listing_id)
-                .fetch_one(&pool)
-                .await?
-                .into();
-        let listing_image = DatabaseHand::get_image(&pool, listing_id).await?;
-        listing.image = listing_image;
-        let bxs = DatabaseHand::get_boxes_of_listing(&pool, listing_id).await?;
-        listing.box_count = bxs.len() as u32;
-        listing.boxes = bxs;
-        Ok(listing)
+        let user = DatabaseHand::get_user(&pool, user_id).await?;
+        DatabaseHand::add_log(
+            &pool,
+            LogData::new(
+                viewer_id,
+                AuditEvent::UserImpersonated,
+                Some(user_id),
+                format!("Viewed the site as user {user_id}"),
+            ),
+        )
+        .await?;
+        Ok(user)
     }
 
-    pub async fn delete_box(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Listing> {
+    // Buying needs a verified email
+    pub async fn check_email_verified(pool: &Pool, id: &Uuid) -> DResult<()> {
         let pool = pool.clone();
-        match DatabaseHand::confirm_user_privilege(&pool, &data.1).await {
-            Ok(true) => {
-                // Delete it's products
-                let id = sqlx::query!("SELECT listing_id FROM box WHERE id = $1", &data.0)
-                    .fetch_one(&pool)
-                    .await?;
-                sqlx::query!("DELETE FROM products WHERE box_id = $1", &data.0)
-                    .execute(&pool)
-                    .await?;
-
-                // Delete Box
-                sqlx::query!("DELETE FROM box where id = $1", &data.0)
-                    .execute(&pool)
-                    .await?;
-
-                let listing = DatabaseHand::get_single_listing(&pool, &id.listing_id).await?;
-                DatabaseHand::add_log(
-                    &pool,
-                    LogData {
-                        user_id: data.clone().1.id,
-                        id: Uuid::new_v4(),
-                        created_at: Utc::now().naive_utc(),
-                        action: "Box deleted".to_owned(),
-                    },
-                )
-                .await?;
-                Ok(listing)
-            }
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
+        let user = sqlx::query!("SELECT email_verified FROM users WHERE id = $1", id)
+            .fetch_one(&pool)
+            .await?;
+        match user.email_verified {
+            true => Ok(()),
+            false => Err(ApiError::EmailNotVerified),
         }
     }
 
-    pub async fn delete_listing(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Listing>> {
-        let (listing_id, req_id) = data;
+    // Create a token to mail to the user, earlier tokens of the same kind stop working.
+    // Only the hash of the token is stored.
+    pub async fn create_user_token(pool: &Pool, data: (Uuid, TokenKind)) -> DResult<String> {
+        let (user_id, kind) = data;
         let pool = pool.clone();
-        match DatabaseHand::confirm_user_privilege(&pool, &req_id).await {
-            Ok(true) => {
-                let box_ids = sqlx::query!("SELECT id FROM box WHERE listing_id = $1", listing_id)
-                    .fetch_all(&pool)
-                    .await?;
-                // sqlx::query!("DELETE FROM listi WHERE listing_id = $1", listing_id)
-                //     .execute(&pool)
-                //     .await?;
-                for box_id in box_ids {
-                    DatabaseHand::delete_box(&pool, (box_id.id, req_id.clone())).await?;
-
-                    // sqlx::query!("DELETE FROM products WHERE box_id = $1", box_id.id)
-                    //     .execute(&pool)
-                    //     .await?;
-                }
-
-                sqlx::query!("DELETE FROM listing WHERE id = $1 ", listing_id)
-                    .execute(&pool)
-                    .await?;
-                let listings = DatabaseHand::get_listing(&pool).await?;
+        let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let now = Utc::now().naive_utc();
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "UPDATE user_tokens SET used_at = $1 WHERE user_id = $2 AND kind = $3 AND used_at IS NULL",
+            now,
+            user_id,
+            kind.as_str()
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO user_tokens(id, user_id, kind, token_hash, expires_at, created_at)
+            VALUES($1, $2, $3, $4, $5, $6)",
+            Uuid::new_v4(),
+            user_id,
+            kind.as_str(),
+            hash_token(&token),
+            now + kind.lifetime(),
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    // Use up a token, returning the user it was made for
+    async fn consume_user_token(
+        conn: &mut PgConnection,
+        token: &str,
+        kind: TokenKind,
+    ) -> DResult<Uuid> {
+        let now = Utc::now().naive_utc();
+        let user_id = sqlx::query!(
+            "UPDATE user_tokens SET used_at = $1
+            WHERE token_hash = $2 AND kind = $3 AND used_at IS NULL AND expires_at > $1
+            RETURNING user_id",
+            now,
+            hash_token(token),
+            kind.as_str()
+        )
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::InvalidToken)?
+        .user_id;
+        Ok(user_id)
+    }
+
+    pub async fn verify_email(pool: &Pool, token: &str) -> DResult<ResponseUser> {
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let user_id =
+            DatabaseHand::consume_user_token(&mut tx, token, TokenKind::VerifyEmail).await?;
+        let email = sqlx::query!(
+            "UPDATE users SET email_verified = true WHERE id = $1 RETURNING email",
+            user_id
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .email;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::EmailVerified,
+                Some(user_id),
+                format!("User {user_id} verified {email}"),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        DatabaseHand::get_user(&pool, user_id).await
+    }
+
+    // Create a password reset token for the user with the email, ignoring case. Gives the
+    // email as stored with the token, `None` if there is no such user.
+    pub async fn request_password_reset(
+        pool: &Pool,
+        email: &str,
+    ) -> DResult<Option<(String, String)>> {
+        let pool = pool.clone();
+        let user = sqlx::query!(
+            "SELECT id, email FROM users WHERE lower(email) = lower($1)",
+            email
+        )
+        .fetch_optional(&pool)
+        .await?;
+        let (user_id, email) = match user {
+            Some(user) => (user.id, user.email),
+            None => return Ok(None),
+        };
+        let token =
+            DatabaseHand::create_user_token(&pool, (user_id, TokenKind::ResetPassword)).await?;
+        DatabaseHand::add_log(
+            &pool,
+            LogData::new(
+                user_id,
+                AuditEvent::PasswordResetRequested,
+                Some(user_id),
+                format!("Password reset requested for user {user_id}"),
+            ),
+        )
+        .await?;
+        Ok(Some((email, token)))
+    }
+
+    // Set a new password with a reset token. Signs the user out everywhere, revoking their
+    // access tokens, and as they got the mail, verifies their email.
+    pub async fn reset_password(pool: &Pool, data: (String, String)) -> DResult<()> {
+        let (token, password) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let user_id =
+            DatabaseHand::consume_user_token(&mut tx, &token, TokenKind::ResetPassword).await?;
+        sqlx::query!(
+            "UPDATE users SET password = $1, private_key = $2, email_verified = true, failed_logins = 0,
+            locked_until = NULL WHERE id = $3",
+            password,
+            Uuid::new_v4(),
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+        DatabaseHand::revoke_user_access_tokens(&mut tx, &user_id).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::PasswordReset,
+                Some(user_id),
+                format!("User {user_id} reset their password"),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Start enrolling a user in two-factor authentication with a new secret. Enrolment
+    // can be restarted until a code confirmed it.
+    pub async fn start_two_factor(pool: &Pool, user_id: &Uuid) -> DResult<TwoFactorSetup> {
+        let pool = pool.clone();
+        let user = sqlx::query!(
+            "SELECT email, totp_enabled FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        if user.totp_enabled {
+            return Err(ApiError::TwoFactorAlreadyEnabled);
+        }
+        let secret = totp::generate_secret();
+        let otpauth_uri = totp::provisioning_uri(&secret, &user.email)
+            .expect("Generated secrets are valid base32");
+        sqlx::query!(
+            "UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2",
+            secret,
+            user_id
+        )
+        .execute(&pool)
+        .await?;
+        Ok(TwoFactorSetup {
+            secret,
+            otpauth_uri,
+        })
+    }
+
+    // Finish enrolment with a code from the authenticator, returning the backup codes and
+    // a new private key. Sessions and access tokens made before were never asked for a
+    // code, so they are signed out and revoked.
+    pub async fn enable_two_factor(
+        pool: &Pool,
+        data: (Uuid, String),
+    ) -> DResult<(BackupCodes, Uuid)> {
+        let (user_id, code) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let user = sqlx::query!(
+            "SELECT totp_secret, totp_enabled FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        if user.totp_enabled {
+            return Err(ApiError::TwoFactorAlreadyEnabled);
+        }
+        let secret = user.totp_secret.ok_or(ApiError::TwoFactorNotStarted)?;
+        let step = totp::verify(&secret, &code, Utc::now().timestamp() as u64)
+            .ok_or(ApiError::InvalidTwoFactorCode)?;
+        let private_key = Uuid::new_v4();
+        sqlx::query!(
+            "UPDATE users SET totp_enabled = true, totp_last_step = $1, private_key = $2
+            WHERE id = $3",
+            step,
+            private_key,
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+        DatabaseHand::revoke_user_access_tokens(&mut tx, &user_id).await?;
+        let codes = DatabaseHand::replace_backup_codes(&mut tx, &user_id).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::TwoFactorEnabled,
+                Some(user_id),
+                format!("User {user_id} enabled two-factor authentication"),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok((codes, private_key))
+    }
+
+    // Turn two-factor authentication off, which needs a valid code
+    pub async fn disable_two_factor(pool: &Pool, data: (Uuid, String)) -> DResult<()> {
+        let (user_id, code) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        if !DatabaseHand::check_two_factor(&mut tx, &user_id, &code).await? {
+            return Err(ApiError::InvalidTwoFactorCode);
+        }
+        sqlx::query!(
+            "UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL
+            WHERE id = $1",
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM backup_codes WHERE user_id = $1", user_id)
+            .execute(&mut tx)
+            .await?;
+        DatabaseHand::check_role_managers(&mut tx).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::TwoFactorDisabled,
+                Some(user_id),
+                format!("User {user_id} disabled two-factor authentication"),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Replace the backup codes of a user, which needs a valid code
+    pub async fn regenerate_backup_codes(
+        pool: &Pool,
+        data: (Uuid, String),
+    ) -> DResult<BackupCodes> {
+        let (user_id, code) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        if !DatabaseHand::check_two_factor(&mut tx, &user_id, &code).await? {
+            return Err(ApiError::InvalidTwoFactorCode);
+        }
+        let codes = DatabaseHand::replace_backup_codes(&mut tx, &user_id).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::BackupCodesRegenerated,
+                Some(user_id),
+                format!("User {user_id} regenerated their backup codes"),
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    async fn replace_backup_codes(conn: &mut PgConnection, user_id: &Uuid) -> DResult<BackupCodes> {
+        sqlx::query!("DELETE FROM backup_codes WHERE user_id = $1", user_id)
+            .execute(&mut *conn)
+            .await?;
+        let codes = totp::generate_backup_codes();
+        let hashes = codes
+            .iter()
+            .map(|c| hash_token(&totp::normalize_backup_code(c)))
+            .collect::<Vec<_>>();
+        let ids = codes.iter().map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        sqlx::query!(
+            "INSERT INTO backup_codes(id, user_id, code_hash, created_at)
+            SELECT id, $2, code_hash, $3 FROM UNNEST($1::uuid[], $4::text[]) AS c(id, code_hash)",
+            &ids,
+            user_id,
+            Utc::now().naive_utc(),
+            &hashes
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(BackupCodes { codes })
+    }
+
+    // Check a code of a user with two-factor authentication enabled. An authenticator code
+    // has to be newer than the last one used, a backup code is used up.
+    async fn check_two_factor(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        code: &str,
+    ) -> DResult<bool> {
+        let user = sqlx::query!(
+            "SELECT totp_secret FROM users WHERE id = $1 AND totp_enabled FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::TwoFactorNotStarted)?;
+        let secret = user.totp_secret.unwrap_or_default();
+        if let Some(step) = totp::verify(&secret, code, Utc::now().timestamp() as u64) {
+            let updated = sqlx::query!(
+                "UPDATE users SET totp_last_step = $1
+                WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+                step,
+                user_id
+            )
+            .execute(&mut *conn)
+            .await?;
+            return Ok(updated.rows_affected() == 1);
+        }
+
+        let used = sqlx::query!(
+            "UPDATE backup_codes SET used_at = $1
+            WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL RETURNING id",
+            Utc::now().naive_utc(),
+            user_id,
+            hash_token(&totp::normalize_backup_code(code))
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if used.is_none() {
+            return Ok(false);
+        }
+        let left = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM backup_codes WHERE user_id = $1 AND used_at IS NULL"#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .count;
+        DatabaseHand::add_log(
+            conn,
+            LogData::new(
+                *user_id,
+                AuditEvent::BackupCodeUsed,
+                Some(*user_id),
+                format!("User {user_id} used a backup code, {left} left"),
+            ),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    // Keep what is needed to finish a sign-in with a provider once the user comes back
+    pub async fn create_oauth_state(pool: &Pool, data: (String, AuthRequest)) -> DResult<()> {
+        let (provider, request) = data;
+        let pool = pool.clone();
+        let now = Utc::now().naive_utc();
+        let mut tx = pool.begin().await?;
+        sqlx::query!("DELETE FROM oauth_states WHERE expires_at < $1", now)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO oauth_states(state_hash, provider, nonce, verifier, expires_at, created_at)
+            VALUES($1, $2, $3, $4, $5, $6)",
+            hash_token(&request.state),
+            provider,
+            request.nonce,
+            request.verifier,
+            now + Duration::minutes(OAUTH_STATE_MINUTES),
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Use up the state a provider sent the user back with, it works once
+    pub async fn consume_oauth_state(pool: &Pool, data: (String, String)) -> DResult<AuthRequest> {
+        let (provider, state) = data;
+        let pool = pool.clone();
+        let request = sqlx::query!(
+            "DELETE FROM oauth_states WHERE state_hash = $1 AND provider = $2 AND expires_at > $3
+            RETURNING nonce, verifier",
+            hash_token(&state),
+            provider,
+            Utc::now().naive_utc()
+        )
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(ApiError::InvalidToken)?;
+        Ok(AuthRequest {
+            state,
+            nonce: request.nonce,
+            verifier: request.verifier,
+        })
+    }
+
+    // Sign in with an identity from a provider. Identities seen before sign in as the user
+    // they were linked to. New ones are linked to the user with the same email, or to a new
+    // user, which needs the provider to have verified the email.
+    pub async fn oauth_sign_in(pool: &Pool, identity: &Identity) -> DResult<ResponseUser> {
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let linked = sqlx::query!(
+            "SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2",
+            identity.provider,
+            identity.subject
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let user_id = match linked {
+            Some(linked) => linked.user_id,
+            None => DatabaseHand::link_identity(&mut tx, identity).await?,
+        };
+        DatabaseHand::check_user_active(&mut tx, &user_id).await?;
+        let totp_enabled = sqlx::query!("SELECT totp_enabled FROM users WHERE id = $1", user_id)
+            .fetch_one(&mut tx)
+            .await?
+            .totp_enabled;
+        if totp_enabled {
+            return Err(ApiError::PasswordSignInRequired);
+        }
+        tx.commit().await?;
+        DatabaseHand::get_user(&pool, user_id).await
+    }
+
+    async fn link_identity(conn: &mut PgConnection, identity: &Identity) -> DResult<Uuid> {
+        let email = match (&identity.email, identity.email_verified) {
+            (Some(email), true) => email,
+            _ => return Err(ApiError::OAuthEmailUnverified),
+        };
+        let existing = sqlx::query!(
+            "SELECT id, email_verified FROM users WHERE lower(email) = lower($1)",
+            email
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        let now = Utc::now().naive_utc();
+        let user_id = match existing {
+            Some(user) => {
+                // Whoever registered with an email they never verified could be someone else,
+                // their password and sessions stop working
+                if !user.email_verified {
+                    sqlx::query!(
+                        "UPDATE users SET email_verified = true, password = $1, private_key = $2
+                        WHERE id = $3",
+                        unusable_password_hash()?,
+                        Uuid::new_v4(),
+                        user.id
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+                }
+                user.id
+            }
+            None => {
+                let username = DatabaseHand::free_username(&mut *conn, identity, email).await?;
+                let id = Uuid::new_v4();
+                sqlx::query!(
+                    "INSERT INTO users(username, email, password, id, created_at, points, is_superuser,
+                    private_key, email_verified) VALUES($1, $2, $3, $4, $5, 0, false, $6, true)",
+                    username,
+                    email,
+                    unusable_password_hash()?,
+                    id,
+                    now,
+                    Uuid::new_v4()
+                )
+                .execute(&mut *conn)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut *conn,
+                    LogData::new(
+                        id,
+                        AuditEvent::UserRegistered,
+                        Some(id),
+                        format!("User {id} registered with {}", identity.provider),
+                    ),
+                )
+                .await?;
+                id
+            }
+        };
+        sqlx::query!(
+            "INSERT INTO user_identities(provider, subject, user_id, email, created_at)
+            VALUES($1, $2, $3, $4, $5)",
+            identity.provider,
+            identity.subject,
+            user_id,
+            email,
+            now
+        )
+        .execute(&mut *conn)
+        .await?;
+        DatabaseHand::add_log(
+            conn,
+            LogData::new(
+                user_id,
+                AuditEvent::IdentityLinked,
+                Some(user_id),
+                format!(
+                    "{} account {} linked to user {user_id}",
+                    identity.provider, identity.subject
+                ),
+            ),
+        )
+        .await?;
+        Ok(user_id)
+    }
+
+    // A valid username nobody has, based on the provider's username or the email
+    async fn free_username(
+        conn: &mut PgConnection,
+        identity: &Identity,
+        email: &str,
+    ) -> DResult<String> {
+        let wanted = identity
+            .username
+            .as_deref()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+        let mut base = wanted
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+            .take(24)
+            .collect::<String>();
+        if base.len() < 3 {
+            base = "user".to_owned();
+        }
+        let mut username = base.clone();
+        loop {
+            let taken = sqlx::query!(
+                r#"SELECT EXISTS (SELECT 1 FROM users WHERE lower(username) = lower($1)) AS "taken!""#,
+                username
+            )
+            .fetch_one(&mut *conn)
+            .await?
+            .taken;
+            if !taken {
+                return Ok(username);
+            }
+            username = format!("{base}-{}", rand::thread_rng().gen_range(1000..10000));
+        }
+    }
+
+    // Create an access token for a user. Its scopes have to be permissions the user has.
+    pub async fn create_access_token(
+        pool: &Pool,
+        data: (Uuid, String, Vec<Permission>, i64),
+    ) -> DResult<NewAccessToken> {
+        let (user_id, name, mut scopes, days) = data;
+        let pool = pool.clone();
+        let permissions = DatabaseHand::get_user_permissions(&pool, &user_id).await?;
+        if let Some(missing) = scopes.iter().find(|s| !permissions.contains(s)) {
+            return Err(ApiError::MissingPermission(*missing));
+        }
+        scopes.sort_by_key(|s| s.as_str());
+        scopes.dedup();
+
+        let token = format!(
+            "{ACCESS_TOKEN_PREFIX}{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+        );
+        let now = Utc::now().naive_utc();
+        let access_token = AccessToken {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            scopes,
+            prefix: token[..ACCESS_TOKEN_PREFIX.len() + 6].to_owned(),
+            expires_at: now + Duration::days(days),
+            last_used_at: None,
+            created_at: now,
+        };
+        let mut tx = pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO access_tokens(id, user_id, name, scopes, token_hash, prefix, expires_at, created_at)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8)",
+            access_token.id,
+            user_id,
+            access_token.name,
+            &access_token
+                .scopes
+                .iter()
+                .map(|s| s.as_str().to_owned())
+                .collect::<Vec<_>>(),
+            hash_token(&token),
+            access_token.prefix,
+            access_token.expires_at,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::AccessTokenCreated,
+                Some(user_id),
+                format!("User {user_id} created the access token {}", access_token.name),
+            )
+            .after(&access_token),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(NewAccessToken {
+            token,
+            access_token,
+        })
+    }
+
+    // The access tokens of a user which weren't revoked, newest first
+    pub async fn get_access_tokens(pool: &Pool, user_id: &Uuid) -> DResult<Vec<AccessToken>> {
+        let pool = pool.clone();
+        let tokens = sqlx::query_as!(
+            DAccessToken,
+            "SELECT id, user_id, name, scopes, prefix, expires_at, last_used_at, created_at
+            FROM access_tokens WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at DESC",
+            user_id
+        )
+        .fetch_all(&pool)
+        .await?;
+        Ok(tokens.into_iter().map(|t| t.into()).collect())
+    }
+
+    // Revoke a token. Users revoke their own, other users' need `manage_users` in
+    // `granted`, the permissions of the session or access token.
+    pub async fn revoke_access_token(
+        pool: &Pool,
+        data: (Uuid, ReqId, Vec<Permission>),
+    ) -> DResult<()> {
+        let (token_id, req_id, granted) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let token = sqlx::query_as!(
+            DAccessToken,
+            "SELECT id, user_id, name, scopes, prefix, expires_at, last_used_at, created_at
+            FROM access_tokens WHERE id = $1 AND revoked_at IS NULL FOR UPDATE",
+            token_id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        if token.user_id != req_id.id && !granted.contains(&Permission::ManageUsers) {
+            return Err(ApiError::MissingPermission(Permission::ManageUsers));
+        }
+        sqlx::query!(
+            "UPDATE access_tokens SET revoked_at = $1 WHERE id = $2",
+            Utc::now().naive_utc(),
+            token_id
+        )
+        .execute(&mut tx)
+        .await?;
+        let token: AccessToken = token.into();
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                req_id.id,
+                AuditEvent::AccessTokenRevoked,
+                Some(token.user_id),
+                format!("Access token {} of user {} revoked", token.name, token.user_id),
+            )
+            .before(&token),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revoke_user_access_tokens(conn: &mut PgConnection, user_id: &Uuid) -> DResult<()> {
+        sqlx::query!(
+            "UPDATE access_tokens SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+            Utc::now().naive_utc(),
+            user_id
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    // The user an access token belongs to, with only the permissions which are also in the
+    // token's scopes
+    pub async fn get_user_from_access_token(pool: &Pool, token: &str) -> DResult<ResponseUser> {
+        let pool = pool.clone();
+        let now = Utc::now().naive_utc();
+        let access = sqlx::query!(
+            "UPDATE access_tokens SET last_used_at = $1
+            WHERE token_hash = $2 AND revoked_at IS NULL AND expires_at > $1
+            RETURNING user_id, scopes",
+            now,
+            hash_token(token)
+        )
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(ApiError::InvalidAccessToken)?;
+        DatabaseHand::check_user_active(&pool, &access.user_id).await?;
+        let mut user = DatabaseHand::get_user(&pool, access.user_id).await?;
+        user.permissions
+            .retain(|p| access.scopes.iter().any(|s| s == p.as_str()));
+        Ok(user)
+    }
+
+    pub async fn get_image(pool: &Pool, id: &Uuid) -> DResult<String> {
+        let pool = pool.clone();
+        let image = sqlx::query!("SELECT for_id FROM images WHERE for_id = $1", id.clone())
+            .fetch_one(&pool)
+            .await?;
+        Ok(image.for_id.to_string())
+    }
+    pub async fn get_listing(pool: &Pool) -> DResult<Vec<Listing>> {
+        let mut conn = pool.acquire().await?;
+        let listings = sqlx::query_as!(DListing, "SELECT * FROM listing")
+            .fetch_all(&mut conn)
+            .await?;
+        DatabaseHand::assemble_listings(&mut conn, listings).await
+    }
+
+    // Load listings with their images, boxes and products, in the same order as `ids`.
+    // The number of queries doesn't depend on how many listings are loaded.
+    pub async fn load_listings(conn: &mut PgConnection, ids: &[Uuid]) -> DResult<Vec<Listing>> {
+        let mut listings =
+            sqlx::query_as!(DListing, "SELECT * FROM listing WHERE id = ANY($1)", ids)
+                .fetch_all(&mut *conn)
+                .await?;
+        listings.sort_by_key(|l| ids.iter().position(|id| *id == l.id));
+        DatabaseHand::assemble_listings(conn, listings).await
+    }
+
+    async fn assemble_listings(
+        conn: &mut PgConnection,
+        listings: Vec<DListing>,
+    ) -> DResult<Vec<Listing>> {
+        let ids = listings.iter().map(|l| l.id).collect::<Vec<_>>();
+        let images = sqlx::query!("SELECT for_id FROM images WHERE for_id = ANY($1)", &ids)
+            .fetch_all(&mut *conn)
+            .await?;
+        let mut boxes = DatabaseHand::load_boxes(conn, &ids).await?;
+        let tags = sqlx::query!(
+            r#"SELECT lt.listing_id, t.id, t.name, t.slug, t.kind, t.created_at
+            FROM listing_tags lt INNER JOIN tag t ON t.id = lt.tag_id
+            WHERE lt.listing_id = ANY($1) ORDER BY t.kind, t.name"#,
+            &ids
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut final_listings: Vec<Listing> = vec![];
+        for listing in listings {
+            let mut listing: Listing = listing.into();
+            if let Some(image) = images.iter().find(|i| i.for_id == listing.id) {
+                listing.image = format!("{BASE_URL}/get/image/{}", image.for_id);
+            }
+            let bxs = boxes.remove(&listing.id).unwrap_or_default();
+            listing.box_count = bxs.len() as u32;
+            listing.boxes = bxs;
+            listing.tags = tags
+                .iter()
+                .filter(|t| t.listing_id == listing.id)
+                .map(|t| Tag {
+                    id: t.id,
+                    name: t.name.clone(),
+                    slug: t.slug.clone(),
+                    kind: t.kind.clone(),
+                    created_at: t.created_at,
+                })
+                .collect();
+            final_listings.push(listing);
+        }
+        Ok(final_listings)
+    }
+
+    // Aggregate the summaries of the given listings in one query, in the same order as `ids`
+    pub async fn load_listing_summaries(pool: &Pool, ids: &[Uuid]) -> DResult<Vec<ListingSummary>> {
+        let pool = pool.clone();
+        let rows = sqlx::query!(
+            r#"SELECT l.id, l.title, l.tty, l.category_id, l.created_at, l.status,
+                EXISTS (SELECT 1 FROM images i WHERE i.for_id = l.id) AS "has_image!",
+                COUNT(DISTINCT b.id) AS "box_count!",
+                MIN(COALESCE(p.price, b.price)) AS min_price,
+                MAX(COALESCE(p.price, b.price)) AS max_price,
+                COALESCE(SUM(p.ini_amount), 0) AS "total_tickets!",
+                COALESCE(SUM(p.amount) FILTER (WHERE NOT p.status), 0) AS "remaining_tickets!",
+                (SELECT tp.image FROM products tp
+                    INNER JOIN box tb ON tb.id = tp.box_id
+                    WHERE tb.listing_id = l.id
+                    ORDER BY tp.level, tp.created_at
+                    LIMIT 1) AS top_prize_image
+            FROM listing l
+            LEFT JOIN box b ON b.listing_id = l.id
+            LEFT JOIN products p ON p.box_id = b.id
+            WHERE l.id = ANY($1)
+            GROUP BY l.id"#,
+            ids
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let mut summaries = rows
+            .into_iter()
+            .map(|r| ListingSummary {
+                image: match r.has_image {
+                    true => format!("{BASE_URL}/get/image/{}", r.id),
+                    false => String::new(),
+                },
+                id: r.id,
+                title: r.title,
+                tty: r.tty,
+                category_id: r.category_id,
+                created_at: r.created_at,
+                status: r.status,
+                box_count: r.box_count as u32,
+                min_price: r.min_price.map(|p| p as u32),
+                max_price: r.max_price.map(|p| p as u32),
+                total_tickets: r.total_tickets as u32,
+                remaining_tickets: r.remaining_tickets as u32,
+                top_prize_image: r.top_prize_image,
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by_key(|l| ids.iter().position(|id| *id == l.id));
+        Ok(summaries)
+    }
+
+    // Turn the words of a search into a prefix query, "one pie" becomes "one:* & pie:*",
+    // so that results show up while the user is still typing
+    fn prefix_tsquery(q: &str) -> Option<String> {
+        let terms = q
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+            .map(|t| format!("{}:*", t.to_lowercase()))
+            .collect::<Vec<_>>();
+        match terms.is_empty() {
+            true => None,
+            false => Some(terms.join(" & ")),
+        }
+    }
+
+    // Full-text search over live listings ranked by relevance. Title matches weigh the
+    // most, then the category, product titles and finally the description.
+    pub async fn search_listings(pool: &Pool, query: &SearchQuery) -> DResult<Page<SearchResult>> {
+        let pool = pool.clone();
+        let limit = query.limit.unwrap_or(20).clamp(1, 100) as i64;
+        let tsquery = match DatabaseHand::prefix_tsquery(&query.q) {
+            Some(tsquery) => tsquery,
+            None => {
+                return Ok(Page {
+                    items: vec![],
+                    next_cursor: None,
+                })
+            }
+        };
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let (rank, id) = cursor.split_once('_').ok_or(ApiError::InvalidCursor)?;
+                let rank = rank.parse::<f32>().map_err(|_| ApiError::InvalidCursor)?;
+                let id = Uuid::parse_str(id).map_err(|_| ApiError::InvalidCursor)?;
+                Some((rank, id))
+            }
+            None => None,
+        };
+        let rows = sqlx::query!(
+            r#"WITH matches AS (
+                SELECT l.id, ts_rank(s.document, q.query) AS rank,
+                    ts_headline('simple', concat_ws('. ', l.title, (
+                        SELECT string_agg(DISTINCT p.title, ', ') FROM products p
+                        INNER JOIN box b ON b.id = p.box_id
+                        WHERE b.listing_id = l.id
+                    ), l.description), q.query,
+                        'StartSel=<b>, StopSel=</b>, MaxWords=30, MinWords=10') AS snippet
+                FROM listing_search s
+                INNER JOIN listing l ON l.id = s.listing_id
+                CROSS JOIN to_tsquery('simple', $1) AS q(query)
+                WHERE s.document @@ q.query AND l.status = 'live'
+            )
+            SELECT id AS "id!", rank AS "rank!", snippet AS "snippet!" FROM matches
+            WHERE $2::real IS NULL OR rank < $2 OR (rank = $2 AND id > $3::uuid)
+            ORDER BY rank DESC, id
+            LIMIT $4"#,
+            tsquery,
+            cursor.map(|(rank, _)| rank),
+            cursor.map(|(_, id)| id),
+            limit + 1
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let next_cursor = match rows.len() as i64 > limit {
+            true => rows
+                .get(limit as usize - 1)
+                .map(|r| format!("{}_{}", r.rank, r.id)),
+            false => None,
+        };
+        let rows = rows.into_iter().take(limit as usize).collect::<Vec<_>>();
+        let ids = rows.iter().map(|r| r.id).collect::<Vec<_>>();
+        let summaries = DatabaseHand::load_listing_summaries(&pool, &ids).await?;
+        let items = rows
+            .into_iter()
+            .filter_map(|row| {
+                let listing = summaries.iter().find(|l| l.id == row.id)?.clone();
+                Some(SearchResult {
+                    listing,
+                    rank: row.rank,
+                    snippet: row.snippet,
+                })
+            })
+            .collect();
+        Ok(Page { items, next_cursor })
+    }
+
+    // Load the boxes of every listing in `listing_ids` with their available products,
+    // grouped by listing id
+    async fn load_boxes(
+        conn: &mut PgConnection,
+        listing_ids: &[Uuid],
+    ) -> DResult<HashMap<Uuid, Vec<Box>>> {
+        let boxes = sqlx::query_as!(
+            DBox,
+            "SELECT * FROM box WHERE listing_id = ANY($1)",
+            listing_ids
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let box_ids = boxes.iter().map(|b| b.id).collect::<Vec<_>>();
+        let products = sqlx::query_as!(
+            DProduct,
+            "SELECT * FROM products WHERE box_id = ANY($1)",
+            &box_ids
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut products_of_box: HashMap<Uuid, Vec<Product>> = HashMap::new();
+        for product in products {
+            products_of_box
+                .entry(product.box_id)
+                .or_default()
+                .push(product.into());
+        }
+        let mut final_boxes: HashMap<Uuid, Vec<Box>> = HashMap::new();
+        for b in boxes {
+            let mut b: Box = b.into();
+            let products = products_of_box.remove(&b.id).unwrap_or_default();
+            b.total = products.len() as u32;
+            let pro = products
+                .into_iter()
+                .filter(|p| !p.status)
+                .collect::<Vec<_>>();
+            b.available_products = pro.len() as u32;
+            b.products = pro;
+            final_boxes.entry(b.listing_id).or_default().push(b);
+        }
+        Ok(final_boxes)
+    }
+
+    // Get one page of listings matching the query. The cursor is the sort key and id
+    // of the last listing of the previous page.
+    pub async fn get_listings_page(
+        pool: &Pool,
+        query: &ListingQuery,
+    ) -> DResult<Page<ListingSummary>> {
+        let pool = pool.clone();
+        let limit = query.limit.unwrap_or(20).clamp(1, 100) as i64;
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let (key, id) = cursor.split_once('_').ok_or(ApiError::InvalidCursor)?;
+                let key = key.parse::<f64>().map_err(|_| ApiError::InvalidCursor)?;
+                let id = Uuid::parse_str(id).map_err(|_| ApiError::InvalidCursor)?;
+                Some((key, id))
+            }
+            None => None,
+        };
+        let status = query.status.unwrap_or(ListingStatus::Live);
+        let mut tags = query
+            .tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        let rows = sqlx::query!(
+            r#"WITH filtered AS (
+                SELECT l.id, l.created_at FROM listing l
+                WHERE l.status = $2
+                AND ($3::text IS NULL OR l.tty = $3)
+                AND ($4::uuid IS NULL OR l.category_id = $4)
+                AND (($5::int IS NULL AND $6::int IS NULL) OR EXISTS (
+                    SELECT 1 FROM box pb
+                    LEFT JOIN products pp ON pp.box_id = pb.id
+                    WHERE pb.listing_id = l.id
+                    AND ($5::int IS NULL OR COALESCE(pp.price, pb.price) >= $5)
+                    AND ($6::int IS NULL OR COALESCE(pp.price, pb.price) <= $6)
+                ))
+                AND ($7::bool IS NULL OR EXISTS (
+                    SELECT 1 FROM box sb
+                    INNER JOIN products sp ON sp.box_id = sb.id
+                    WHERE sb.listing_id = l.id AND NOT sp.status AND sp.amount > 0
+                ) = $7)
+                AND (cardinality($11::text[]) = 0 OR (
+                    SELECT COUNT(DISTINCT t.id) FROM tag t
+                    WHERE t.slug = ANY($11)
+                    AND (EXISTS (
+                        SELECT 1 FROM listing_tags lt
+                        WHERE lt.listing_id = l.id AND lt.tag_id = t.id
+                    ) OR EXISTS (
+                        SELECT 1 FROM product_tags pt
+                        INNER JOIN products tp ON tp.id = pt.product_id
+                        INNER JOIN box tb ON tb.id = tp.box_id
+                        WHERE tb.listing_id = l.id AND pt.tag_id = t.id
+                    ))
+                ) = cardinality($11::text[]))
+            ), keyed AS (
+                -- Only the branch of the sort is run, so the newest first only needs the
+                -- listing rows and the other sorts only aggregate their own figure
+                SELECT f.id, CASE $1
+                    WHEN 'price' THEN (
+                        SELECT COALESCE(MIN(COALESCE(p.price, b.price)), 2147483647)::float8
+                        FROM box b LEFT JOIN products p ON p.box_id = b.id
+                        WHERE b.listing_id = f.id
+                    )
+                    WHEN 'price_desc' THEN (
+                        SELECT -COALESCE(MAX(COALESCE(p.price, b.price)), 0)::float8
+                        FROM box b LEFT JOIN products p ON p.box_id = b.id
+                        WHERE b.listing_id = f.id
+                    )
+                    WHEN 'remaining' THEN (
+                        SELECT -COALESCE(SUM(p.amount) FILTER (WHERE NOT p.status), 0)::float8
+                        FROM box b INNER JOIN products p ON p.box_id = b.id
+                        WHERE b.listing_id = f.id
+                    )
+                    WHEN 'popularity' THEN (
+                        SELECT -COUNT(*)::float8 FROM products_owned po
+                        INNER JOIN products op ON op.id = po.product_id
+                        INNER JOIN box ob ON ob.id = op.box_id
+                        WHERE ob.listing_id = f.id
+                    )
+                    ELSE -EXTRACT(EPOCH FROM f.created_at)::float8
+                END AS sort_key
+                FROM filtered f
+            )
+            SELECT id AS "id!", sort_key AS "sort_key!" FROM keyed
+            WHERE ($8::float8 IS NULL OR (sort_key, id) > ($8, $9::uuid))
+            ORDER BY sort_key, id
+            LIMIT $10"#,
+            query.sort.as_str(),
+            status.as_str(),
+            query.tty.map(|t| t.as_str()),
+            query.category_id,
+            query.min_price.map(|p| p as i32),
+            query.max_price.map(|p| p as i32),
+            query.has_stock,
+            cursor.map(|(key, _)| key),
+            cursor.map(|(_, id)| id),
+            // One extra row tells us whether there is a next page
+            limit + 1,
+            &tags
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let next_cursor = match rows.len() as i64 > limit {
+            true => rows
+                .get(limit as usize - 1)
+                .map(|r| format!("{}_{}", r.sort_key, r.id)),
+            false => None,
+        };
+        let ids = rows
+            .iter()
+            .take(limit as usize)
+            .map(|r| r.id)
+            .collect::<Vec<_>>();
+        let items = DatabaseHand::load_listing_summaries(&pool, &ids).await?;
+        Ok(Page { items, next_cursor })
+    }
+
+    pub async fn update_listing_status(
+        pool: &Pool,
+        data: (Uuid, ListingStatus, ReqId),
+    ) -> DResult<Listing> {
+        let (listing_id, status, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = sqlx::query!(
+                    "SELECT status FROM listing WHERE id = $1 FOR UPDATE",
+                    listing_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                sqlx::query!(
+                    "UPDATE listing SET status = $1 WHERE id = $2",
+                    status.as_str(),
+                    listing_id
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::ListingStatusChanged,
+                        Some(listing_id),
+                        format!("Listing {listing_id} set to {}", status.as_str()),
+                    )
+                    .before(&json!({ "status": before.status }))
+                    .after(&json!({ "status": status })),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_listing_from_id(&pool, &listing_id).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn get_listings_by_type(pool: &Pool, tty: ListingType) -> DResult<Vec<Listing>> {
+        let mut conn = pool.acquire().await?;
+        let listings =
+            sqlx::query_as!(DListing, "SELECT * FROM listing WHERE tty = $1", tty.as_str())
+                .fetch_all(&mut conn)
+                .await?;
+        DatabaseHand::assemble_listings(&mut conn, listings).await
+    }
+
+    pub async fn get_boxes_of_listing(pool: &Pool, listing_id: &Uuid) -> DResult<Vec<Box>> {
+        let mut conn = pool.acquire().await?;
+        let mut boxes = DatabaseHand::load_boxes(&mut conn, &[*listing_id]).await?;
+        Ok(boxes.remove(listing_id).unwrap_or_default())
+    }
+
+    pub async fn get_products(pool: &Pool, box_id: &Uuid) -> DResult<Vec<Product>> {
+        let mut final_products = vec![];
+        let pool = pool.clone();
+        let products =
+            sqlx::query_as!(DProduct, "SELECT * FROM products WHERE box_id = $1", box_id)
+                .fetch_all(&pool)
+                .await?;
+
+        for pro in products {
+            let product: Product = pro.into();
+
+            final_products.push(product);
+        }
+
+        Ok(final_products)
+    }
+
+    // Check whether one of the user's roles grants `permission`
+    pub async fn confirm_permission(
+        pool: &Pool,
+        id: &ReqId,
+        permission: Permission,
+    ) -> DResult<bool> {
+        let pool = pool.clone();
+        let granted = sqlx::query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM user_roles ur
+                INNER JOIN role_permissions rp ON rp.role_id = ur.role_id
+                INNER JOIN role r ON r.id = ur.role_id
+                INNER JOIN users u ON u.id = ur.user_id
+                WHERE ur.user_id = $1 AND rp.permission = $2
+                AND (NOT r.require_two_factor OR u.totp_enabled)
+                AND (u.status = 'active' OR (u.status = 'suspended' AND u.suspended_until <= $3))
+            ) AS "granted!""#,
+            id.id,
+            permission.as_str(),
+            Utc::now().naive_utc()
+        )
+        .fetch_one(&pool)
+        .await?;
+        Ok(granted.granted)
+    }
+
+    pub async fn get_user_permissions(pool: &Pool, id: &Uuid) -> DResult<Vec<Permission>> {
+        let pool = pool.clone();
+        let permissions = sqlx::query!(
+            "SELECT DISTINCT rp.permission FROM user_roles ur
+            INNER JOIN role_permissions rp ON rp.role_id = ur.role_id
+            INNER JOIN role r ON r.id = ur.role_id
+            INNER JOIN users u ON u.id = ur.user_id
+            WHERE ur.user_id = $1 AND (NOT r.require_two_factor OR u.totp_enabled)
+            AND (u.status = 'active' OR (u.status = 'suspended' AND u.suspended_until <= $2))
+            ORDER BY rp.permission",
+            id,
+            Utc::now().naive_utc()
+        )
+        .fetch_all(&pool)
+        .await?;
+        Ok(permissions
+            .iter()
+            .filter_map(|p| p.permission.parse().ok())
+            .collect())
+    }
+    pub async fn create_listing(
+        pool: &Pool,
+        data: (Listing, ReqId, ImageData),
+    ) -> DResult<Vec<Listing>> {
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &data.1, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query!(
+                    "INSERT INTO listing (title, created_at, id, tty, description, category_id) VALUES($1, $2, $3, $4, $5, $6)",
+                    &data.0.title,
+                    &data.0.created_at,
+                    &data.0.id,
+                    &data.0.tty,
+                    &data.0.description,
+                    data.0.category_id
+                )
+                .execute(&mut tx)
+                .await?;
+                sqlx::query!(
+                    "INSERT INTO images (path, for_id, extension) VALUES($1, $2, $3)",
+                    data.2.path,
+                    data.2.id,
+                    data.2.ext
+                )
+                .execute(&mut tx)
+                .await?;
+                let listing = DatabaseHand::load_listings(&mut tx, &[data.0.id]).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        data.1.id,
+                        AuditEvent::ListingCreated,
+                        Some(data.0.id),
+                        format!("Listing {} created", data.0.id),
+                    )
+                    .after(&listing.first()),
+                )
+                .await?;
+                tx.commit().await?;
+                let listings = DatabaseHand::get_listing(&pool).await?;
+                Ok(listings)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn create_box(pool: &Pool, data: (Box, Vec<Product>, ReqId)) -> DResult<Vec<Box>> {
+        let (bx, prods, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query!(
+                    "INSERT INTO box (id, price, listing_id, created_at, original_price, claim_seconds) VALUES ($1, $2, $3, $4, $5, $6)",
+                    bx.id,
+                    bx.price as i32,
+                    bx.listing_id,
+                    bx.created_at,
+                    bx.original_price as i32,
+                    bx.claim_seconds.map(|c| c as i32)
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::check_product_prices(&mut tx, &bx.id, &prods).await?;
+                for prod in prods {
+                    sqlx::query!(
+                        "INSERT INTO products
+                    (box_id, title, id, description, level, status, created_at, amount, image, ini_amount, price)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                        // Remember that prod.box_id is a temporary id so we have
+                        // to use `bx.id`
+                        bx.id,
+                        prod.title,
+                        prod.id,
+                        prod.description,
+                        prod.level as i32,
+                        prod.status,
+                        prod.created_at,
+                        prod.amount,
+                        prod.image,
+                        prod.ini_amount,
+                        prod.price.map(|p| p as i32)
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                let bxs = DatabaseHand::load_boxes(&mut tx, &[bx.listing_id])
+                    .await?
+                    .remove(&bx.listing_id)
+                    .unwrap_or_default();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::BoxCreated,
+                        Some(bx.id),
+                        format!("Box {} created in listing {}", bx.id, bx.listing_id),
+                    )
+                    .after(&bxs.iter().find(|b| b.id == bx.id)),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(bxs)
+            }
+
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Save the price and prize composition of an existing box as a template
+    pub async fn create_box_template(
+        pool: &Pool,
+        data: (Uuid, String, ReqId),
+    ) -> DResult<BoxTemplate> {
+        let (box_id, name, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let bx = sqlx::query_as!(DBox, "SELECT * FROM box WHERE id = $1", box_id)
+                    .fetch_one(&mut tx)
+                    .await?;
+                let template = sqlx::query_as!(
+                    DBoxTemplate,
+                    "INSERT INTO box_template (id, name, price, original_price, created_at)
+                     VALUES ($1, $2, $3, $4, $5) RETURNING *",
+                    Uuid::new_v4(),
+                    name,
+                    bx.price,
+                    bx.original_price,
+                    Utc::now().naive_utc()
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                // Templates store the initial amounts so that cloned boxes start full
+                let products = sqlx::query_as!(
+                    DTemplateProduct,
+                    "INSERT INTO box_template_products (id, template_id, title, description, level, amount, image)
+                     SELECT gen_random_uuid(), $1, title, description, level, ini_amount, image
+                     FROM products WHERE box_id = $2
+                     RETURNING *",
+                    template.id,
+                    box_id
+                )
+                .fetch_all(&mut tx)
+                .await?;
+                let mut template: BoxTemplate = template.into();
+                template.products = products.into_iter().map(|p| p.into()).collect();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::BoxTemplateCreated,
+                        Some(template.id),
+                        format!("Box template {} saved from box {box_id}", template.id),
+                    )
+                    .after(&template),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(template)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn get_box_templates(pool: &Pool) -> DResult<Vec<BoxTemplate>> {
+        let pool = pool.clone();
+        let templates = sqlx::query_as!(
+            DBoxTemplate,
+            "SELECT * FROM box_template ORDER BY created_at"
+        )
+        .fetch_all(&pool)
+        .await?;
+        let products = sqlx::query_as!(
+            DTemplateProduct,
+            "SELECT * FROM box_template_products ORDER BY level"
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let mut templates: Vec<BoxTemplate> = templates.into_iter().map(|t| t.into()).collect();
+        for product in products {
+            if let Some(template) = templates.iter_mut().find(|t| t.id == product.template_id) {
+                template.products.push(product.into());
+            }
+        }
+        Ok(templates)
+    }
+
+    // Create `count` boxes under a listing from a template
+    pub async fn clone_boxes_from_template(
+        pool: &Pool,
+        data: (Uuid, Uuid, u32, ReqId),
+    ) -> DResult<Vec<Box>> {
+        let (template_id, listing_id, count, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let template = sqlx::query_as!(
+                    DBoxTemplate,
+                    "SELECT * FROM box_template WHERE id = $1",
+                    template_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                sqlx::query!("SELECT id FROM listing WHERE id = $1 FOR SHARE", listing_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or(ApiError::InvalidId)?;
+                let products = sqlx::query_as!(
+                    DTemplateProduct,
+                    "SELECT * FROM box_template_products WHERE template_id = $1",
+                    template_id
+                )
+                .fetch_all(&mut tx)
+                .await?;
+
+                let mut box_ids = vec![];
+                for _ in 0..count {
+                    let box_id = Uuid::new_v4();
+                    let created_at = Utc::now().naive_utc();
+                    sqlx::query!(
+                        "INSERT INTO box (id, price, listing_id, created_at, original_price) VALUES ($1, $2, $3, $4, $5)",
+                        box_id,
+                        template.price,
+                        listing_id,
+                        created_at,
+                        template.original_price
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    for prod in &products {
+                        sqlx::query!(
+                            "INSERT INTO products
+                        (box_id, title, id, description, level, status, created_at, amount, image, ini_amount)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                            box_id,
+                            prod.title,
+                            Uuid::new_v4(),
+                            prod.description,
+                            prod.level,
+                            false,
+                            created_at,
+                            prod.amount,
+                            prod.image,
+                            prod.amount
+                        )
+                        .execute(&mut tx)
+                        .await?;
+                    }
+                    box_ids.push(box_id);
+                }
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::BoxesCloned,
+                        Some(listing_id),
+                        format!(
+                            "Cloned {count} boxes from template {template_id} into listing {listing_id}"
+                        ),
+                    )
+                    .after(&json!({ "template_id": template_id, "box_ids": box_ids })),
+                )
+                .await?;
+                tx.commit().await?;
+
+                let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing_id).await?;
+                Ok(bxs)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Insert validated catalogue listings, after the rows which didn't validate. Every
+    // listing is tried so the report is complete, but nothing is written unless `commit`
+    // is set and there were no errors at all.
+    pub async fn import_catalogue(
+        pool: &Pool,
+        data: (Vec<ImportListing>, Vec<RowError>, bool, ReqId),
+    ) -> DResult<ImportReport> {
+        let (listings, errors, commit, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut report = ImportReport {
+                    committed: false,
+                    listings: 0,
+                    boxes: 0,
+                    products: 0,
+                    errors,
+                };
+                let mut tx = pool.begin().await?;
+                for import in listings {
+                    let ImportListing {
+                        row,
+                        listing,
+                        image,
+                        tags,
+                        boxes,
+                    } = import;
+                    // A listing with errors is left out, the ones after it are still checked
+                    let errors_before = report.errors.len();
+                    if let Some(category_id) = listing.category_id {
+                        let category =
+                            sqlx::query!("SELECT id FROM category WHERE id = $1", category_id)
+                                .fetch_optional(&mut tx)
+                                .await?;
+                        if category.is_none() {
+                            report.errors.push(RowError {
+                                row,
+                                field: "category_id".to_owned(),
+                                message: format!("Category {category_id} does not exist."),
+                            });
+                        }
+                    }
+                    // Tags are referenced by slug and have to exist already
+                    let mut slugs = tags.iter().map(|slug| (row, slug)).collect::<Vec<_>>();
+                    for import_box in &boxes {
+                        for prod in &import_box.products {
+                            slugs.extend(prod.tags.iter().map(|slug| (prod.row, slug)));
+                        }
+                    }
+                    let wanted = slugs
+                        .iter()
+                        .map(|(_, slug)| (*slug).clone())
+                        .collect::<Vec<_>>();
+                    let tag_ids =
+                        sqlx::query!("SELECT id, slug FROM tag WHERE slug = ANY($1)", &wanted)
+                            .fetch_all(&mut tx)
+                            .await?
+                            .into_iter()
+                            .map(|t| (t.slug, t.id))
+                            .collect::<HashMap<_, _>>();
+                    for (tag_row, slug) in slugs {
+                        if !tag_ids.contains_key(slug) {
+                            report.errors.push(RowError {
+                                row: tag_row,
+                                field: match tag_row == row {
+                                    true => "tags".to_owned(),
+                                    false => "product_tags".to_owned(),
+                                },
+                                message: format!("Tag {slug} does not exist."),
+                            });
+                        }
+                    }
+                    // Images are referenced by the id returned from /admin/generate/image_link
+                    let image = sqlx::query!(
+                        "SELECT path, extension FROM images WHERE path = $1 LIMIT 1",
+                        format!("database/images/{image}")
+                    )
+                    .fetch_optional(&mut tx)
+                    .await?;
+                    let image = match image {
+                        Some(image) => image,
+                        None => {
+                            report.errors.push(RowError {
+                                row,
+                                field: "image".to_owned(),
+                                message: "Image does not exist.".to_owned(),
+                            });
+                            continue;
+                        }
+                    };
+                    if report.errors.len() > errors_before {
+                        continue;
+                    }
+
+                    sqlx::query!(
+                        "INSERT INTO listing (title, created_at, id, tty, description, category_id, status) VALUES($1, $2, $3, $4, $5, $6, $7)",
+                        listing.title,
+                        listing.created_at,
+                        listing.id,
+                        listing.tty,
+                        listing.description,
+                        listing.category_id,
+                        listing.status
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    sqlx::query!(
+                        "INSERT INTO images (path, for_id, extension) VALUES($1, $2, $3)",
+                        image.path,
+                        listing.id,
+                        image.extension
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    sqlx::query!(
+                        "INSERT INTO listing_tags(listing_id, tag_id)
+                        SELECT $1, tag_id FROM unnest($2::uuid[]) AS t(tag_id)",
+                        listing.id,
+                        &tags.iter().map(|slug| tag_ids[slug]).collect::<Vec<_>>()
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    report.listings += 1;
+
+                    for import_box in boxes {
+                        let bx = import_box.bx;
+                        sqlx::query!(
+                            "INSERT INTO box (id, price, listing_id, created_at, original_price) VALUES ($1, $2, $3, $4, $5)",
+                            bx.id,
+                            bx.price as i32,
+                            bx.listing_id,
+                            bx.created_at,
+                            bx.original_price as i32
+                        )
+                        .execute(&mut tx)
+                        .await?;
+                        report.boxes += 1;
+                        for import_product in import_box.products {
+                            let ImportProduct {
+                                product: prod,
+                                tags,
+                                ..
+                            } = import_product;
+                            sqlx::query!(
+                                "INSERT INTO products
+                            (box_id, title, id, description, level, status, created_at, amount, image, ini_amount, price)
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                                bx.id,
+                                prod.title,
+                                prod.id,
+                                prod.description,
+                                prod.level as i32,
+                                prod.status,
+                                prod.created_at,
+                                prod.amount,
+                                prod.image,
+                                prod.ini_amount,
+                                prod.price.map(|p| p as i32)
+                            )
+                            .execute(&mut tx)
+                            .await?;
+                            sqlx::query!(
+                                "INSERT INTO product_tags(product_id, tag_id)
+                                SELECT $1, tag_id FROM unnest($2::uuid[]) AS t(tag_id)",
+                                prod.id,
+                                &tags.iter().map(|slug| tag_ids[slug]).collect::<Vec<_>>()
+                            )
+                            .execute(&mut tx)
+                            .await?;
+                            report.products += 1;
+                        }
+                    }
+                }
+
+                report.errors.sort_by_key(|e| e.row);
+                if commit && report.errors.is_empty() {
+                    report.committed = true;
+                    DatabaseHand::add_log(
+                        &mut tx,
+                        LogData::new(
+                            req_id.id,
+                            AuditEvent::CatalogueImported,
+                            None,
+                            format!(
+                                "Catalogue imported: {} listings, {} boxes, {} products",
+                                report.listings, report.boxes, report.products
+                            ),
+                        )
+                        .after(&report),
+                    )
+                    .await?;
+                    tx.commit().await?;
+                } else {
+                    tx.rollback().await?;
+                }
+                Ok(report)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Export every listing, box and product as catalogue rows which `import_catalogue`
+    // accepts again
+    pub async fn export_catalogue(pool: &Pool) -> DResult<Vec<CatalogueRow>> {
+        let pool = pool.clone();
+        let records = sqlx::query!(
+            r#"SELECT l.id AS listing_id, l.title, l.tty, l.status, l.description, l.category_id,
+                i.path AS "path?",
+                (SELECT string_agg(t.slug, ',' ORDER BY t.slug) FROM listing_tags lt
+                    INNER JOIN tag t ON t.id = lt.tag_id WHERE lt.listing_id = l.id) AS tags,
+                b.id AS "box_id?", b.price AS "price?", b.original_price AS "original_price?",
+                p.title AS "product?", p.description AS "product_description?", p.level AS "level?",
+                p.ini_amount AS "amount?", p.price AS product_price, p.image AS "product_image?",
+                (SELECT string_agg(t.slug, ',' ORDER BY t.slug) FROM product_tags pt
+                    INNER JOIN tag t ON t.id = pt.tag_id WHERE pt.product_id = p.id) AS product_tags
+            FROM listing l
+            LEFT JOIN LATERAL (
+                SELECT path FROM images WHERE for_id = l.id ORDER BY path LIMIT 1
+            ) i ON true
+            LEFT JOIN box b ON b.listing_id = l.id
+            LEFT JOIN products p ON p.box_id = b.id
+            ORDER BY l.created_at, l.id, b.created_at, b.id, p.level, p.title"#
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let mut rows = vec![];
+        let mut current: Option<(Uuid, Vec<Uuid>)> = None;
+        for record in records {
+            // Boxes are numbered in the order they were created within their listing
+            let box_label = match record.box_id {
+                Some(box_id) => {
+                    let boxes = match &mut current {
+                        Some((listing_id, boxes)) if *listing_id == record.listing_id => boxes,
+                        _ => &mut current.insert((record.listing_id, vec![])).1,
+                    };
+                    if !boxes.contains(&box_id) {
+                        boxes.push(box_id);
+                    }
+                    Some((boxes.iter().position(|id| *id == box_id).unwrap() + 1).to_string())
+                }
+                None => None,
+            };
+            let image = record
+                .path
+                .as_deref()
+                .and_then(|path| path.rsplit('/').next())
+                .unwrap_or_default()
+                .to_owned();
+            rows.push(CatalogueRow {
+                listing_key: Some(record.listing_id.to_string()),
+                listing: record.title,
+                tty: record.tty,
+                status: Some(record.status),
+                description: record.description,
+                category_id: record.category_id,
+                image,
+                tags: record.tags,
+                box_label,
+                price: record.price.map(|p| p as u32),
+                original_price: record.original_price.map(|p| p as u32),
+                product: record.product,
+                product_description: record.product_description,
+                level: record.level.map(|l| l as u32),
+                amount: record.amount.map(|a| a as u32),
+                product_price: record.product_price.map(|p| p as u32),
+                product_image: record.product_image,
+                product_tags: record.product_tags,
+            });
+        }
+        Ok(rows)
+    }
+
+    // Deletion
+
+    pub async fn get_single_listing(pool: &Pool, listing_id: &Uuid) -> DResult<Listing> {
+        let mut conn = pool.acquire().await?;
+        DatabaseHand::load_listings(&mut conn, &[*listing_id])
+            .await?
+            .pop()
+            .ok_or(ApiError::DatabaseError(sqlx::Error::RowNotFound))
+    }
+
+    // Delete a box with its products and log it, returns the listing it was in
+    async fn remove_box(conn: &mut PgConnection, box_id: &Uuid, actor: &Uuid) -> DResult<Uuid> {
+        let id = sqlx::query!("SELECT listing_id FROM box WHERE id = $1", box_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        let before = DatabaseHand::load_boxes(&mut *conn, &[id.listing_id])
+            .await?
+            .remove(&id.listing_id)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|b| b.id == *box_id);
+
+        // Delete it's products
+        sqlx::query!("DELETE FROM products WHERE box_id = $1", box_id)
+            .execute(&mut *conn)
+            .await?;
+
+        // Delete Box
+        sqlx::query!("DELETE FROM box where id = $1", box_id)
+            .execute(&mut *conn)
+            .await?;
+
+        DatabaseHand::add_log(
+            &mut *conn,
+            LogData::new(
+                *actor,
+                AuditEvent::BoxDeleted,
+                Some(*box_id),
+                format!("Box {box_id} deleted from listing {}", id.listing_id),
+            )
+            .before(&before),
+        )
+        .await?;
+        Ok(id.listing_id)
+    }
+
+    pub async fn delete_box(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Listing> {
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &data.1, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let listing_id = DatabaseHand::remove_box(&mut tx, &data.0, &data.1.id).await?;
+                tx.commit().await?;
+                let listing = DatabaseHand::get_single_listing(&pool, &listing_id).await?;
+                Ok(listing)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn delete_listing(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Listing>> {
+        let (listing_id, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let before = DatabaseHand::get_single_listing(&pool, &listing_id).await?;
+                let mut tx = pool.begin().await?;
+                let box_ids = sqlx::query!("SELECT id FROM box WHERE listing_id = $1", listing_id)
+                    .fetch_all(&mut tx)
+                    .await?;
+                for box_id in box_ids {
+                    DatabaseHand::remove_box(&mut tx, &box_id.id, &req_id.id).await?;
+                }
+
+                sqlx::query!("DELETE FROM listing WHERE id = $1 ", listing_id)
+                    .execute(&mut tx)
+                    .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::ListingDeleted,
+                        Some(listing_id),
+                        format!("Listing {listing_id} deleted"),
+                    )
+                    .before(&before),
+                )
+                .await?;
+                tx.commit().await?;
+                let listings = DatabaseHand::get_listing(&pool).await?;
+                Ok(listings)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Delete a single product from a single box and return all the listings
+    pub async fn delete_product(pool: &Pool, data: (Uuid, Uuid, ReqId)) -> DResult<Vec<Listing>> {
+        let (product_id, box_id, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let deleted: Product = sqlx::query_as!(
+                    DProduct,
+                    "DELETE FROM products WHERE id = $1 AND box_id = $2 RETURNING *",
+                    product_id,
+                    box_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?
+                .into();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::ProductDeleted,
+                        Some(product_id),
+                        format!("Deleted product {product_id} from box {box_id}"),
+                    )
+                    .before(&deleted),
+                )
+                .await?;
+                tx.commit().await?;
+
+                let listings = DatabaseHand::get_listing(&pool).await?;
+                Ok(listings)
+            }
+
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Find every product in the listing which shares the given product's title,
+    // i.e. the same prize in each of the listing's boxes.
+    async fn find_prize_in_listing<'e, E: PgExecutor<'e>>(
+        executor: E,
+        product_id: &Uuid,
+    ) -> DResult<PrizeRemoval> {
+        let rows = sqlx::query!(
+            "SELECT p.id, p.title, b.listing_id FROM products p
+             INNER JOIN box b ON b.id = p.box_id
+             WHERE b.listing_id = (SELECT b2.listing_id FROM products p2 INNER JOIN box b2 ON b2.id = p2.box_id WHERE p2.id = $1)
+             AND p.title = (SELECT title FROM products WHERE id = $1)
+             ORDER BY p.id",
+            product_id
+        )
+        .fetch_all(executor)
+        .await?;
+        match rows.first() {
+            Some(first) => Ok(PrizeRemoval {
+                listing_id: first.listing_id,
+                title: first.title.clone(),
+                product_ids: rows.iter().map(|r| r.id).collect(),
+            }),
+            None => Err(ApiError::InvalidId),
+        }
+    }
+
+    // Preview which products `remove_prize_from_listing` would delete
+    pub async fn preview_prize_removal(pool: &Pool, data: (Uuid, ReqId)) -> DResult<PrizeRemoval> {
+        let (product_id, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => DatabaseHand::find_prize_in_listing(&pool, &product_id).await,
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Remove a prize from all the boxes of its listing. The caller has to send back the
+    // ids it got from `preview_prize_removal`, nothing is deleted if they no longer match.
+    pub async fn remove_prize_from_listing(
+        pool: &Pool,
+        data: (Uuid, Vec<Uuid>, ReqId),
+    ) -> DResult<Vec<Listing>> {
+        let (product_id, mut confirmed_ids, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let removal = DatabaseHand::find_prize_in_listing(&mut tx, &product_id).await?;
+                confirmed_ids.sort();
+                if removal.product_ids != confirmed_ids {
+                    return Err(ApiError::PreviewMismatch);
+                }
+                let deleted = sqlx::query_as!(
+                    DProduct,
+                    "DELETE FROM products WHERE id = ANY($1) RETURNING *",
+                    &removal.product_ids
+                )
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|p| p.into())
+                .collect::<Vec<Product>>();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::PrizeRemoved,
+                        Some(removal.listing_id),
+                        format!(
+                            "Removed prize \"{}\" from listing {}",
+                            removal.title, removal.listing_id
+                        ),
+                    )
+                    .before(&deleted),
+                )
+                .await?;
+                tx.commit().await?;
+
+                let listings = DatabaseHand::get_listing(&pool).await?;
+                Ok(listings)
+            }
+
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Count the tickets left in each of the given boxes, per prize level
+    pub async fn get_box_stock(pool: &Pool, box_ids: &[Uuid]) -> DResult<Vec<BoxStock>> {
+        let pool = pool.clone();
+        let rows = sqlx::query!(
+            r#"SELECT b.id, b.listing_id, p.level AS "level?",
+                COALESCE(SUM(p.amount) FILTER (WHERE NOT p.status), 0) AS "remaining!",
+                COALESCE(SUM(p.ini_amount), 0) AS "total!"
+            FROM box b
+            LEFT JOIN products p ON p.box_id = b.id
+            WHERE b.id = ANY($1)
+            GROUP BY b.id, b.listing_id, p.level
+            ORDER BY b.created_at, b.id, p.level"#,
+            box_ids
+        )
+        .fetch_all(&pool)
+        .await?;
+
+        let mut stock: Vec<BoxStock> = vec![];
+        for row in rows {
+            let bx = match stock.last_mut() {
+                Some(bx) if bx.box_id == row.id => bx,
+                _ => {
+                    stock.push(BoxStock {
+                        listing_id: row.listing_id,
+                        box_id: row.id,
+                        remaining: 0,
+                        total: 0,
+                        tiers: vec![],
+                    });
+                    stock.last_mut().unwrap()
+                }
+            };
+            if let Some(level) = row.level {
+                bx.remaining += row.remaining as u32;
+                bx.total += row.total as u32;
+                bx.tiers.push(TierStock {
+                    level: level as u32,
+                    remaining: row.remaining as u32,
+                    total: row.total as u32,
+                });
+            }
+        }
+        Ok(stock)
+    }
+
+    pub async fn get_box_cost(pool: &Pool, box_id: &Uuid) -> DResult<i32> {
+        let pool = pool.clone();
+        let cost = sqlx::query!("SELECT price FROM box WHERE id = $1", box_id)
+            .fetch_one(&pool)
+            .await?;
+
+        Ok(cost.price)
+    }
+
+    pub async fn deduct_points_from_user(pool: &Pool, data: (u32, Uuid)) -> DResult<()> {
+        let (points, user_id) = data;
+        let user_points = DatabaseHand::get_user_points(pool, &user_id).await?;
+        let p = user_points - points;
+        let pool = pool.clone();
+        sqlx::query!(
+            "UPDATE users SET points = $1 WHERE id = $2",
+            p as i32,
+            user_id
+        )
+        .execute(&pool)
+        .await?;
+        Ok(())
+    }
+    // Confirm user privilege also
+    // Products of a direct-sale listing are bought at their own price, so they need one
+    async fn check_product_prices(
+        conn: &mut PgConnection,
+        box_id: &Uuid,
+        products: &[Product],
+    ) -> DResult<()> {
+        let listing = sqlx::query!(
+            "SELECT l.tty FROM box b INNER JOIN listing l ON l.id = b.listing_id WHERE b.id = $1",
+            box_id
+        )
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        let direct = listing.tty == ListingType::Direct.as_str();
+        Validator::new()
+            .check(
+                "price",
+                (direct && products.iter().any(|p| p.price.is_none()))
+                    .then(|| "Products of a direct-sale listing need a price.".to_owned()),
+            )
+            .finish()
+    }
+
+    pub async fn add_product_to_box(
+        pool: &Pool,
+        data: (ReqId, Uuid, Vec<Product>),
+    ) -> DResult<Vec<Listing>> {
+        let (req_id, box_id, products) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                DatabaseHand::check_product_prices(&mut tx, &box_id, &products).await?;
+                for product in products {
+                    sqlx::query!(
+                        "INSERT INTO products
+                (box_id, title, id, description, level, status, created_at, amount, image, ini_amount, price)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                        box_id,
+                        product.title,
+                        product.id,
+                        product.description,
+                        product.level as i32,
+                        product.status,
+                        product.created_at,
+                        product.amount,
+                        product.image,
+                        product.ini_amount,
+                        product.price.map(|p| p as i32)
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    DatabaseHand::add_log(
+                        &mut tx,
+                        LogData::new(
+                            req_id.id,
+                            AuditEvent::ProductAdded,
+                            Some(product.id),
+                            format!("Product {} added to box {box_id}", product.id),
+                        )
+                        .after(&Product { box_id, ..product }),
+                    )
+                    .await?;
+                }
+                tx.commit().await?;
+
+                let listing = DatabaseHand::get_listing(&pool).await?;
+                Ok(listing)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+    pub async fn get_single_product(pool: &Pool, product_id: &Uuid) -> DResult<Product> {
+        let pool = pool.clone();
+        let product = sqlx::query_as!(DProduct, "SELECT * FROM products WHERE id = $1", product_id)
+            .fetch_one(&pool)
+            .await?
+            .into();
+        Ok(product)
+    }
+    // Get product amount from product's id
+    pub async fn get_product_amount(pool: &Pool, product_id: &Uuid) -> DResult<i32> {
+        let pool = pool.clone();
+        let amount = sqlx::query!("SELECT amount FROM products WHERE id = $1", product_id)
+            .fetch_one(&pool)
+            .await?;
+        Ok(amount.amount)
+    }
+    pub async fn buy_box(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Product> {
+        let (box_id, req_id) = data;
+        let pool = pool.clone();
+        let listing = sqlx::query!(
+            "SELECT l.tty, l.status FROM box b INNER JOIN listing l ON l.id = b.listing_id
+            WHERE b.id = $1",
+            box_id
+        )
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        // Direct-sale products are bought with `buy_product`, and only live listings sell
+        if listing.tty == ListingType::Direct.as_str()
+            || listing.status != ListingStatus::Live.as_str()
+        {
+            return Err(ApiError::WrongSaleMode);
+        }
+        DatabaseHand::check_user_active(&pool, &req_id.id).await?;
+        DatabaseHand::check_email_verified(&pool, &req_id.id).await?;
+
+        // The draw, its log, ledger entry and order are written together. The user
+        // row stays locked until then, so parallel draws can't pass the limit, and
+        // a queued box stays locked so the claim can't run out in between.
+        let mut tx = pool.begin().await?;
+        sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", req_id.id)
+            .fetch_one(&mut tx)
+            .await?;
+        DatabaseHand::check_purchase_limit(&mut tx, &box_id, &req_id.id).await?;
+        DatabaseHand::check_box_claim(&mut tx, &box_id, &req_id.id).await?;
+        let cost = sqlx::query!("SELECT price FROM box WHERE id = $1", box_id)
+            .fetch_one(&mut tx)
+            .await?
+            .price;
+
+        // Deducting points from user
+        let points = sqlx::query!(
+            "UPDATE users SET points = points - $1 WHERE id = $2 AND points >= $1 RETURNING points",
+            cost,
+            req_id.id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(ApiError::InsufficientPoints)?
+        .points;
+
+        // Selecting a random product, the products are locked so the same ticket
+        // can't be drawn twice
+        let mut products_idents = Vec::new();
+        let products = sqlx::query_as!(
+            DProduct,
+            "SELECT * FROM products WHERE box_id = $1 FOR UPDATE",
+            box_id
+        )
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(Product::from)
+        .filter(|prod| !prod.status)
+        .collect::<Vec<Product>>();
+
+        for product in &products {
+            let product: ProductIdent = product.clone().into();
+            products_idents.push(product);
+        }
+        if products_idents.iter().all(|p| p.total == 0) {
+            return Err(ApiError::OutOfStock);
+        }
+
+        let prod = DatabaseHand::select_weighted_random_product(&products_idents);
+        match prod {
+            Some(prod) => {
+                let product: Product = sqlx::query_as!(
+                    DProduct,
+                    "UPDATE products SET amount = amount - 1, status = amount - 1 = 0
+                    WHERE id = $1 RETURNING *",
+                    &prod.id
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .into();
+
+                // Adding the product purchase to products_owned
+                let t = Utc::now().naive_utc();
+                sqlx::query!(
+                    "INSERT INTO products_owned(user_id, product_id, bought_at, id) 
+                VALUES($1, $2, $3, $4)",
+                    &req_id.id,
+                    &prod.id,
+                    t,
+                    Uuid::new_v4()
+                )
+                .execute(&mut tx)
+                .await?;
+
+                let order = Order {
+                    id: Uuid::new_v4(),
+                    user_id: req_id.id,
+                    product_id: prod.id,
+                    created_at: t,
+                    status: "Pending".to_owned(),
+                    product_name: product.clone().title,
+                };
                 DatabaseHand::add_log(
-                    &pool,
-                    LogData {
-                        user_id: req_id.id.clone(),
-                        id: Uuid::new_v4(),
-                        created_at: Utc::now().naive_utc(),
-                        action: "Listing deleted".to_owned(),
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::TicketDrawn,
+                        Some(box_id),
+                        format!("Drew \"{}\" from box {box_id}", product.title),
+                    )
+                    .after(&order),
+                )
+                .await?;
+                DatabaseHand::add_ledger_entry(
+                    &mut tx,
+                    LedgerEntry {
+                        reference_id: Some(order.id),
+                        ..LedgerEntry::new(req_id.id, -cost, points as u32, LedgerReason::Draw)
                     },
                 )
                 .await?;
-                Ok(listings)
+                DatabaseHand::add_order(order, &mut tx).await?;
+                tx.commit().await?;
+
+                Ok(product)
+            }
+            None => Err(ApiError::SelectionError),
+        }
+    }
+
+    pub async fn get_purchase_limits(pool: &Pool) -> DResult<Vec<PurchaseLimit>> {
+        let pool = pool.clone();
+        let limits = sqlx::query_as!(
+            DPurchaseLimit,
+            "SELECT * FROM purchase_limit ORDER BY created_at"
+        )
+        .fetch_all(&pool)
+        .await?;
+        Ok(limits.into_iter().map(|l| l.into()).collect())
+    }
+
+    // Set the limit of a listing or a box, replacing the one it already has
+    pub async fn set_purchase_limit(
+        pool: &Pool,
+        data: (PurchaseLimit, ReqId),
+    ) -> DResult<PurchaseLimit> {
+        let (limit, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let target = match (limit.listing_id, limit.box_id) {
+                    (Some(listing_id), None) => sqlx::query!(
+                        "SELECT id FROM listing WHERE id = $1",
+                        listing_id
+                    )
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .map(|l| l.id),
+                    (None, Some(box_id)) => {
+                        sqlx::query!("SELECT id FROM box WHERE id = $1", box_id)
+                            .fetch_optional(&mut tx)
+                            .await?
+                            .map(|b| b.id)
+                    }
+                    _ => None,
+                }
+                .ok_or(ApiError::InvalidId)?;
+                let before = sqlx::query_as!(
+                    DPurchaseLimit,
+                    "DELETE FROM purchase_limit WHERE listing_id = $1 OR box_id = $1 RETURNING *",
+                    target
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .map(PurchaseLimit::from);
+                let limit: PurchaseLimit = sqlx::query_as!(
+                    DPurchaseLimit,
+                    "INSERT INTO purchase_limit
+                    (id, listing_id, box_id, max_per_box, max_per_day, cooldown_seconds, created_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+                    limit.id,
+                    limit.listing_id,
+                    limit.box_id,
+                    limit.max_per_box.map(|m| m as i32),
+                    limit.max_per_day.map(|m| m as i32),
+                    limit.cooldown_seconds.map(|c| c as i32),
+                    limit.created_at
+                )
+                .fetch_one(&mut tx)
+                .await?
+                .into();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::PurchaseLimitSet,
+                        Some(limit.id),
+                        format!("Purchase limit of {target} set"),
+                    )
+                    .before(&before)
+                    .after(&limit),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(limit)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn delete_purchase_limit(
+        pool: &Pool,
+        data: (Uuid, ReqId),
+    ) -> DResult<Vec<PurchaseLimit>> {
+        let (limit_id, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let deleted: PurchaseLimit = sqlx::query_as!(
+                    DPurchaseLimit,
+                    "DELETE FROM purchase_limit WHERE id = $1 RETURNING *",
+                    limit_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?
+                .into();
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::PurchaseLimitDeleted,
+                        Some(limit_id),
+                        format!("Purchase limit {limit_id} deleted"),
+                    )
+                    .before(&deleted),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_purchase_limits(&pool).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Check the limit of the box, or of its listing, against the user's earlier draws
+    async fn check_purchase_limit(
+        conn: &mut PgConnection,
+        box_id: &Uuid,
+        user_id: &Uuid,
+    ) -> DResult<()> {
+        let limit = sqlx::query_as!(
+            DPurchaseLimit,
+            "SELECT pl.* FROM purchase_limit pl
+            INNER JOIN box b ON pl.box_id = b.id OR pl.listing_id = b.listing_id
+            WHERE b.id = $1
+            ORDER BY pl.box_id IS NULL
+            LIMIT 1",
+            box_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        let limit: PurchaseLimit = match limit {
+            Some(limit) => limit.into(),
+            None => return Ok(()),
+        };
+
+        // A limit set on the listing counts the daily draws and the cooldown across all its boxes
+        let listing_id = match limit.box_id {
+            Some(_) => None,
+            None => limit.listing_id,
+        };
+        let now = Utc::now().naive_utc();
+        let day_ago = now - Duration::days(1);
+        let draws = sqlx::query!(
+            r#"SELECT COUNT(*) FILTER (WHERE p.box_id = $2) AS "total!",
+                COUNT(*) FILTER (WHERE po.bought_at > $3) AS "today!",
+                MIN(po.bought_at) FILTER (WHERE po.bought_at > $3) AS first_today,
+                MAX(po.bought_at) AS last
+            FROM products_owned po
+            INNER JOIN products p ON p.id = po.product_id
+            WHERE po.user_id = $1
+                AND (p.box_id = $2 OR p.box_id IN (SELECT id FROM box WHERE listing_id = $4))"#,
+            user_id,
+            box_id,
+            day_ago,
+            listing_id as Option<Uuid>
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if let Some(max) = limit.max_per_box {
+            if draws.total >= max as i64 {
+                return Err(ApiError::PurchaseLimitReached(None));
+            }
+        }
+        let mut retry_at = None;
+        if let (Some(max), Some(first_today)) = (limit.max_per_day, draws.first_today) {
+            if draws.today >= max as i64 {
+                retry_at = Some(first_today + Duration::days(1));
+            }
+        }
+        if let (Some(cooldown), Some(last)) = (limit.cooldown_seconds, draws.last) {
+            let ready_at = last + Duration::seconds(cooldown as i64);
+            if ready_at > now {
+                retry_at = retry_at.max(Some(ready_at));
+            }
+        }
+        match retry_at {
+            Some(retry_at) => Err(ApiError::PurchaseLimitReached(Some(retry_at))),
+            None => Ok(()),
+        }
+    }
+
+    // Turn the queue mode of a box on or off. Turning it off empties the queue.
+    pub async fn set_box_queue(pool: &Pool, data: (Uuid, Option<u32>, ReqId)) -> DResult<Vec<Box>> {
+        let (box_id, claim_seconds, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let bx = sqlx::query!(
+                    "SELECT listing_id, claim_seconds FROM box WHERE id = $1 FOR UPDATE",
+                    box_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                sqlx::query!(
+                    "UPDATE box SET claim_seconds = $1 WHERE id = $2",
+                    claim_seconds.map(|c| c as i32),
+                    box_id
+                )
+                .execute(&mut tx)
+                .await?;
+                if claim_seconds.is_none() {
+                    sqlx::query!("DELETE FROM box_queue WHERE box_id = $1", box_id)
+                        .execute(&mut tx)
+                        .await?;
+                }
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::BoxQueueChanged,
+                        Some(box_id),
+                        match claim_seconds {
+                            Some(seconds) => format!("Box {box_id} queued with {seconds}s claims"),
+                            None => format!("Box {box_id} queue turned off"),
+                        },
+                    )
+                    .before(&json!({ "claim_seconds": bx.claim_seconds }))
+                    .after(&json!({ "claim_seconds": claim_seconds })),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_boxes_of_listing(&pool, &bx.listing_id).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Lock the box so queue changes happen one at a time, drop expired claims and
+    // hand the claim to the next user. Returns the claim length of the box.
+    async fn advance_queue(conn: &mut PgConnection, box_id: &Uuid) -> DResult<u32> {
+        let claim_seconds = sqlx::query!(
+            "SELECT claim_seconds FROM box WHERE id = $1 FOR UPDATE",
+            box_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::InvalidId)?
+        .claim_seconds
+        .ok_or(ApiError::NoQueue)?;
+
+        let now = Utc::now().naive_utc();
+        sqlx::query!(
+            "DELETE FROM box_queue WHERE box_id = $1 AND expires_at <= $2",
+            box_id,
+            now
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            "UPDATE box_queue SET claimed_at = $2, expires_at = $3
+            WHERE id = (SELECT id FROM box_queue WHERE box_id = $1 ORDER BY joined_at, id LIMIT 1)
+            AND claimed_at IS NULL",
+            box_id,
+            now,
+            now + Duration::seconds(claim_seconds as i64)
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(claim_seconds as u32)
+    }
+
+    async fn queue_status(
+        conn: &mut PgConnection,
+        box_id: &Uuid,
+        user_id: &Uuid,
+        claim_seconds: u32,
+    ) -> DResult<QueueStatus> {
+        let entries = sqlx::query!(
+            "SELECT user_id, expires_at FROM box_queue WHERE box_id = $1 ORDER BY joined_at, id",
+            box_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(QueueStatus {
+            box_id: *box_id,
+            claim_seconds,
+            length: entries.len() as u32,
+            position: entries
+                .iter()
+                .position(|e| e.user_id == *user_id)
+                .map(|p| p as u32),
+            claim_expires_at: entries.first().and_then(|e| e.expires_at),
+        })
+    }
+
+    pub async fn join_queue(pool: &Pool, data: (Uuid, ReqId)) -> DResult<QueueStatus> {
+        let (box_id, req_id) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        DatabaseHand::advance_queue(&mut tx, &box_id).await?;
+        sqlx::query!(
+            "INSERT INTO box_queue (id, box_id, user_id, joined_at) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (box_id, user_id) DO NOTHING",
+            Uuid::new_v4(),
+            box_id,
+            req_id.id,
+            Utc::now().naive_utc()
+        )
+        .execute(&mut tx)
+        .await?;
+        // The user may be first in line and get the claim straight away
+        let claim_seconds = DatabaseHand::advance_queue(&mut tx, &box_id).await?;
+        let status = DatabaseHand::queue_status(&mut tx, &box_id, &req_id.id, claim_seconds).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                req_id.id,
+                AuditEvent::QueueJoined,
+                Some(box_id),
+                format!("Joined the queue of box {box_id}"),
+            )
+            .after(&status),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(status)
+    }
+
+    // Leave the queue, or give the claim up early
+    pub async fn leave_queue(pool: &Pool, data: (Uuid, ReqId)) -> DResult<QueueStatus> {
+        let (box_id, req_id) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        DatabaseHand::advance_queue(&mut tx, &box_id).await?;
+        sqlx::query!(
+            "DELETE FROM box_queue WHERE box_id = $1 AND user_id = $2",
+            box_id,
+            req_id.id
+        )
+        .execute(&mut tx)
+        .await?;
+        let claim_seconds = DatabaseHand::advance_queue(&mut tx, &box_id).await?;
+        let status = DatabaseHand::queue_status(&mut tx, &box_id, &req_id.id, claim_seconds).await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                req_id.id,
+                AuditEvent::QueueLeft,
+                Some(box_id),
+                format!("Left the queue of box {box_id}"),
+            )
+            .after(&status),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(status)
+    }
+
+    pub async fn get_queue_status(pool: &Pool, data: (Uuid, ReqId)) -> DResult<QueueStatus> {
+        let (box_id, req_id) = data;
+        let pool = pool.clone();
+        let mut tx = pool.begin().await?;
+        let claim_seconds = DatabaseHand::advance_queue(&mut tx, &box_id).await?;
+        let status = DatabaseHand::queue_status(&mut tx, &box_id, &req_id.id, claim_seconds).await?;
+        tx.commit().await?;
+        Ok(status)
+    }
+
+    // Boxes in queue mode can only be drawn from by the user holding the claim
+    async fn check_box_claim(
+        conn: &mut PgConnection,
+        box_id: &Uuid,
+        user_id: &Uuid,
+    ) -> DResult<()> {
+        let claim_seconds = match DatabaseHand::advance_queue(&mut *conn, box_id).await {
+            Ok(claim_seconds) => claim_seconds,
+            Err(ApiError::NoQueue) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let status = DatabaseHand::queue_status(conn, box_id, user_id, claim_seconds).await?;
+        match status.position {
+            Some(0) => Ok(()),
+            _ => Err(ApiError::NotYourTurn),
+        }
+    }
+
+    // Buy a product of a direct-sale listing at its own price
+    pub async fn buy_product(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Product> {
+        let (product_id, req_id) = data;
+        let pool = pool.clone();
+        DatabaseHand::check_user_active(&pool, &req_id.id).await?;
+        DatabaseHand::check_email_verified(&pool, &req_id.id).await?;
+        let mut tx = pool.begin().await?;
+        // Lock the product so two buyers can't both get the last one
+        let product = sqlx::query!(
+            "SELECT p.title, p.amount, p.price, l.tty, l.status AS listing_status FROM products p
+            INNER JOIN box b ON b.id = p.box_id
+            INNER JOIN listing l ON l.id = b.listing_id
+            WHERE p.id = $1 FOR UPDATE OF p",
+            product_id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(ApiError::InvalidId)?;
+        let price = match product.price {
+            Some(price)
+                if product.tty == ListingType::Direct.as_str()
+                    && product.listing_status == ListingStatus::Live.as_str() =>
+            {
+                price
+            }
+            _ => return Err(ApiError::WrongSaleMode),
+        };
+        if product.amount <= 0 {
+            return Err(ApiError::OutOfStock);
+        }
+
+        // Deducting points from user
+        let balance = sqlx::query!(
+            "UPDATE users SET points = points - $1 WHERE id = $2 AND points >= $1 RETURNING points",
+            price,
+            req_id.id
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(ApiError::InsufficientPoints)?
+        .points;
+
+        sqlx::query!(
+            "UPDATE products SET amount = amount - 1, status = amount - 1 = 0 WHERE id = $1",
+            product_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        // Adding the product purchase to products_owned
+        let t = Utc::now().naive_utc();
+        sqlx::query!(
+            "INSERT INTO products_owned(user_id, product_id, bought_at, id) VALUES($1, $2, $3, $4)",
+            req_id.id,
+            product_id,
+            t,
+            Uuid::new_v4()
+        )
+        .execute(&mut tx)
+        .await?;
+        let order = Order {
+            id: Uuid::new_v4(),
+            user_id: req_id.id,
+            product_id,
+            created_at: t,
+            status: "Pending".to_owned(),
+            product_name: product.title,
+        };
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                req_id.id,
+                AuditEvent::ProductBought,
+                Some(product_id),
+                format!("Bought \"{}\" for {price} points", order.product_name),
+            )
+            .after(&order),
+        )
+        .await?;
+        DatabaseHand::add_ledger_entry(
+            &mut tx,
+            LedgerEntry {
+                reference_id: Some(order.id),
+                ..LedgerEntry::new(req_id.id, -price, balance as u32, LedgerReason::Purchase)
+            },
+        )
+        .await?;
+        DatabaseHand::add_order(order, &mut tx).await?;
+        tx.commit().await?;
+
+        DatabaseHand::get_single_product(&pool, &product_id).await
+    }
+
+    fn select_weighted_random_product(products: &Vec<ProductIdent>) -> Option<ProductIdent> {
+        let total_sum = products.iter().map(|p| p.total).sum();
+        let mut rng = rand::thread_rng();
+        let random_number = rng.gen_range(0..total_sum);
+        let mut running_total = 0;
+        for product in products {
+            running_total += product.total;
+            if running_total >= random_number {
+                return Some(product.clone());
             }
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
         }
+        None
     }
 
-    // delete_single_product and return all the listings
-    pub async fn delete_product(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Listing>> {
-        let (product_id, req_id) = data;
+    // Get image path and extension from id and return as tuple
+    pub async fn get_image_p_and_ext(pool: &Pool, id: &Uuid) -> DResult<(String, String)> {
         let pool = pool.clone();
-        println!("{:?}", req_id);
-        match DatabaseHand::confirm_user_privilege(&pool, &req_id).await {
-            Ok(true) => {
-                let product_e = DatabaseHand::get_single_product(&pool, &product_id).await?;
-                let box_id = sqlx::query!("SELECT box_id FROM products WHERE id = $1", product_id)
-                    .fetch_one(&pool)
-                    .await?;
-                // sqlx::query!("DELETE FROM products WHERE id = $1", product_id)
-                //     .execute(&pool)
-                //     .await?;
-                let listing_id =
-                    sqlx::query!("SELECT listing_id FROM box WHERE id = $1", box_id.box_id)
-                        .fetch_one(&pool)
-                        .await?;
+        let image = sqlx::query!(
+            "SELECT path, extension FROM images WHERE for_id = $1",
+            id
+        )
+        .fetch_one(&pool)
+        .await?;
+        Ok((image.path, image.extension))
+    }
 
-                // Get boxes of listing
-                let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing_id.listing_id).await?;
+   
 
-                for bx in bxs {
-                    for product in bx.products {
-                        if product.title == product_e.title {
-                            
-                            sqlx::query!("DELETE FROM products WHERE id = $1", product.id)
-                                .execute(&pool)
-                                .await?;
-                        }
-                    }
-                }
 
-                let listings = DatabaseHand::get_listing(&pool).await?;
-                DatabaseHand::add_log(
-                    &pool,
-                    LogData {
-                        user_id: req_id.id.clone(),
-                        id: Uuid::new_v4(),
-                        created_at: Utc::now().naive_utc(),
-                        action: "Deleted Product".to_owned(),
-                    },
-                )
-                .await?;
-                Ok(listings)
-            }
+    pub async fn save_image(pool: &Pool, data: ImageData) -> DResult<String> {
+        let pool = pool.clone();
+        let ImageData { id, path, ext } = data;
+        let image = sqlx::query!(
+            "INSERT INTO images(path, for_id, extension) VALUES($1, $2, $3) RETURNING path",
+            path,
+            id,
+            ext
+        )
+        .fetch_one(&pool)
+        .await?;
 
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
-        }
+        Ok(image.path.clone())
     }
 
-    pub async fn get_box_cost(pool: &Pool, box_id: &Uuid) -> DResult<i32> {
+    // Logging
+    // Logs added while handling a request record the request's id and address
+    pub async fn add_log<'e, E: PgExecutor<'e>>(executor: E, data: LogData) -> DResult<()> {
+        let request = request::current();
+        let LogData {
+            user_id,
+            id,
+            created_at,
+            action,
+            event,
+            entity_type,
+            entity_id,
+            before,
+            after,
+            request_id,
+            ip,
+        } = data;
+        sqlx::query!(
+            "INSERT INTO logs(user_id, id, created_at, action, event, entity_type, entity_id, before, after, request_id, ip)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            user_id,
+            id,
+            created_at,
+            action,
+            event.map(|e| e.as_str()),
+            entity_type.map(|e| e.as_str()),
+            entity_id,
+            before,
+            after,
+            request_id.or(request.as_ref().map(|r| r.id)),
+            ip.or(request.and_then(|r| r.ip))
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_log(pool: &Pool, id: &Uuid) -> DResult<LogData> {
         let pool = pool.clone();
-        let cost = sqlx::query!("SELECT price FROM box WHERE id = $1", box_id)
+        let log = sqlx::query_as!(DLog, "SELECT * FROM logs WHERE id = $1", id)
             .fetch_one(&pool)
             .await?;
-
-        Ok(cost.price)
+        Ok(log.into())
     }
 
-    pub async fn deduct_points_from_user(pool: &Pool, data: (u32, Uuid)) -> DResult<()> {
-        let (points, user_id) = data;
-        let user_points = DatabaseHand::get_user_points(&pool, &user_id).await?;
-        let p = user_points - points;
+    // Get one page of logs matching the query, newest first. The cursor is the creation
+    // time and id of the last log of the previous page.
+    pub async fn get_logs(pool: &Pool, query: &LogQuery) -> DResult<Page<LogData>> {
         let pool = pool.clone();
-        sqlx::query!(
-            "UPDATE users SET points = $1 WHERE id = $2",
-            p as i32,
-            user_id
+        let limit = query.limit.unwrap_or(50).clamp(1, 500) as i64;
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let (created_at, id) = cursor.split_once('_').ok_or(ApiError::InvalidCursor)?;
+                let created_at = NaiveDateTime::parse_from_str(created_at, CURSOR_TIME_FORMAT)
+                    .map_err(|_| ApiError::InvalidCursor)?;
+                let id = Uuid::parse_str(id).map_err(|_| ApiError::InvalidCursor)?;
+                Some((created_at, id))
+            }
+            None => None,
+        };
+        let logs = sqlx::query_as!(
+            DLog,
+            "SELECT * FROM logs
+            WHERE ($1::uuid IS NULL OR user_id = $1)
+            AND ($2::text IS NULL OR event = $2)
+            AND ($3::text IS NULL OR entity_type = $3)
+            AND ($4::uuid IS NULL OR entity_id = $4)
+            AND ($5::text IS NULL OR strpos(lower(action), lower($5)) > 0)
+            AND ($6::timestamp IS NULL OR created_at >= $6)
+            AND ($7::timestamp IS NULL OR created_at < $7)
+            AND ($8::timestamp IS NULL OR (created_at, id) < ($8, $9::uuid))
+            ORDER BY created_at DESC, id DESC
+            LIMIT $10",
+            query.user_id,
+            query.event.map(|e| e.as_str()),
+            query.entity_type.map(|e| e.as_str()),
+            query.entity_id,
+            query.action,
+            query.from,
+            query.to,
+            cursor.map(|(created_at, _)| created_at),
+            cursor.map(|(_, id)| id),
+            // One extra row tells us whether there is a next page
+            limit + 1
         )
-        .execute(&pool)
+        .fetch_all(&pool)
         .await?;
-        Ok(())
+
+        let next_cursor = match logs.len() as i64 > limit {
+            true => logs.get(limit as usize - 1).map(|l| {
+                format!("{}_{}", l.created_at.format(CURSOR_TIME_FORMAT), l.id)
+            }),
+            false => None,
+        };
+        let items = logs
+            .into_iter()
+            .take(limit as usize)
+            .map(|l| l.into())
+            .collect();
+        Ok(Page { items, next_cursor })
     }
-    // Confirm user privilege also
-    pub async fn add_product_to_box(
+
+    // Get the latest `limit` logs matching the stream filters, oldest first
+    pub async fn get_recent_logs(
         pool: &Pool,
-        data: (ReqId, Uuid, Vec<Product>),
-    ) -> DResult<Vec<Listing>> {
-        let (req_id, box_id, products) = data;
+        query: &LogStreamQuery,
+        limit: u32,
+    ) -> DResult<Vec<LogData>> {
         let pool = pool.clone();
-        match DatabaseHand::confirm_user_privilege(&pool, &req_id).await {
-            Ok(true) => {
-                for product in products {
-                    sqlx::query!(
-                        "INSERT INTO products
-                (box_id, title, id, description, level, status, created_at, amount, image, ini_amount)
-                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
-                        box_id,
-                        product.title,
-                        product.id,
-                        product.description,
-                        product.level as i32,
-                        product.status,
-                        product.created_at,
-                        product.amount,
-                        product.image,
-                        product.ini_amount
-                    )
-                    .execute(&pool)
-                    .await?;
-                }
+        let logs = sqlx::query_as!(
+            DLog,
+            "SELECT * FROM logs
+            WHERE ($1::uuid IS NULL OR user_id = $1)
+            AND ($2::text IS NULL OR strpos(lower(action), lower($2)) > 0)
+            ORDER BY created_at DESC
+            LIMIT $3",
+            query.user_id,
+            query.action,
+            limit as i64
+        )
+        .fetch_all(&pool)
+        .await?;
+        Ok(logs.into_iter().rev().map(|l| l.into()).collect())
+    }
 
-                let listing = DatabaseHand::get_listing(&pool).await?;
-                DatabaseHand::add_log(
-                    &pool,
-                    LogData {
-                        user_id: req_id.id.clone(),
-                        id: Uuid::new_v4(),
-                        created_at: Utc::now().naive_utc(),
-                        action: "Product Added to a box".to_owned(),
-                    },
-                )
-                .await?;
-                Ok(listing)
-            }
-            Ok(false) | Err(_) => Err(ApiError::NotSuperuser),
-        }
+    pub async fn get_listing_from_id(pool: &Pool, id: &Uuid) -> DResult<Listing> {
+        let mut conn = pool.acquire().await?;
+        DatabaseHand::load_listings(&mut conn, &[*id])
+            .await?
+            .pop()
+            .ok_or(ApiError::DatabaseError(sqlx::Error::RowNotFound))
     }
-    pub async fn get_single_product(pool: &Pool, product_id: &Uuid) -> DResult<Product> {
+
+    pub async fn add_order<'e, E: PgExecutor<'e>>(order: Order, executor: E) -> DResult<()> {
+        let Order {
+            id,
+            user_id,
+            created_at,
+            status,
+            product_id,
+            product_name
+        } = order;
+        sqlx::query!(
+            "INSERT INTO order_tracking(id, user_id, product_id, created_at, status, product_name) VALUES($1, $2, $3, $4, $5, $6)",
+            id,
+            user_id,
+            product_id,
+            created_at,
+            status,
+            product_name
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    // Get user's orders from user_id
+    pub async fn get_orders(pool: &Pool, user_id: &Uuid) -> DResult<Vec<Order>> {
         let pool = pool.clone();
-        let product = sqlx::query_as!(DProduct, "SELECT * FROM products WHERE id = $1", product_id)
-            .fetch_one(&pool)
-            .await?
-            .into();
-        Ok(product)
+        let orders = sqlx::query_as!(
+            Order,
+            "SELECT * FROM order_tracking WHERE user_id = $1",
+            user_id
+        )
+        .fetch_all(&pool)
+        .await?;
+        Ok(orders)
     }
-    // Get product amount from product's id
-    pub async fn get_product_amount(pool: &Pool, product_id: &Uuid) -> DResult<i32> {
+
+    // Get all the orders
+    pub async fn get_all_orders(pool: &Pool) -> DResult<Vec<Order>> {
         let pool = pool.clone();
-        let amount = sqlx::query!("SELECT amount FROM products WHERE id = $1", product_id)
-            .fetch_one(&pool)
+        let orders = sqlx::query_as!(Order, "SELECT * FROM order_tracking")
+            .fetch_all(&pool)
             .await?;
-        Ok(amount.amount)
+        Ok(orders)
     }
-    pub async fn buy_box(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Product> {
-        let (box_id, req_id) = data;
-        let pool = pool.clone();
-        let points = DatabaseHand::get_user_points(&pool, &req_id.id).await?;
-        let cost = DatabaseHand::get_box_cost(&pool, &box_id).await?;
 
-        // Checking if user has enough points
-        if points == 0 {
-            return Err(ApiError::InsufficientPoints);
-        } else {
-            if points < cost as u32 {
-                return Err(ApiError::InsufficientPoints);
+    // Change the status of an order, e.g. when it ships
+    pub async fn update_order_status(pool: &Pool, data: (Uuid, String, ReqId)) -> DResult<Order> {
+        let (order_id, status, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::ManageOrders).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = sqlx::query_as!(
+                    Order,
+                    "SELECT * FROM order_tracking WHERE id = $1 FOR UPDATE",
+                    order_id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                let order = sqlx::query_as!(
+                    Order,
+                    "UPDATE order_tracking SET status = $1 WHERE id = $2 RETURNING *",
+                    status,
+                    order_id
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::OrderUpdated,
+                        Some(order_id),
+                        format!("Order {order_id} set to {}", order.status),
+                    )
+                    .before(&before)
+                    .after(&order),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(order)
             }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::ManageOrders)),
         }
+    }
 
-        // Deducting points from user
-        DatabaseHand::deduct_points_from_user(&pool, (cost as u32, req_id.id)).await?;
+    // Roles
 
-        // Selecting a random product
-        let mut products_idents = Vec::new();
-        let products = DatabaseHand::get_products(&pool, &box_id)
-            .await?
-            .into_iter()
-            .filter(|prod| prod.status == false)
-            .collect::<Vec<Product>>();
+    // Load every role, or only the roles of `user_id`
+    async fn load_roles<'e, E: PgExecutor<'e>>(
+        executor: E,
+        user_id: Option<Uuid>,
+    ) -> DResult<Vec<Role>> {
+        let roles = sqlx::query_as!(
+            DRole,
+            r#"SELECT r.id, r.name, r.require_two_factor, r.created_at,
+                COALESCE(array_agg(rp.permission ORDER BY rp.permission)
+                    FILTER (WHERE rp.permission IS NOT NULL), '{}') AS "permissions!"
+            FROM role r
+            LEFT JOIN role_permissions rp ON rp.role_id = r.id
+            WHERE $1::uuid IS NULL
+            OR r.id IN (SELECT role_id FROM user_roles WHERE user_id = $1)
+            GROUP BY r.id
+            ORDER BY r.created_at, r.name"#,
+            user_id
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(roles.into_iter().map(|r| r.into()).collect())
+    }
 
-        for product in &products {
-            let product: ProductIdent = product.clone().into();
-            products_idents.push(product);
-        }
+    pub async fn get_roles(pool: &Pool) -> DResult<Vec<Role>> {
+        DatabaseHand::load_roles(pool, None).await
+    }
 
-        let prod = DatabaseHand::select_weighted_random_product(&products_idents);
-        match prod {
-            Some(prod) => {
+    pub async fn get_user_roles(pool: &Pool, user_id: &Uuid) -> DResult<Vec<Role>> {
+        DatabaseHand::load_roles(pool, Some(*user_id)).await
+    }
+
+    pub async fn create_role(pool: &Pool, data: (Role, ReqId)) -> DResult<Role> {
+        let (role, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::ManageRoles).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let taken = sqlx::query!("SELECT id FROM role WHERE name = $1", role.name)
+                    .fetch_optional(&mut tx)
+                    .await?;
+                if taken.is_some() {
+                    return Err(ApiError::NameTaken);
+                }
                 sqlx::query!(
-                    "UPDATE products SET amount = amount - 1 WHERE id = $1",
-                    &prod.id
+                    "INSERT INTO role (id, name, require_two_factor, created_at) VALUES ($1, $2, $3, $4)",
+                    role.id,
+                    role.name,
+                    role.require_two_factor,
+                    role.created_at
                 )
-                .execute(&pool)
+                .execute(&mut tx)
                 .await?;
-                
-                if DatabaseHand::get_product_amount(&pool, &prod.id).await? == 0 {
-                    sqlx::query!(
-                        "UPDATE products SET status = true WHERE id = $1",
-                        &prod.id
+                let permissions = role
+                    .permissions
+                    .iter()
+                    .map(|p| p.as_str().to_owned())
+                    .collect::<Vec<_>>();
+                sqlx::query!(
+                    "INSERT INTO role_permissions (role_id, permission)
+                    SELECT $1, permission FROM unnest($2::text[]) AS p(permission)
+                    ON CONFLICT DO NOTHING",
+                    role.id,
+                    &permissions
+                )
+                .execute(&mut tx)
+                .await?;
+                let role = DatabaseHand::load_roles(&mut tx, None)
+                    .await?
+                    .into_iter()
+                    .find(|r| r.id == role.id)
+                    .ok_or(ApiError::InvalidId)?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::RoleCreated,
+                        Some(role.id),
+                        format!("Role {} created", role.name),
                     )
-                    .execute(&pool)
-                    .await?;
-                }
+                    .after(&role),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(role)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::ManageRoles)),
+        }
+    }
 
-                // Adding the product purchase to products_owned
-                let t = Utc::now().naive_utc();
-                sqlx::query!(
-                    "INSERT INTO products_owned(user_id, product_id, bought_at, id) 
-                VALUES($1, $2, $3, $4)",
-                    &req_id.id,
-                    &prod.id,
-                    t,
-                    Uuid::new_v4()
+    // Delete a role, it is taken away from every user who has it
+    pub async fn delete_role(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Role>> {
+        let (role_id, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::ManageRoles).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = DatabaseHand::load_roles(&mut tx, None)
+                    .await?
+                    .into_iter()
+                    .find(|r| r.id == role_id)
+                    .ok_or(ApiError::InvalidId)?;
+                sqlx::query!("DELETE FROM role WHERE id = $1", role_id)
+                    .execute(&mut tx)
+                    .await?;
+                DatabaseHand::check_role_managers(&mut tx).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::RoleDeleted,
+                        Some(role_id),
+                        format!("Role {} deleted", before.name),
+                    )
+                    .before(&before),
                 )
-                .execute(&pool)
                 .await?;
+                tx.commit().await?;
+                DatabaseHand::get_roles(&pool).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::ManageRoles)),
+        }
+    }
 
-                
-                let product = DatabaseHand::get_single_product(&pool, &prod.id).await?;
-                let order = Order {
-                    id: Uuid::new_v4(),
-                    user_id: req_id.id,
-                    product_id: prod.id,
-                    created_at: t,
-                    status: "Pending".to_owned(),
-                    product_name: product.clone().title,
+    // Make the permissions of a role apply only to its users with two-factor authentication
+    pub async fn set_role_two_factor(pool: &Pool, data: (Uuid, bool, ReqId)) -> DResult<Role> {
+        let (role_id, required, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::ManageRoles).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = DatabaseHand::load_roles(&mut tx, None)
+                    .await?
+                    .into_iter()
+                    .find(|r| r.id == role_id)
+                    .ok_or(ApiError::InvalidId)?;
+                sqlx::query!(
+                    "UPDATE role SET require_two_factor = $1 WHERE id = $2",
+                    required,
+                    role_id
+                )
+                .execute(&mut tx)
+                .await?;
+                let after = Role {
+                    require_two_factor: required,
+                    ..before.clone()
                 };
-                DatabaseHand::add_order(order, &pool).await?;
-
-                Ok(product)
+                DatabaseHand::check_role_managers(&mut tx).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::RoleUpdated,
+                        Some(role_id),
+                        format!(
+                            "Role {} now {} two-factor authentication",
+                            before.name,
+                            if required { "requires" } else { "doesn't require" }
+                        ),
+                    )
+                    .before(&before)
+                    .after(&after),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(after)
             }
-            None => Err(ApiError::SelectionError),
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::ManageRoles)),
         }
     }
 
-    fn select_weighted_random_product(products: &Vec<ProductIdent>) -> Option<ProductIdent> {
-        let total_sum = products.iter().map(|p| p.total).sum();
-        let mut rng = rand::thread_rng();
-        let random_number = rng.gen_range(0..total_sum);
-        let mut running_total = 0;
-        for product in products {
-            running_total += product.total;
-            if running_total >= random_number {
-                return Some(product.clone());
+    // Replace the roles of a user and return them
+    pub async fn set_user_roles(pool: &Pool, data: (Uuid, Vec<Uuid>, ReqId)) -> DResult<Vec<Role>> {
+        let (user_id, role_ids, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::ManageRoles).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query!("SELECT id FROM users WHERE id = $1", user_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or(ApiError::InvalidId)?;
+                let found = sqlx::query!(
+                    r#"SELECT COUNT(*) AS "count!" FROM role WHERE id = ANY($1)"#,
+                    &role_ids
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                let mut unique = role_ids.clone();
+                unique.sort();
+                unique.dedup();
+                if found.count as usize != unique.len() {
+                    return Err(ApiError::InvalidId);
+                }
+                let before = DatabaseHand::load_roles(&mut tx, Some(user_id)).await?;
+                sqlx::query!("DELETE FROM user_roles WHERE user_id = $1", user_id)
+                    .execute(&mut tx)
+                    .await?;
+                sqlx::query!(
+                    "INSERT INTO user_roles (user_id, role_id)
+                    SELECT $1, role_id FROM unnest($2::uuid[]) AS r(role_id) ON CONFLICT DO NOTHING",
+                    user_id,
+                    &unique
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::check_role_managers(&mut tx).await?;
+                let roles = DatabaseHand::load_roles(&mut tx, Some(user_id)).await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::UserRolesChanged,
+                        Some(user_id),
+                        format!("Roles of user {user_id} updated"),
+                    )
+                    .before(&before)
+                    .after(&roles),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(roles)
             }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::ManageRoles)),
         }
-        None
     }
 
-    // Get image path and extension from id and return as tuple
-    pub async fn get_image_p_and_ext(pool: &Pool, id: &Uuid) -> DResult<(String, String)> {
-        let pool = pool.clone();
-        let image = sqlx::query!(
-            "SELECT path, extension FROM images WHERE for_id = $1",
-            id
+    // Nobody could hand out roles anymore if the last role manager lost the permission
+    async fn check_role_managers(conn: &mut PgConnection) -> DResult<()> {
+        let managers = sqlx::query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM user_roles ur
+                INNER JOIN role_permissions rp ON rp.role_id = ur.role_id
+                INNER JOIN role r ON r.id = ur.role_id
+                INNER JOIN users u ON u.id = ur.user_id
+                WHERE rp.permission = $1 AND (NOT r.require_two_factor OR u.totp_enabled)
+            ) AS "exists!""#,
+            Permission::ManageRoles.as_str()
         )
-        .fetch_one(&pool)
+        .fetch_one(&mut *conn)
         .await?;
-        Ok((image.path, image.extension))
+        match managers.exists {
+            true => Ok(()),
+            false => Err(ApiError::LastRoleManager),
+        }
     }
 
-   
-
+    pub async fn get_categories(pool: &Pool) -> DResult<Vec<Category>> {
+        let pool = pool.clone();
+        let categories =
+            sqlx::query_as!(Category, "SELECT * FROM category ORDER BY position, name")
+                .fetch_all(&pool)
+                .await?;
+        Ok(categories)
+    }
 
-    pub async fn save_image(pool: &Pool, data: ImageData) -> DResult<String> {
+    // Get the categories as a tree with the number of live listings under each node
+    pub async fn get_category_tree(pool: &Pool) -> DResult<Vec<CategoryNode>> {
         let pool = pool.clone();
-        let ImageData { id, path, ext } = data;
-        let image = sqlx::query!(
-            "INSERT INTO images(path, for_id, extension) VALUES($1, $2, $3) RETURNING path",
-            path,
-            id,
-            ext
+        let categories = DatabaseHand::get_categories(&pool).await?;
+        let counts = sqlx::query!(
+            r#"SELECT category_id AS "category_id!", COUNT(*) AS "count!" FROM listing
+            WHERE status = 'live' AND category_id IS NOT NULL
+            GROUP BY category_id"#
         )
-        .fetch_one(&pool)
+        .fetch_all(&pool)
         .await?;
 
-        Ok(image.path.clone())
+        fn build(
+            parent_id: Option<Uuid>,
+            categories: &[Category],
+            counts: &HashMap<Uuid, i64>,
+        ) -> Vec<CategoryNode> {
+            categories
+                .iter()
+                .filter(|c| c.parent_id == parent_id)
+                .map(|c| {
+                    let children = build(Some(c.id), categories, counts);
+                    let own = counts.get(&c.id).copied().unwrap_or(0) as u32;
+                    CategoryNode {
+                        id: c.id,
+                        name: c.name.clone(),
+                        slug: c.slug.clone(),
+                        position: c.position,
+                        listing_count: own + children.iter().map(|n| n.listing_count).sum::<u32>(),
+                        children,
+                    }
+                })
+                .collect()
+        }
+        let counts = counts
+            .into_iter()
+            .map(|c| (c.category_id, c.count))
+            .collect::<HashMap<_, _>>();
+        Ok(build(None, &categories, &counts))
     }
 
-    // Logging
-    pub async fn add_log(pool: &Pool, data: LogData) -> DResult<()> {
-        let pool = pool.clone();
-        let LogData {
-            user_id,
-            id,
-            created_at,
-            action,
-        } = data;
-        sqlx::query!(
-            "INSERT INTO logs(user_id, id, created_at, action) VALUES($1, $2, $3, $4)",
-            user_id,
-            id,
-            created_at,
-            action
+    // Check that the slug is free and that the parent exists and isn't the category
+    // itself or one of its descendants
+    async fn check_category(conn: &mut PgConnection, category: &Category) -> DResult<()> {
+        let taken = sqlx::query!(
+            "SELECT id FROM category WHERE slug = $1 AND id <> $2",
+            category.slug,
+            category.id
         )
-        .execute(&pool)
+        .fetch_optional(&mut *conn)
         .await?;
+        if taken.is_some() {
+            return Err(ApiError::SlugTaken);
+        }
+        if let Some(parent_id) = category.parent_id {
+            let ancestors = sqlx::query!(
+                r#"WITH RECURSIVE ancestors AS (
+                    SELECT id, parent_id FROM category WHERE id = $1
+                    UNION
+                    SELECT c.id, c.parent_id FROM category c
+                    INNER JOIN ancestors a ON a.parent_id = c.id
+                )
+                SELECT id AS "id!" FROM ancestors"#,
+                parent_id
+            )
+            .fetch_all(&mut *conn)
+            .await?;
+            if ancestors.is_empty() || ancestors.iter().any(|a| a.id == category.id) {
+                return Err(ApiError::InvalidCategoryParent);
+            }
+        }
         Ok(())
     }
 
-    pub async fn get_logs(pool: &Pool) -> DResult<Vec<LogData>> {
+    // Get i
+
+    
+    pub async fn create_category(pool: &Pool, data: (Category, ReqId)) -> DResult<Category> {
+        let (category, req_id) = data;
         let pool = pool.clone();
-        let logs = sqlx::query_as!(LogData, "SELECT * FROM logs")
-            .fetch_all(&pool)
-            .await?;
-        Ok(logs)
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                DatabaseHand::check_category(&mut tx, &category).await?;
+                let Category {
+                    name,
+                    created_at,
+                    id,
+                    parent_id,
+                    slug,
+                    ..
+                } = category;
+                // New categories go after their siblings
+                let category = sqlx::query_as!(
+                    Category,
+                    "INSERT INTO category(name, created_at, id, parent_id, slug, position)
+                    VALUES($1, $2, $3, $4, $5, (
+                        SELECT COALESCE(MAX(position) + 1, 0) FROM category WHERE parent_id IS NOT DISTINCT FROM $4
+                    )) RETURNING *",
+                    name,
+                    created_at,
+                    id,
+                    parent_id,
+                    slug
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::CategoryCreated,
+                        Some(category.id),
+                        format!("Category {} created", category.id),
+                    )
+                    .after(&category),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(category)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
     }
 
-    pub async fn get_listing_from_id(pool: &Pool, id: &Uuid) -> DResult<Listing> {
+    // Update a category, an empty slug keeps the stored one. A category that moves
+    // to another parent goes after its new siblings.
+    pub async fn update_category(pool: &Pool, data: (Category, ReqId)) -> DResult<Category> {
+        let (mut category, req_id) = data;
         let pool = pool.clone();
-        let listing = sqlx::query_as!(DListing, "SELECT * FROM listing WHERE id = $1", id)
-            .fetch_one(&pool)
-            .await?;
-        let mut listing: Listing = listing.into();
-        let listing_image = DatabaseHand::get_image(&pool, &listing.id).await?;
-        let ed_img = listing_image.split('/').collect::<Vec<_>>();
-        listing.image = BASE_URL.to_owned() + "/get/image/" + ed_img.last().unwrap();
-        let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing.id).await?;
-        listing.box_count = bxs.len() as u32;
-        listing.boxes = bxs;
-        Ok(listing)
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = sqlx::query_as!(
+                    Category,
+                    "SELECT * FROM category WHERE id = $1 FOR UPDATE",
+                    category.id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                if category.slug.is_empty() {
+                    category.slug = before.slug.clone();
+                }
+                DatabaseHand::check_category(&mut tx, &category).await?;
+                let category = sqlx::query_as!(
+                    Category,
+                    "UPDATE category SET name = $1, slug = $2, parent_id = $3, position = CASE
+                        WHEN parent_id IS NOT DISTINCT FROM $3 THEN position
+                        ELSE (
+                            SELECT COALESCE(MAX(position) + 1, 0) FROM category
+                            WHERE parent_id IS NOT DISTINCT FROM $3
+                        )
+                    END
+                    WHERE id = $4 RETURNING *",
+                    category.name,
+                    category.slug,
+                    category.parent_id,
+                    category.id
+                )
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::InvalidId)?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::CategoryUpdated,
+                        Some(category.id),
+                        format!("Category {} updated", category.id),
+                    )
+                    .before(&before)
+                    .after(&category),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(category)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
     }
 
-    pub async fn add_order(order: Order, pool: &Pool) -> DResult<()> {
+    // Delete a category. Its children move up to the end of its parent, in their
+    // current order, and its listings are left without a category.
+    pub async fn delete_category(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Category>> {
+        let (category_id, req_id) = data;
         let pool = pool.clone();
-        let Order {
-            id,
-            user_id,
-            created_at,
-            status,
-            product_id,
-            product_name
-        } = order;
-        sqlx::query!(
-            "INSERT INTO order_tracking(id, user_id, product_id, created_at, status, product_name) VALUES($1, $2, $3, $4, $5, $6)",
-            id,
-            user_id,
-            product_id,
-            created_at,
-            status,
-            product_name
-        )
-        .execute(&pool)
-        .await?;
-        Ok(())
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let category =
+                    sqlx::query_as!(Category, "SELECT * FROM category WHERE id = $1", category_id)
+                        .fetch_optional(&mut tx)
+                        .await?
+                        .ok_or(ApiError::InvalidId)?;
+                sqlx::query!(
+                    "UPDATE category SET parent_id = $1, position = last.position + children.rank
+                    FROM (
+                        SELECT id, ROW_NUMBER() OVER (ORDER BY position, name)::int AS rank
+                        FROM category WHERE parent_id = $2
+                    ) AS children, (
+                        SELECT COALESCE(MAX(position), -1) AS position FROM category
+                        WHERE parent_id IS NOT DISTINCT FROM $1 AND id <> $2
+                    ) AS last
+                    WHERE category.id = children.id",
+                    category.parent_id,
+                    category_id
+                )
+                .execute(&mut tx)
+                .await?;
+                sqlx::query!("DELETE FROM category WHERE id = $1", category_id)
+                    .execute(&mut tx)
+                    .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::CategoryDeleted,
+                        Some(category_id),
+                        format!("Category {category_id} deleted"),
+                    )
+                    .before(&category),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_categories(&pool).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
     }
 
-    // Get user's orders from user_id
-    pub async fn get_orders(pool: &Pool, user_id: &Uuid) -> DResult<Vec<Order>> {
+    // Reorder the children of a category, `ids` has to contain every child exactly once
+    pub async fn reorder_categories(
+        pool: &Pool,
+        data: (Option<Uuid>, Vec<Uuid>, ReqId),
+    ) -> DResult<Vec<Category>> {
+        let (parent_id, mut ids, req_id) = data;
         let pool = pool.clone();
-        let orders = sqlx::query_as!(
-            Order,
-            "SELECT * FROM order_tracking WHERE user_id = $1",
-            user_id
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let before = sqlx::query!(
+                    "SELECT id FROM category WHERE parent_id IS NOT DISTINCT FROM $1
+                    ORDER BY position, name",
+                    parent_id
+                )
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|c| c.id)
+                .collect::<Vec<_>>();
+                let mut children = before.clone();
+                let order = ids.clone();
+                children.sort();
+                ids.sort();
+                if children != ids {
+                    return Err(ApiError::InvalidId);
+                }
+                sqlx::query!(
+                    "UPDATE category SET position = o.position - 1
+                    FROM unnest($1::uuid[]) WITH ORDINALITY AS o(id, position)
+                    WHERE category.id = o.id",
+                    &order
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::CategoriesReordered,
+                        parent_id,
+                        match parent_id {
+                            Some(parent_id) => format!("Categories under {parent_id} reordered"),
+                            None => "Top level categories reordered".to_owned(),
+                        },
+                    )
+                    .before(&before)
+                    .after(&order),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_categories(&pool).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    pub async fn get_tags(pool: &Pool, kind: Option<TagKind>) -> DResult<Vec<Tag>> {
+        let pool = pool.clone();
+        Ok(sqlx::query_as!(
+            Tag,
+            "SELECT * FROM tag WHERE $1::text IS NULL OR kind = $1 ORDER BY kind, name",
+            kind.map(|k| k.as_str())
         )
         .fetch_all(&pool)
-        .await?;
-        Ok(orders)
+        .await?)
     }
 
-    // Get all the orders
-    pub async fn get_all_orders(pool: &Pool) -> DResult<Vec<Order>> {
+    pub async fn create_tag(pool: &Pool, data: (Tag, ReqId)) -> DResult<Tag> {
+        let (tag, req_id) = data;
         let pool = pool.clone();
-        let orders = sqlx::query_as!(Order, "SELECT * FROM order_tracking")
-            .fetch_all(&pool)
-            .await?;
-        Ok(orders)
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let taken = sqlx::query!("SELECT id FROM tag WHERE slug = $1", tag.slug)
+                    .fetch_optional(&mut tx)
+                    .await?;
+                if taken.is_some() {
+                    return Err(ApiError::SlugTaken);
+                }
+                let tag = sqlx::query_as!(
+                    Tag,
+                    "INSERT INTO tag(id, name, slug, kind, created_at) VALUES($1, $2, $3, $4, $5) RETURNING *",
+                    tag.id,
+                    tag.name,
+                    tag.slug,
+                    tag.kind,
+                    tag.created_at
+                )
+                .fetch_one(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::TagCreated,
+                        Some(tag.id),
+                        format!("Tag {} created", tag.id),
+                    )
+                    .after(&tag),
+                )
+                .await?;
+                tx.commit().await?;
+                Ok(tag)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
     }
 
-    pub async fn get_categories(pool: &Pool) -> DResult<Vec<Category>> {
+    // Delete a tag, it is removed from every listing and product carrying it
+    pub async fn delete_tag(pool: &Pool, data: (Uuid, ReqId)) -> DResult<Vec<Tag>> {
+        let (tag_id, req_id) = data;
         let pool = pool.clone();
-        let categories = sqlx::query_as!(Category, "SELECT * FROM category")
-            .fetch_all(&pool)
-            .await?;
-        Ok(categories)
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                let deleted =
+                    sqlx::query_as!(Tag, "DELETE FROM tag WHERE id = $1 RETURNING *", tag_id)
+                        .fetch_optional(&mut tx)
+                        .await?
+                        .ok_or(ApiError::InvalidId)?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::TagDeleted,
+                        Some(tag_id),
+                        format!("Tag {tag_id} deleted"),
+                    )
+                    .before(&deleted),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_tags(&pool, None).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
     }
 
-    // Get i
+    // Replace the tags of a listing
+    pub async fn set_listing_tags(
+        pool: &Pool,
+        data: (Uuid, Vec<Uuid>, ReqId),
+    ) -> DResult<Listing> {
+        let (listing_id, tag_ids, req_id) = data;
+        let pool = pool.clone();
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query!("SELECT id FROM listing WHERE id = $1", listing_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or(ApiError::InvalidId)?;
+                DatabaseHand::check_tags(&mut tx, &tag_ids).await?;
+                let before = sqlx::query!(
+                    "DELETE FROM listing_tags WHERE listing_id = $1 RETURNING tag_id",
+                    listing_id
+                )
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|t| t.tag_id)
+                .collect::<Vec<_>>();
+                sqlx::query!(
+                    "INSERT INTO listing_tags(listing_id, tag_id)
+                    SELECT $1, tag_id FROM unnest($2::uuid[]) AS t(tag_id) ON CONFLICT DO NOTHING",
+                    listing_id,
+                    &tag_ids
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::ListingTagged,
+                        Some(listing_id),
+                        format!("Tags of listing {listing_id} updated"),
+                    )
+                    .before(&before)
+                    .after(&tag_ids),
+                )
+                .await?;
+                tx.commit().await?;
+                DatabaseHand::get_single_listing(&pool, &listing_id).await
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
 
-    
-    pub async fn create_category(pool: &Pool, data: &Category) -> DResult<Category> {
+    // Replace the tags of a product
+    pub async fn set_product_tags(
+        pool: &Pool,
+        data: (Uuid, Vec<Uuid>, ReqId),
+    ) -> DResult<Vec<Tag>> {
+        let (product_id, tag_ids, req_id) = data;
         let pool = pool.clone();
-        let Category { name, created_at, id } = data;
-        let category = sqlx::query_as!(
-            Category,
-            "INSERT INTO category(name, created_at, id) VALUES($1, $2, $3) RETURNING *",
-            name,
-            created_at,
-            id
+        match DatabaseHand::confirm_permission(&pool, &req_id, Permission::EditCatalogue).await {
+            Ok(true) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query!("SELECT id FROM products WHERE id = $1", product_id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or(ApiError::InvalidId)?;
+                DatabaseHand::check_tags(&mut tx, &tag_ids).await?;
+                let before = sqlx::query!(
+                    "DELETE FROM product_tags WHERE product_id = $1 RETURNING tag_id",
+                    product_id
+                )
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|t| t.tag_id)
+                .collect::<Vec<_>>();
+                sqlx::query!(
+                    "INSERT INTO product_tags(product_id, tag_id)
+                    SELECT $1, tag_id FROM unnest($2::uuid[]) AS t(tag_id) ON CONFLICT DO NOTHING",
+                    product_id,
+                    &tag_ids
+                )
+                .execute(&mut tx)
+                .await?;
+                DatabaseHand::add_log(
+                    &mut tx,
+                    LogData::new(
+                        req_id.id,
+                        AuditEvent::ProductTagged,
+                        Some(product_id),
+                        format!("Tags of product {product_id} updated"),
+                    )
+                    .before(&before)
+                    .after(&tag_ids),
+                )
+                .await?;
+                let tags = sqlx::query_as!(
+                    Tag,
+                    "SELECT t.* FROM tag t INNER JOIN product_tags pt ON pt.tag_id = t.id
+                    WHERE pt.product_id = $1 ORDER BY t.kind, t.name",
+                    product_id
+                )
+                .fetch_all(&mut tx)
+                .await?;
+                tx.commit().await?;
+                Ok(tags)
+            }
+            Ok(false) | Err(_) => Err(ApiError::MissingPermission(Permission::EditCatalogue)),
+        }
+    }
+
+    // Every id has to belong to an existing tag
+    async fn check_tags(conn: &mut PgConnection, tag_ids: &[Uuid]) -> DResult<()> {
+        let found = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM tag WHERE id = ANY($1)"#,
+            tag_ids
         )
-        .fetch_one(&pool)
+        .fetch_one(&mut *conn)
         .await?;
-        Ok(category)
+        let mut unique = tag_ids.to_vec();
+        unique.sort();
+        unique.dedup();
+        match found.count as usize == unique.len() {
+            true => Ok(()),
+            false => Err(ApiError::InvalidId),
+        }
     }
 
     // Update address by user's id and return the user
     pub async fn update_address(pool: &Pool, data: AddressData) -> DResult<ResponseUser> {
         let pool = pool.clone();
         let AddressData { address, user_id } = data;
+        let mut tx = pool.begin().await?;
+        let before = sqlx::query!(
+            "SELECT address FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_one(&mut tx)
+        .await?;
         sqlx::query!("UPDATE users SET address = $1 WHERE id = $2", address, user_id)
-            .execute(&pool)
+            .execute(&mut tx)
             .await?;
+        DatabaseHand::add_log(
+            &mut tx,
+            LogData::new(
+                user_id,
+                AuditEvent::AddressUpdated,
+                Some(user_id),
+                format!("Address of user {user_id} updated"),
+            )
+            .before(&json!({ "address": before.address }))
+            .after(&json!({ "address": address })),
+        )
+        .await?;
+        tx.commit().await?;
         let user = DatabaseHand::get_user(&pool, user_id).await?;
         Ok(user)
         
     }
 
-    pub async fn get_random_listings(pool: &Pool) -> DResult<Vec<Listing>> {
+    pub async fn get_random_listings(pool: &Pool) -> DResult<Vec<ListingSummary>> {
         let pool = pool.clone();
-        let listings = sqlx::query_as!(DListing, "SELECT * FROM listing ORDER BY RANDOM() LIMIT 4")
-            .fetch_all(&pool)
-            .await?;
-        let mut listings: Vec<Listing> = listings.into_iter().map(|l| l.into()).collect();
-        for listing in &mut listings {
-            let listing_image = DatabaseHand::get_image(&pool, &listing.id).await?;
-            let ed_img = listing_image.split('/').collect::<Vec<_>>();
-            listing.image = BASE_URL.to_owned() + "/get/image/" + ed_img.last().unwrap();
-            let bxs = DatabaseHand::get_boxes_of_listing(&pool, &listing.id).await?;
-            listing.box_count = bxs.len() as u32;
-            listing.boxes = bxs;
-        }
-        Ok(listings)
+        let ids = sqlx::query!(
+            "SELECT id FROM listing WHERE status = 'live' ORDER BY RANDOM() LIMIT 4"
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|r| r.id)
+        .collect::<Vec<_>>();
+        DatabaseHand::load_listing_summaries(&pool, &ids).await
     }
 
 
@@ -893,3 +4563,20 @@ impl DatabaseHand {
     // Get product from id 
     
 }
+
+/// Checked against when signing in with an unknown email, so it takes as long as a wrong
+/// password
+fn dummy_password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| bcrypt::hash("not a password", bcrypt::DEFAULT_COST).unwrap())
+}
+
+/// For users who sign in through a provider, nobody knows the password it is a hash of
+fn unusable_password_hash() -> DResult<String> {
+    Ok(bcrypt::hash(hex::encode(rand::thread_rng().gen::<[u8; 32]>()), bcrypt::DEFAULT_COST)?)
+}
+
+/// Tokens are stored hashed so a leaked table can't be used to take over accounts
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
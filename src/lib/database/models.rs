@@ -11,6 +11,8 @@ pub struct User {
     pub points: i32,
     pub is_superuser: bool,
     pub address: Option<String>,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -20,7 +22,8 @@ pub struct Listing {
     pub created_at: NaiveDateTime,
     pub tty: String,
     pub description: String,
-    pub category_id: Option<Uuid>
+    pub category_id: Option<Uuid>,
+    pub status: String,
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +33,7 @@ pub struct Box {
     pub listing_id: Uuid,
     pub created_at: NaiveDateTime,
     pub original_price: i32,
+    pub claim_seconds: Option<i32>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,10 +48,39 @@ pub struct Product {
     pub created_at: NaiveDateTime,
     pub amount: i32,
     pub image: String,
+    pub price: Option<i32>,
 }
 
+#[derive(Debug, Clone)]
+pub struct BoxTemplate {
+    pub id: Uuid,
+    pub name: String,
+    pub price: i32,
+    pub original_price: i32,
+    pub created_at: NaiveDateTime,
+}
 
+#[derive(Debug, Clone)]
+pub struct PurchaseLimit {
+    pub id: Uuid,
+    pub listing_id: Option<Uuid>,
+    pub box_id: Option<Uuid>,
+    pub max_per_box: Option<i32>,
+    pub max_per_day: Option<i32>,
+    pub cooldown_seconds: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
 
+#[derive(Debug, Clone)]
+pub struct TemplateProduct {
+    pub id: Uuid,
+    pub template_id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub level: i32,
+    pub amount: i32,
+    pub image: String,
+}
 
 impl From<Box> for models::Box {
     fn from(value: Box) -> Self {
@@ -59,6 +92,7 @@ impl From<Box> for models::Box {
             products: vec![],
             total: 0,
             available_products: 0,
+            claim_seconds: value.claim_seconds.map(|c| c as u32),
             original_price: value.original_price as u32
         }
     }
@@ -76,7 +110,8 @@ impl From<Product> for models::Product {
             created_at: value.created_at,
             amount: value.amount,
             available: value.amount,
-            image: value.image
+            image: value.image,
+            price: value.price.map(|p| p as u32),
         }
     }
 }
@@ -92,7 +127,9 @@ impl From<Listing> for models::Listing {
             image: "".to_owned(),
             tty: value.tty,
             description: value.description,
-            category_id: value.category_id
+            category_id: value.category_id,
+            status: value.status,
+            tags: vec![],
         }
     }
 }
@@ -108,7 +145,194 @@ impl From<User> for models::ResponseUser {
             owned_products: vec![],
             points: value.points as u32,
             orders: vec![],
-            address: value.address
+            address: value.address,
+            email_verified: value.email_verified,
+            two_factor_enabled: value.two_factor_enabled,
+            permissions: vec![],
+        }
+    }
+}
+
+impl From<BoxTemplate> for models::BoxTemplate {
+    fn from(value: BoxTemplate) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            price: value.price as u32,
+            original_price: value.original_price as u32,
+            created_at: value.created_at,
+            products: vec![],
+        }
+    }
+}
+
+impl From<TemplateProduct> for models::TemplateProduct {
+    fn from(value: TemplateProduct) -> Self {
+        Self {
+            id: value.id,
+            template_id: value.template_id,
+            title: value.title,
+            description: value.description,
+            level: value.level as u32,
+            amount: value.amount,
+            image: value.image,
+        }
+    }
+}
+
+impl From<PurchaseLimit> for models::PurchaseLimit {
+    fn from(value: PurchaseLimit) -> Self {
+        Self {
+            id: value.id,
+            listing_id: value.listing_id,
+            box_id: value.box_id,
+            max_per_box: value.max_per_box.map(|m| m as u32),
+            max_per_day: value.max_per_day.map(|m| m as u32),
+            cooldown_seconds: value.cooldown_seconds.map(|c| c as u32),
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Log {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub action: String,
+    pub created_at: NaiveDateTime,
+    pub event: Option<String>,
+    pub entity_type: Option<String>,
+    pub entity_id: Option<Uuid>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub request_id: Option<Uuid>,
+    pub ip: Option<String>,
+}
+
+impl From<Log> for models::LogData {
+    fn from(value: Log) -> Self {
+        Self {
+            user_id: value.user_id,
+            id: value.id,
+            created_at: value.created_at,
+            action: value.action,
+            event: value.event.and_then(|e| e.parse().ok()),
+            entity_type: value.entity_type.and_then(|e| e.parse().ok()),
+            entity_id: value.entity_id,
+            before: value.before,
+            after: value.after,
+            request_id: value.request_id,
+            ip: value.ip,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Role {
+    pub id: Uuid,
+    pub name: String,
+    pub require_two_factor: bool,
+    pub created_at: NaiveDateTime,
+    pub permissions: Vec<String>,
+}
+
+impl From<Role> for models::Role {
+    fn from(value: Role) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            // Permissions which no longer exist are ignored
+            permissions: value
+                .permissions
+                .iter()
+                .filter_map(|p| p.parse().ok())
+                .collect(),
+            require_two_factor: value.require_two_factor,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub prefix: String,
+    pub expires_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<AccessToken> for models::AccessToken {
+    fn from(value: AccessToken) -> Self {
+        Self {
+            id: value.id,
+            user_id: value.user_id,
+            name: value.name,
+            scopes: value.scopes.iter().filter_map(|p| p.parse().ok()).collect(),
+            prefix: value.prefix,
+            expires_at: value.expires_at,
+            last_used_at: value.last_used_at,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub created_at: NaiveDateTime,
+    pub points: i32,
+    pub address: Option<String>,
+    pub status: String,
+    pub suspended_until: Option<NaiveDateTime>,
+    pub status_reason: Option<String>,
+}
+
+impl From<UserSummary> for models::UserSummary {
+    fn from(value: UserSummary) -> Self {
+        Self {
+            id: value.id,
+            username: value.username,
+            email: value.email,
+            email_verified: value.email_verified,
+            created_at: value.created_at,
+            points: value.points as u32,
+            address: value.address,
+            // Guarded by the `users_status_check` constraint
+            status: value.status.parse().unwrap_or(models::UserStatus::Active),
+            suspended_until: value.suspended_until,
+            status_reason: value.status_reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LedgerEntry {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub delta: i32,
+    pub balance: i32,
+    pub reason: String,
+    pub reference_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<LedgerEntry> for models::LedgerEntry {
+    fn from(value: LedgerEntry) -> Self {
+        Self {
+            id: value.id,
+            user_id: value.user_id,
+            delta: value.delta,
+            balance: value.balance as u32,
+            reason: value.reason.parse().unwrap_or(models::LedgerReason::Grant),
+            reference_id: value.reference_id,
+            created_at: value.created_at,
         }
     }
 }
@@ -1,17 +1,20 @@
-use axum::http::StatusCode;
+use axum::http::{header::RETRY_AFTER, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
 use bcrypt::BcryptError;
+use chrono::{NaiveDateTime, Utc};
 use serde::Serialize;
 
+use crate::models::Permission;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ApiError {
     #[error("An error occurred in the database.")]
     DatabaseError(#[from] sqlx::Error),
     #[error("Incorrect Password")]
     IncorrectPassword(#[from] BcryptError),
-    #[error("User is not a superuser")]
-    NotSuperuser,
+    #[error("User is missing a permission.")]
+    MissingPermission(Permission),
     #[error("Error has been occured while parsing image.")]
     ImageError(#[from] axum::extract::multipart::MultipartError),
     #[error("Error has been occurred while selecting the product for the user.")]
@@ -23,13 +26,95 @@ pub enum ApiError {
     #[error("Image not found.")]
     ImageNotFound,
     #[error("Invalid id.")]
-    InvalidId
+    InvalidId,
+    #[error("Previewed products do not match the current products.")]
+    PreviewMismatch,
+    #[error("Unknown format.")]
+    UnknownFormat,
+    #[error("Invalid cursor.")]
+    InvalidCursor,
+    #[error("Slug is already used.")]
+    SlugTaken,
+    #[error("Name is already used.")]
+    NameTaken,
+    #[error("Invalid category parent.")]
+    InvalidCategoryParent,
+    #[error("Unknown listing type.")]
+    UnknownListingType,
+    #[error("Listing is not sold this way.")]
+    WrongSaleMode,
+    #[error("Product is out of stock.")]
+    OutOfStock,
+    /// Holds when the user can buy again, `None` if they can't buy from the box anymore.
+    #[error("Purchase limit reached.")]
+    PurchaseLimitReached(Option<NaiveDateTime>),
+    #[error("Box has no queue.")]
+    NoQueue,
+    #[error("Another user holds the claim on this box.")]
+    NotYourTurn,
+    #[error("At least one user has to keep the manage_roles permission.")]
+    LastRoleManager,
+    /// Holds when the suspension ends, `None` if it doesn't.
+    #[error("User is suspended.")]
+    UserSuspended(Option<NaiveDateTime>),
+    #[error("User is banned.")]
+    UserBanned,
+    #[error("Invalid email or password.")]
+    InvalidCredentials,
+    #[error("Some fields are invalid.")]
+    Validation(Vec<FieldError>),
+    /// Holds when the next attempt is allowed.
+    #[error("Too many attempts.")]
+    TooManyAttempts(NaiveDateTime),
+    #[error("Invalid request body.")]
+    InvalidBody,
+    #[error("Request body is too large.")]
+    BodyTooLarge,
+    #[error("Two-factor code required.")]
+    TwoFactorRequired,
+    #[error("Invalid two-factor code.")]
+    InvalidTwoFactorCode,
+    #[error("Two-factor authentication is already enabled.")]
+    TwoFactorAlreadyEnabled,
+    #[error("Two-factor enrolment has not been started.")]
+    TwoFactorNotStarted,
+    #[error("Email is not verified.")]
+    EmailNotVerified,
+    #[error("Invalid or expired token.")]
+    InvalidToken,
+    #[error("Error has been occurred while sending a mail.")]
+    MailError(String),
+    #[error("Unknown sign-in provider.")]
+    UnknownProvider,
+    #[error("Error has been occurred while talking to the sign-in provider.")]
+    OAuthError(String),
+    #[error("The provider didn't give a verified email.")]
+    OAuthEmailUnverified,
+    #[error("Password sign-in required.")]
+    PasswordSignInRequired,
+    #[error("Invalid, expired or revoked access token.")]
+    InvalidAccessToken,
+    #[error("Session required.")]
+    SessionRequired,
+    #[error("Error has been occurred while writing the export.")]
+    ExportError(String),
+}
+
+/// What is wrong with one field of a request
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
 }
 
 #[derive(Serialize)]
 pub struct ErrorBody {
     error: String,
     status_code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_at: Option<NaiveDateTime>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
 }
 
 impl IntoResponse for ErrorBody {
@@ -40,6 +125,15 @@ impl IntoResponse for ErrorBody {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
+        let retry_at = match self {
+            Self::PurchaseLimitReached(retry_at) | Self::UserSuspended(retry_at) => retry_at,
+            Self::TooManyAttempts(retry_at) => Some(retry_at),
+            _ => None,
+        };
+        let fields = match &self {
+            Self::Validation(fields) => fields.clone(),
+            _ => vec![],
+        };
         let (status, error_msg) = match self {
             Self::DatabaseError(a) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -48,9 +142,9 @@ impl IntoResponse for ApiError {
             Self::IncorrectPassword(_) => {
                 (StatusCode::BAD_REQUEST, "Incorrct passsword".to_string())
             }
-            Self::NotSuperuser => (
-                StatusCode::BAD_REQUEST,
-                "User is not a superuser.".to_string(),
+            Self::MissingPermission(permission) => (
+                StatusCode::FORBIDDEN,
+                format!("User needs the {} permission.", permission.as_str()),
             ),
             Self::ImageError(_) => (
                 StatusCode::BAD_REQUEST,
@@ -75,14 +169,143 @@ impl IntoResponse for ApiError {
             Self::InvalidId => (
                 StatusCode::BAD_REQUEST,
                 "Invalid id.".to_string(),
-            )
+            ),
+            Self::PreviewMismatch => (
+                StatusCode::CONFLICT,
+                "Previewed products do not match the current products.".to_string(),
+            ),
+            Self::UnknownFormat => (StatusCode::BAD_REQUEST, "Unknown format.".to_string()),
+            Self::InvalidCursor => (StatusCode::BAD_REQUEST, "Invalid cursor.".to_string()),
+            Self::SlugTaken => (StatusCode::CONFLICT, "Slug is already used.".to_string()),
+            Self::NameTaken => (StatusCode::CONFLICT, "Name is already used.".to_string()),
+            Self::InvalidCategoryParent => (
+                StatusCode::BAD_REQUEST,
+                "Invalid category parent.".to_string(),
+            ),
+            Self::UnknownListingType => (
+                StatusCode::BAD_REQUEST,
+                "Unknown listing type.".to_string(),
+            ),
+            Self::WrongSaleMode => (
+                StatusCode::BAD_REQUEST,
+                "Listing is not sold this way.".to_string(),
+            ),
+            Self::OutOfStock => (StatusCode::CONFLICT, "Product is out of stock.".to_string()),
+            Self::PurchaseLimitReached(Some(retry_at)) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Purchase limit reached, you can buy again at {retry_at}."),
+            ),
+            Self::PurchaseLimitReached(None) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Purchase limit reached for this box.".to_string(),
+            ),
+            Self::NoQueue => (StatusCode::BAD_REQUEST, "Box has no queue.".to_string()),
+            Self::NotYourTurn => (
+                StatusCode::CONFLICT,
+                "Another user holds the claim on this box, join its queue and wait for your turn."
+                    .to_string(),
+            ),
+            Self::LastRoleManager => (
+                StatusCode::CONFLICT,
+                "At least one user has to keep the manage_roles permission.".to_string(),
+            ),
+            Self::UserSuspended(Some(until)) => (
+                StatusCode::FORBIDDEN,
+                format!("Account is suspended until {until}."),
+            ),
+            Self::UserSuspended(None) => {
+                (StatusCode::FORBIDDEN, "Account is suspended.".to_string())
+            }
+            Self::UserBanned => (StatusCode::FORBIDDEN, "Account is banned.".to_string()),
+            Self::InvalidCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid email or password.".to_string(),
+            ),
+            Self::Validation(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Some fields are invalid.".to_string(),
+            ),
+            Self::EmailNotVerified => (
+                StatusCode::FORBIDDEN,
+                "Verify your email before buying.".to_string(),
+            ),
+            Self::InvalidToken => (
+                StatusCode::BAD_REQUEST,
+                "The link is invalid or has expired.".to_string(),
+            ),
+            Self::MailError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to send the mail. {e}"),
+            ),
+            Self::UnknownProvider => (
+                StatusCode::NOT_FOUND,
+                "There is no sign-in provider with that name.".to_string(),
+            ),
+            Self::OAuthError(e) => (
+                StatusCode::BAD_GATEWAY,
+                format!("Signing in with the provider failed. {e}"),
+            ),
+            Self::OAuthEmailUnverified => (
+                StatusCode::FORBIDDEN,
+                "The provider has to give a verified email to sign in with it.".to_string(),
+            ),
+            Self::PasswordSignInRequired => (
+                StatusCode::FORBIDDEN,
+                "Accounts with two-factor authentication sign in with their password.".to_string(),
+            ),
+            Self::InvalidAccessToken => (
+                StatusCode::UNAUTHORIZED,
+                "The access token is invalid, has expired or was revoked.".to_string(),
+            ),
+            Self::SessionRequired => (
+                StatusCode::FORBIDDEN,
+                "This can't be done with an access token, sign in instead.".to_string(),
+            ),
+            Self::ExportError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to write the export. {e}"),
+            ),
+            Self::TooManyAttempts(retry_at) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Too many attempts, try again at {retry_at}."),
+            ),
+            Self::InvalidBody => (StatusCode::BAD_REQUEST, "Invalid request body.".to_string()),
+            Self::BodyTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body is too large.".to_string(),
+            ),
+            Self::TwoFactorRequired => (
+                StatusCode::UNAUTHORIZED,
+                "Enter the code from your authenticator app or a backup code.".to_string(),
+            ),
+            Self::InvalidTwoFactorCode => (
+                StatusCode::UNAUTHORIZED,
+                "The two-factor code is wrong or was already used.".to_string(),
+            ),
+            Self::TwoFactorAlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "Two-factor authentication is already enabled.".to_string(),
+            ),
+            Self::TwoFactorNotStarted => (
+                StatusCode::BAD_REQUEST,
+                "Start two-factor enrolment first.".to_string(),
+            ),
         };
 
         let body = ErrorBody {
             error: error_msg,
             status_code: status.as_u16(),
+            retry_at,
+            fields,
         };
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            if let Some(retry_at) = retry_at {
+                let seconds = (retry_at - Utc::now().naive_utc()).num_seconds().max(0) + 1;
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+            }
+        }
+        response
     }
 }
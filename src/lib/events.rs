@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{
+    database::actions::{DatabaseHand, Pool},
+    models::{BoxEvent, LogData},
+};
+
+/// Events a receiver can fall behind by before it starts missing them.
+const CAPACITY: usize = 1024;
+
+/// In-process channels the websocket routes subscribe to.
+#[derive(Debug, Clone)]
+pub struct Events {
+    boxes: broadcast::Sender<BoxEvent>,
+    logs: broadcast::Sender<LogData>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (boxes, _) = broadcast::channel(CAPACITY);
+        let (logs, _) = broadcast::channel(CAPACITY);
+        Self { boxes, logs }
+    }
+
+    pub fn publish_box(&self, event: BoxEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.boxes.send(event);
+    }
+
+    pub fn subscribe_boxes(&self) -> broadcast::Receiver<BoxEvent> {
+        self.boxes.subscribe()
+    }
+
+    pub fn subscribe_logs(&self) -> broadcast::Receiver<LogData> {
+        self.logs.subscribe()
+    }
+
+    /// Forward every committed log to the log subscribers. The `logs_notify` trigger
+    /// sends the id of each inserted row on the `logs` channel, so logs of transactions
+    /// which are rolled back are never published.
+    pub fn listen_for_logs(&self, pool: &Pool) {
+        let pool = pool.clone();
+        let logs = self.logs.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut listener = match PgListener::connect_with(&pool).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Failed to listen for logs: {e}");
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        continue;
+                    }
+                };
+                if let Err(e) = listener.listen("logs").await {
+                    eprintln!("Failed to listen for logs: {e}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+                // `recv` reconnects by itself, an error means it gave up
+                while let Ok(notification) = listener.recv().await {
+                    let id = match Uuid::parse_str(notification.payload()) {
+                        Ok(id) => id,
+                        Err(_) => continue,
+                    };
+                    if let Ok(log) = DatabaseHand::get_log(&pool, &id).await {
+                        let _ = logs.send(log);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
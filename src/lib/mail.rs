@@ -0,0 +1,171 @@
+use std::{fmt::Debug, path::PathBuf, sync::Arc, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+/// Where the links in mails lead to
+pub(crate) const FRONTEND_URL: &str = "http://localhost:4200";
+const MAIL_DIR: &str = "database/mail";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    pub fn email_verification(to: &str, token: &str) -> Self {
+        Mail {
+            to: to.to_owned(),
+            subject: "Verify your email".to_owned(),
+            body: format!(
+                "Open this link to verify your email:\n\n\
+                {FRONTEND_URL}/verify-email?token={token}\n\n\
+                The link works once and expires in 24 hours."
+            ),
+        }
+    }
+
+    pub fn password_reset(to: &str, token: &str) -> Self {
+        Mail {
+            to: to.to_owned(),
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "Open this link to choose a new password:\n\n\
+                {FRONTEND_URL}/reset-password?token={token}\n\n\
+                The link works once and expires in an hour. If you didn't ask for it you can \
+                ignore this mail."
+            ),
+        }
+    }
+}
+
+/// Sends mails to users. `from_env` picks the implementation the server uses.
+#[async_trait]
+pub trait Mailer: Send + Sync + Debug {
+    async fn send(&self, mail: Mail) -> Result<(), ApiError>;
+}
+
+/// Sends mails through an SMTP relay over TLS
+#[derive(Debug)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: &str, password: &str, from: &str) -> Result<Self, ApiError> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .map_err(|e| ApiError::MailError(e.to_string()))?
+            .credentials(Credentials::new(username.to_owned(), password.to_owned()))
+            .build();
+        let from = from
+            .parse()
+            .map_err(|e: lettre::address::AddressError| ApiError::MailError(e.to_string()))?;
+        Ok(SmtpMailer { transport, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), ApiError> {
+        let to = mail
+            .to
+            .parse()
+            .map_err(|e: lettre::address::AddressError| ApiError::MailError(e.to_string()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| ApiError::MailError(e.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| ApiError::MailError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Writes every mail to its own file in `dir`, for local development
+#[derive(Debug)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileMailer { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<(), ApiError> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| ApiError::MailError(e.to_string()))?;
+        let name = format!("{}-{}.txt", Utc::now().format("%Y%m%d%H%M%S"), Uuid::new_v4());
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+        tokio::fs::write(self.dir.join(name), content)
+            .await
+            .map_err(|e| ApiError::MailError(e.to_string()))
+    }
+}
+
+/// Keeps the mails in memory, for tests
+#[derive(Debug, Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> Self {
+        MemoryMailer::default()
+    }
+
+    /// Every mail sent so far, oldest first
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> Result<(), ApiError> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
+/// The mailer chosen by `MAILER`: `smtp` (configured by `SMTP_HOST`, `SMTP_USERNAME`,
+/// `SMTP_PASSWORD` and `MAIL_FROM`), `memory`, or by default `file`, which writes the
+/// mails to `MAIL_DIR` or `database/mail`.
+pub fn from_env() -> Arc<dyn Mailer> {
+    let var = |name: &str| std::env::var(name).unwrap_or_default();
+    match var("MAILER").as_str() {
+        "smtp" => Arc::new(
+            SmtpMailer::new(
+                &var("SMTP_HOST"),
+                &var("SMTP_USERNAME"),
+                &var("SMTP_PASSWORD"),
+                &var("MAIL_FROM"),
+            )
+            .expect("Invalid SMTP settings"),
+        ),
+        "memory" => Arc::new(MemoryMailer::new()),
+        _ => match std::env::var("MAIL_DIR") {
+            Ok(dir) => Arc::new(FileMailer::new(dir)),
+            Err(_) => Arc::new(FileMailer::new(MAIL_DIR)),
+        },
+    }
+}
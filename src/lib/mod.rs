@@ -1,11 +1,24 @@
+use std::sync::Arc;
+
 use database::Database;
+use events::Events;
+use mail::Mailer;
+use oauth::Providers;
 pub mod web;
 pub mod database;
 pub mod models;
 pub mod error;
+pub mod catalogue;
+pub mod events;
+pub mod mail;
+pub mod oauth;
+pub mod totp;
 
 
 #[derive(Debug, Clone)]
 pub struct State {
-    pub database: Database
+    pub database: Database,
+    pub events: Events,
+    pub mailer: Arc<dyn Mailer>,
+    pub oauth: Providers,
 }
@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -12,7 +14,98 @@ pub struct Box {
     pub products: Vec<Product>,
     pub total: u32,
     pub available_products: u32,
+    /// Length of the exclusive claim in seconds when the box is in queue mode.
+    pub claim_seconds: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BoxTemplate {
+    pub id: Uuid,
+    pub name: String,
+    pub price: u32,
+    pub original_price: u32,
+    pub created_at: NaiveDateTime,
+    pub products: Vec<TemplateProduct>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TierStock {
+    pub level: u32,
+    pub remaining: u32,
+    pub total: u32,
+}
+
+/// Tickets left in a box, overall and per prize level.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BoxStock {
+    pub listing_id: Uuid,
+    pub box_id: Uuid,
+    pub remaining: u32,
+    pub total: u32,
+    pub tiers: Vec<TierStock>,
+}
+
+/// Messages of the box websocket. Subscribers get a `stock` snapshot of every box
+/// first and a `draw` whenever a ticket of one of them is drawn.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BoxEvent {
+    Stock(BoxStock),
+    Draw {
+        stock: BoxStock,
+        prize: String,
+        level: u32,
+        message: String,
+        created_at: NaiveDateTime,
+    },
+}
+
+impl BoxEvent {
+    pub fn stock(&self) -> &BoxStock {
+        match self {
+            BoxEvent::Stock(stock) | BoxEvent::Draw { stock, .. } => stock,
+        }
+    }
+}
+
+/// Where a user stands in the queue of a box. Position 0 holds the claim and is the
+/// only one who can draw until `claim_expires_at`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueueStatus {
+    pub box_id: Uuid,
+    pub claim_seconds: u32,
+    pub length: u32,
+    pub position: Option<u32>,
+    pub claim_expires_at: Option<NaiveDateTime>,
+}
+
+/// Limits on how many tickets one user can draw from a box. A limit set on a
+/// listing applies to each of its boxes, unless the box has its own limit.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PurchaseLimit {
+    pub id: Uuid,
+    pub listing_id: Option<Uuid>,
+    pub box_id: Option<Uuid>,
+    /// Tickets one user can draw from a box in total.
+    pub max_per_box: Option<u32>,
+    /// Tickets one user can draw from a box in any 24 hours.
+    pub max_per_day: Option<u32>,
+    /// Seconds a user has to wait between two draws from a box.
+    pub cooldown_seconds: Option<u32>,
+    pub created_at: NaiveDateTime,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TemplateProduct {
+    pub id: Uuid,
+    pub template_id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub level: u32,
+    pub amount: i32,
+    pub image: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerStatus {
     pub status: bool,
@@ -68,6 +161,21 @@ pub struct Category {
     pub id: Uuid,
     pub name: String,
     pub created_at: NaiveDateTime,
+    pub parent_id: Option<Uuid>,
+    pub slug: String,
+    /// Order of the category among its siblings.
+    pub position: i32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CategoryNode {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub position: i32,
+    /// Live listings in this category and all of its descendants.
+    pub listing_count: u32,
+    pub children: Vec<CategoryNode>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -81,6 +189,138 @@ pub struct Listing {
     pub created_at: NaiveDateTime,
     pub box_count: u32,
     pub tty: String,
+    pub status: String,
+    pub tags: Vec<Tag>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagKind {
+    Franchise,
+    Character,
+    Manufacturer,
+}
+
+impl TagKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagKind::Franchise => "franchise",
+            TagKind::Character => "character",
+            TagKind::Manufacturer => "manufacturer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tag {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub kind: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// What the storefront grids need from a listing, without its boxes and products.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListingSummary {
+    pub id: Uuid,
+    pub title: String,
+    pub image: String,
+    pub tty: String,
+    pub category_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    pub status: String,
+    pub box_count: u32,
+    pub min_price: Option<u32>,
+    pub max_price: Option<u32>,
+    pub total_tickets: u32,
+    pub remaining_tickets: u32,
+    /// Image of the highest tier prize across the listing's boxes.
+    pub top_prize_image: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub listing: ListingSummary,
+    pub rank: f32,
+    /// Part of the title, product titles and description with the matches wrapped in `<b>` tags.
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingStatus {
+    Draft,
+    Live,
+    Archived,
+}
+
+impl ListingStatus {
+    pub const ALL: [ListingStatus; 3] = [
+        ListingStatus::Draft,
+        ListingStatus::Live,
+        ListingStatus::Archived,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingStatus::Draft => "draft",
+            ListingStatus::Live => "live",
+            ListingStatus::Archived => "archived",
+        }
+    }
+}
+
+impl FromStr for ListingStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ListingStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown listing status {s}"))
+    }
+}
+
+/// How the prizes of a listing are sold. New sale formats are added here and to
+/// the `listing_tty_check` constraint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ListingType {
+    #[serde(rename = "ICH", alias = "ich")]
+    Ichiban,
+    #[serde(rename = "HEX", alias = "hex")]
+    Hex,
+    /// Products are bought directly at their own price instead of drawn from a box.
+    #[serde(rename = "DIRECT", alias = "direct")]
+    Direct,
+}
+
+impl ListingType {
+    pub const ALL: [ListingType; 3] = [ListingType::Ichiban, ListingType::Hex, ListingType::Direct];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingType::Ichiban => "ICH",
+            ListingType::Hex => "HEX",
+            ListingType::Direct => "DIRECT",
+        }
+    }
+}
+
+impl FromStr for ListingType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ListingType::ALL
+            .into_iter()
+            .find(|t| t.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown listing type {s}"))
+    }
+}
+
+/// Envelope for every paginated response. `next_cursor` is `None` on the last page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,7 +335,16 @@ pub struct Product {
     pub amount: i32,
     pub available: i32,
     pub image: String,
-    pub ini_amount: i32
+    pub ini_amount: i32,
+    /// Points price of a product sold in a direct-sale listing.
+    pub price: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrizeRemoval {
+    pub listing_id: Uuid,
+    pub title: String,
+    pub product_ids: Vec<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -110,26 +359,482 @@ pub struct Order {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityType {
+    User,
+    Listing,
+    Box,
+    BoxTemplate,
+    Product,
+    Catalogue,
+    Category,
+    Tag,
+    PurchaseLimit,
+    Order,
+    Role,
+}
+
+impl EntityType {
+    pub const ALL: [EntityType; 11] = [
+        EntityType::User,
+        EntityType::Listing,
+        EntityType::Box,
+        EntityType::BoxTemplate,
+        EntityType::Product,
+        EntityType::Catalogue,
+        EntityType::Category,
+        EntityType::Tag,
+        EntityType::PurchaseLimit,
+        EntityType::Order,
+        EntityType::Role,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityType::User => "user",
+            EntityType::Listing => "listing",
+            EntityType::Box => "box",
+            EntityType::BoxTemplate => "box_template",
+            EntityType::Product => "product",
+            EntityType::Catalogue => "catalogue",
+            EntityType::Category => "category",
+            EntityType::Tag => "tag",
+            EntityType::PurchaseLimit => "purchase_limit",
+            EntityType::Order => "order",
+            EntityType::Role => "role",
+        }
+    }
+}
+
+impl FromStr for EntityType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EntityType::ALL
+            .into_iter()
+            .find(|e| e.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// Every change which is written to the audit log. New mutating operations get a
+/// variant here, the entity type it touches is fixed by the variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    UserRegistered,
+    PointsAdded,
+    AddressUpdated,
+    UserRolesChanged,
+    UserStatusChanged,
+    UserUpdated,
+    UserImpersonated,
+    EmailVerified,
+    PasswordResetRequested,
+    PasswordReset,
+    LoginFailed,
+    AccountLocked,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    BackupCodesRegenerated,
+    BackupCodeUsed,
+    IdentityLinked,
+    AccessTokenCreated,
+    AccessTokenRevoked,
+    ListingCreated,
+    ListingStatusChanged,
+    ListingTagged,
+    ListingDeleted,
+    BoxesCloned,
+    PrizeRemoved,
+    BoxCreated,
+    BoxQueueChanged,
+    BoxDeleted,
+    TicketDrawn,
+    QueueJoined,
+    QueueLeft,
+    BoxTemplateCreated,
+    ProductAdded,
+    ProductTagged,
+    ProductDeleted,
+    ProductBought,
+    CatalogueImported,
+    CategoryCreated,
+    CategoryUpdated,
+    CategoryDeleted,
+    CategoriesReordered,
+    TagCreated,
+    TagDeleted,
+    PurchaseLimitSet,
+    PurchaseLimitDeleted,
+    OrderUpdated,
+    RoleCreated,
+    RoleDeleted,
+    RoleUpdated,
+}
+
+impl AuditEvent {
+    pub const ALL: [AuditEvent; 49] = [
+        AuditEvent::UserRegistered,
+        AuditEvent::PointsAdded,
+        AuditEvent::AddressUpdated,
+        AuditEvent::UserRolesChanged,
+        AuditEvent::UserStatusChanged,
+        AuditEvent::UserUpdated,
+        AuditEvent::UserImpersonated,
+        AuditEvent::EmailVerified,
+        AuditEvent::PasswordResetRequested,
+        AuditEvent::PasswordReset,
+        AuditEvent::LoginFailed,
+        AuditEvent::AccountLocked,
+        AuditEvent::TwoFactorEnabled,
+        AuditEvent::TwoFactorDisabled,
+        AuditEvent::BackupCodesRegenerated,
+        AuditEvent::BackupCodeUsed,
+        AuditEvent::IdentityLinked,
+        AuditEvent::AccessTokenCreated,
+        AuditEvent::AccessTokenRevoked,
+        AuditEvent::ListingCreated,
+        AuditEvent::ListingStatusChanged,
+        AuditEvent::ListingTagged,
+        AuditEvent::ListingDeleted,
+        AuditEvent::BoxesCloned,
+        AuditEvent::PrizeRemoved,
+        AuditEvent::BoxCreated,
+        AuditEvent::BoxQueueChanged,
+        AuditEvent::BoxDeleted,
+        AuditEvent::TicketDrawn,
+        AuditEvent::QueueJoined,
+        AuditEvent::QueueLeft,
+        AuditEvent::BoxTemplateCreated,
+        AuditEvent::ProductAdded,
+        AuditEvent::ProductTagged,
+        AuditEvent::ProductDeleted,
+        AuditEvent::ProductBought,
+        AuditEvent::CatalogueImported,
+        AuditEvent::CategoryCreated,
+        AuditEvent::CategoryUpdated,
+        AuditEvent::CategoryDeleted,
+        AuditEvent::CategoriesReordered,
+        AuditEvent::TagCreated,
+        AuditEvent::TagDeleted,
+        AuditEvent::PurchaseLimitSet,
+        AuditEvent::PurchaseLimitDeleted,
+        AuditEvent::OrderUpdated,
+        AuditEvent::RoleCreated,
+        AuditEvent::RoleDeleted,
+        AuditEvent::RoleUpdated,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::UserRegistered => "user_registered",
+            AuditEvent::PointsAdded => "points_added",
+            AuditEvent::AddressUpdated => "address_updated",
+            AuditEvent::UserRolesChanged => "user_roles_changed",
+            AuditEvent::UserStatusChanged => "user_status_changed",
+            AuditEvent::UserUpdated => "user_updated",
+            AuditEvent::UserImpersonated => "user_impersonated",
+            AuditEvent::EmailVerified => "email_verified",
+            AuditEvent::PasswordResetRequested => "password_reset_requested",
+            AuditEvent::PasswordReset => "password_reset",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::AccountLocked => "account_locked",
+            AuditEvent::TwoFactorEnabled => "two_factor_enabled",
+            AuditEvent::TwoFactorDisabled => "two_factor_disabled",
+            AuditEvent::BackupCodesRegenerated => "backup_codes_regenerated",
+            AuditEvent::BackupCodeUsed => "backup_code_used",
+            AuditEvent::IdentityLinked => "identity_linked",
+            AuditEvent::AccessTokenCreated => "access_token_created",
+            AuditEvent::AccessTokenRevoked => "access_token_revoked",
+            AuditEvent::ListingCreated => "listing_created",
+            AuditEvent::ListingStatusChanged => "listing_status_changed",
+            AuditEvent::ListingTagged => "listing_tagged",
+            AuditEvent::ListingDeleted => "listing_deleted",
+            AuditEvent::BoxesCloned => "boxes_cloned",
+            AuditEvent::PrizeRemoved => "prize_removed",
+            AuditEvent::BoxCreated => "box_created",
+            AuditEvent::BoxQueueChanged => "box_queue_changed",
+            AuditEvent::BoxDeleted => "box_deleted",
+            AuditEvent::TicketDrawn => "ticket_drawn",
+            AuditEvent::QueueJoined => "queue_joined",
+            AuditEvent::QueueLeft => "queue_left",
+            AuditEvent::BoxTemplateCreated => "box_template_created",
+            AuditEvent::ProductAdded => "product_added",
+            AuditEvent::ProductTagged => "product_tagged",
+            AuditEvent::ProductDeleted => "product_deleted",
+            AuditEvent::ProductBought => "product_bought",
+            AuditEvent::CatalogueImported => "catalogue_imported",
+            AuditEvent::CategoryCreated => "category_created",
+            AuditEvent::CategoryUpdated => "category_updated",
+            AuditEvent::CategoryDeleted => "category_deleted",
+            AuditEvent::CategoriesReordered => "categories_reordered",
+            AuditEvent::TagCreated => "tag_created",
+            AuditEvent::TagDeleted => "tag_deleted",
+            AuditEvent::PurchaseLimitSet => "purchase_limit_set",
+            AuditEvent::PurchaseLimitDeleted => "purchase_limit_deleted",
+            AuditEvent::OrderUpdated => "order_updated",
+            AuditEvent::RoleCreated => "role_created",
+            AuditEvent::RoleDeleted => "role_deleted",
+            AuditEvent::RoleUpdated => "role_updated",
+        }
+    }
+
+    /// The type of the entity the event is about
+    pub fn entity_type(&self) -> EntityType {
+        match self {
+            AuditEvent::UserRegistered
+            | AuditEvent::PointsAdded
+            | AuditEvent::AddressUpdated
+            | AuditEvent::UserRolesChanged
+            | AuditEvent::UserStatusChanged
+            | AuditEvent::UserUpdated
+            | AuditEvent::UserImpersonated
+            | AuditEvent::EmailVerified
+            | AuditEvent::PasswordResetRequested
+            | AuditEvent::PasswordReset
+            | AuditEvent::LoginFailed
+            | AuditEvent::AccountLocked
+            | AuditEvent::TwoFactorEnabled
+            | AuditEvent::TwoFactorDisabled
+            | AuditEvent::BackupCodesRegenerated
+            | AuditEvent::BackupCodeUsed
+            | AuditEvent::IdentityLinked
+            | AuditEvent::AccessTokenCreated
+            | AuditEvent::AccessTokenRevoked => EntityType::User,
+            AuditEvent::ListingCreated
+            | AuditEvent::ListingStatusChanged
+            | AuditEvent::ListingTagged
+            | AuditEvent::ListingDeleted
+            | AuditEvent::BoxesCloned
+            | AuditEvent::PrizeRemoved => EntityType::Listing,
+            AuditEvent::BoxCreated
+            | AuditEvent::BoxQueueChanged
+            | AuditEvent::BoxDeleted
+            | AuditEvent::TicketDrawn
+            | AuditEvent::QueueJoined
+            | AuditEvent::QueueLeft => EntityType::Box,
+            AuditEvent::BoxTemplateCreated => EntityType::BoxTemplate,
+            AuditEvent::ProductAdded
+            | AuditEvent::ProductTagged
+            | AuditEvent::ProductDeleted
+            | AuditEvent::ProductBought => EntityType::Product,
+            AuditEvent::CatalogueImported => EntityType::Catalogue,
+            AuditEvent::CategoryCreated
+            | AuditEvent::CategoryUpdated
+            | AuditEvent::CategoryDeleted
+            | AuditEvent::CategoriesReordered => EntityType::Category,
+            AuditEvent::TagCreated | AuditEvent::TagDeleted => EntityType::Tag,
+            AuditEvent::PurchaseLimitSet | AuditEvent::PurchaseLimitDeleted => {
+                EntityType::PurchaseLimit
+            }
+            AuditEvent::OrderUpdated => EntityType::Order,
+            AuditEvent::RoleCreated | AuditEvent::RoleDeleted | AuditEvent::RoleUpdated => {
+                EntityType::Role
+            }
+        }
+    }
+}
+
+impl FromStr for AuditEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AuditEvent::ALL
+            .into_iter()
+            .find(|e| e.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// What a role allows its users to do. Every admin operation checks for one of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Listings, boxes, products, templates, categories, tags, limits and queues
+    EditCatalogue,
+    ManageOrders,
+    ViewUsers,
+    /// Suspends, bans and edits users
+    ManageUsers,
+    /// Grants up to `POINT_GRANT_LIMIT` points at a time
+    GrantPoints,
+    GrantAnyPoints,
+    ViewLogs,
+    ManageRoles,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Permission::EditCatalogue,
+        Permission::ManageOrders,
+        Permission::ViewUsers,
+        Permission::ManageUsers,
+        Permission::GrantPoints,
+        Permission::GrantAnyPoints,
+        Permission::ViewLogs,
+        Permission::ManageRoles,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::EditCatalogue => "edit_catalogue",
+            Permission::ManageOrders => "manage_orders",
+            Permission::ViewUsers => "view_users",
+            Permission::ManageUsers => "manage_users",
+            Permission::GrantPoints => "grant_points",
+            Permission::GrantAnyPoints => "grant_any_points",
+            Permission::ViewLogs => "view_logs",
+            Permission::ManageRoles => "manage_roles",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .into_iter()
+            .find(|p| p.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// The most points a user with only `Permission::GrantPoints` can grant at once
+pub const POINT_GRANT_LIMIT: u32 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub id: Uuid,
+    pub name: String,
+    pub permissions: Vec<Permission>,
+    /// The permissions only apply to users who enabled two-factor authentication
+    #[serde(default)]
+    pub require_two_factor: bool,
+    pub created_at: NaiveDateTime,
+}
+
+/// Sent when two-factor enrolment starts. The URI is shown as a QR code for
+/// authenticator apps, the secret can be typed in instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// A personal access token, sent as `Authorization: Bearer`. It can do what its scopes
+/// allow, as far as its user still has those permissions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Permission>,
+    /// The start of the token, to tell tokens apart
+    pub prefix: String,
+    pub expires_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A token as it is created, the only time the token itself is shown
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewAccessToken {
+    pub token: String,
+    pub access_token: AccessToken,
+}
+
+/// Single-use codes which sign in instead of an authenticator code. They are only
+/// shown once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupCodes {
+    pub codes: Vec<String>,
+}
+
+/// An entry of the audit log. Logs written before events were recorded only have
+/// `action` set.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogData {
     pub user_id: Uuid,
     pub id: Uuid,
     pub created_at: NaiveDateTime,
     pub action: String,
+    pub event: Option<AuditEvent>,
+    pub entity_type: Option<EntityType>,
+    pub entity_id: Option<Uuid>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub request_id: Option<Uuid>,
+    pub ip: Option<String>,
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+
+impl LogData {
+    /// A log of `event` done by `user_id` to the entity `entity_id`. The request id and
+    /// ip are filled in when the log is added.
+    pub fn new(user_id: Uuid, event: AuditEvent, entity_id: Option<Uuid>, action: String) -> Self {
+        LogData {
+            user_id,
+            id: Uuid::new_v4(),
+            created_at: chrono::Utc::now().naive_utc(),
+            action,
+            event: Some(event),
+            entity_type: Some(event.entity_type()),
+            entity_id,
+            before: None,
+            after: None,
+            request_id: None,
+            ip: None,
+        }
+    }
+
+    /// Snapshot of the entity before the change
+    pub fn before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    /// Snapshot of the entity after the change
+    pub fn after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+}
+/// A user with their secrets. It can't be serialised, responses are a `ResponseUser`
+/// made from the profile alone.
+#[derive(Debug, Clone)]
 pub struct User {
+    pub profile: UserProfile,
+    pub secrets: UserSecrets,
+}
+
+/// What can be shown of a user
+#[derive(Debug, Clone)]
+pub struct UserProfile {
     pub username: String,
     pub email: String,
     pub id: Uuid,
-    pub password: String,
     pub created_at: NaiveDateTime,
     pub owned_products: Vec<Product>,
     pub points: u32,
     pub is_superuser: bool,
-    pub private_key: Uuid,
     pub orders: Vec<Order>,
-    pub address: Option<String>
+    pub address: Option<String>,
+}
+
+/// The password hash and session key of a user. Not `Serialize` on purpose, and its
+/// `Debug` leaves them out.
+#[derive(Clone)]
+pub struct UserSecrets {
+    pub password: String,
+    pub private_key: Uuid,
+}
+
+impl std::fmt::Debug for UserSecrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserSecrets").finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -142,7 +847,177 @@ pub struct ResponseUser {
     pub owned_products: Vec<Uuid>,
     pub points: u32,
     pub orders: Vec<Order>,
-    pub address: Option<String>
+    pub address: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub two_factor_enabled: bool,
+    /// Everything the user's roles allow
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+}
+
+/// What a token mailed to a user is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl TokenKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenKind::VerifyEmail => "verify_email",
+            TokenKind::ResetPassword => "reset_password",
+        }
+    }
+
+    /// How long a token can be used after it was mailed
+    pub fn lifetime(&self) -> chrono::Duration {
+        match self {
+            TokenKind::VerifyEmail => chrono::Duration::hours(24),
+            TokenKind::ResetPassword => chrono::Duration::hours(1),
+        }
+    }
+}
+
+/// Whether a user may sign in and buy. A suspension ends at `suspended_until`, or
+/// never when it isn't set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    Suspended,
+    Banned,
+}
+
+impl UserStatus {
+    pub const ALL: [UserStatus; 3] =
+        [UserStatus::Active, UserStatus::Suspended, UserStatus::Banned];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Active => "active",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Banned => "banned",
+        }
+    }
+}
+
+impl FromStr for UserStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        UserStatus::ALL
+            .into_iter()
+            .find(|st| st.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// A user as listed in the admin user search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub created_at: NaiveDateTime,
+    pub points: u32,
+    pub address: Option<String>,
+    pub status: UserStatus,
+    pub suspended_until: Option<NaiveDateTime>,
+    pub status_reason: Option<String>,
+}
+
+/// Profile fields of a user an admin can change, `None` keeps the field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileUpdate {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub address: Option<String>,
+}
+
+/// Why a user's points changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerReason {
+    Grant,
+    Draw,
+    Purchase,
+}
+
+impl LedgerReason {
+    pub const ALL: [LedgerReason; 3] =
+        [LedgerReason::Grant, LedgerReason::Draw, LedgerReason::Purchase];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedgerReason::Grant => "grant",
+            LedgerReason::Draw => "draw",
+            LedgerReason::Purchase => "purchase",
+        }
+    }
+}
+
+impl FromStr for LedgerReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LedgerReason::ALL
+            .into_iter()
+            .find(|r| r.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// One change of a user's points. `balance` is the points after the change and
+/// `reference_id` the order of a draw or purchase, or the user who granted the points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub delta: i32,
+    pub balance: u32,
+    pub reason: LedgerReason,
+    pub reference_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+impl LedgerEntry {
+    pub fn new(user_id: Uuid, delta: i32, balance: u32, reason: LedgerReason) -> Self {
+        LedgerEntry {
+            id: Uuid::new_v4(),
+            user_id,
+            delta,
+            balance,
+            reason,
+            reference_id: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// A prize a user has won or bought
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedPrize {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub title: String,
+    pub level: u32,
+    pub image: String,
+    pub bought_at: NaiveDateTime,
+}
+
+/// Everything support needs to know about a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDetail {
+    pub user: UserSummary,
+    pub roles: Vec<Role>,
+    pub orders: Vec<Order>,
+    pub prizes: Vec<OwnedPrize>,
+    pub ledger: Vec<LedgerEntry>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -151,8 +1026,8 @@ pub struct Amount {
     pub points: u32,
 }
 
-impl From<User> for ResponseUser {
-    fn from(value: User) -> Self {
+impl From<UserProfile> for ResponseUser {
+    fn from(value: UserProfile) -> Self {
         ResponseUser {
             is_superuser: value.is_superuser,
             username: value.username,
@@ -162,7 +1037,10 @@ impl From<User> for ResponseUser {
             owned_products: vec![],
             points: value.points,
             orders: value.orders,
-            address: value.address
+            address: value.address,
+            email_verified: false,
+            two_factor_enabled: false,
+            permissions: vec![],
         }
     }
 }
@@ -211,6 +1089,65 @@ impl From<u32> for Level {
     }
 }
 
+/// One row of a catalogue import or export. Rows are grouped into listings by
+/// `listing_key`, or by `listing` when they have no key, and into boxes by `box`. A
+/// row without a `box` only declares the listing and a row without a `product` only
+/// declares the box. Exports key every listing by its id, so listings with the same
+/// title stay apart. `tags` and `product_tags` are comma separated tag slugs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogueRow {
+    #[serde(default)]
+    pub listing_key: Option<String>,
+    pub listing: String,
+    pub tty: String,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub category_id: Option<Uuid>,
+    pub image: String,
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(rename = "box", default)]
+    pub box_label: Option<String>,
+    #[serde(default)]
+    pub price: Option<u32>,
+    #[serde(default)]
+    pub original_price: Option<u32>,
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(default)]
+    pub product_description: Option<String>,
+    #[serde(default)]
+    pub level: Option<u32>,
+    #[serde(default)]
+    pub amount: Option<u32>,
+    #[serde(default)]
+    pub product_price: Option<u32>,
+    #[serde(default)]
+    pub product_image: Option<String>,
+    #[serde(default)]
+    pub product_tags: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RowError {
+    /// 1-based index of the data row, not counting the CSV header.
+    pub row: usize,
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub committed: bool,
+    pub listings: u32,
+    pub boxes: u32,
+    pub products: u32,
+    pub errors: Vec<RowError>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddressData {
     pub user_id: Uuid,
@@ -0,0 +1,288 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use crate::error::ApiError;
+
+/// Providers send users back to `<CALLBACK_URL>/<provider>/callback`
+const CALLBACK_URL: &str = "http://localhost:3000/auth/oauth";
+const DEFAULT_SCOPES: &str = "openid email profile";
+
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    /// Used in the routes, like `google` in `/auth/oauth/google/login`
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: Vec<String>,
+    pub redirect_uri: String,
+}
+
+/// The endpoints from the provider's discovery document
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// What has to be kept between sending a user to the provider and their return
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub state: String,
+    pub nonce: String,
+    /// The PKCE code verifier, the provider only gets its hash
+    pub verifier: String,
+}
+
+impl AuthRequest {
+    fn new() -> Self {
+        AuthRequest {
+            state: random_token(),
+            nonce: random_token(),
+            verifier: random_token(),
+        }
+    }
+}
+
+/// A user as the provider's ID token describes them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    /// Some providers send it as a string
+    email_verified: Option<serde_json::Value>,
+    preferred_username: Option<String>,
+}
+
+/// An OpenID Connect provider users sign in with through the authorization code flow
+#[derive(Debug)]
+pub struct Provider {
+    config: ProviderConfig,
+    client: reqwest::Client,
+    metadata: OnceCell<Metadata>,
+}
+
+impl Provider {
+    pub fn new(config: ProviderConfig) -> Self {
+        Provider {
+            config,
+            client: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    // Discovered on first use, so the server starts while a provider is down
+    async fn metadata(&self) -> Result<&Metadata, ApiError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: Metadata = self.get_json(&url).await?;
+                match metadata.issuer == self.config.issuer {
+                    true => Ok(metadata),
+                    false => Err(ApiError::OAuthError(format!(
+                        "Discovery document is for issuer {}",
+                        metadata.issuer
+                    ))),
+                }
+            })
+            .await
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        self.client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)
+    }
+
+    /// The URL to send the user to, and what to keep until they come back
+    pub async fn authorize(&self) -> Result<(String, AuthRequest), ApiError> {
+        let metadata = self.metadata().await?;
+        let request = AuthRequest::new();
+        let mut url =
+            reqwest::Url::parse(&metadata.authorization_endpoint).map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &request.state)
+            .append_pair("nonce", &request.nonce)
+            .append_pair("code_challenge", &code_challenge(&request.verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok((url.to_string(), request))
+    }
+
+    /// Trade the code the user came back with for their verified identity
+    pub async fn exchange(&self, code: &str, request: &AuthRequest) -> Result<Identity, ApiError> {
+        let metadata = self.metadata().await?;
+        let tokens: TokenResponse = self
+            .client
+            .post(&metadata.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("code_verifier", &request.verifier),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)?;
+
+        let claims = self.verify_id_token(metadata, &tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(request.nonce.as_str()) {
+            return Err(ApiError::OAuthError("ID token has the wrong nonce".to_owned()));
+        }
+        let email_verified = match claims.email_verified {
+            Some(serde_json::Value::Bool(verified)) => verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+        Ok(Identity {
+            provider: self.config.name.clone(),
+            subject: claims.sub,
+            email: claims.email.map(|e| e.trim().to_lowercase()),
+            email_verified,
+            username: claims.preferred_username,
+        })
+    }
+
+    // Check the signature against the provider's published keys, and that the token was
+    // issued by the provider for us and hasn't expired
+    async fn verify_id_token(&self, metadata: &Metadata, token: &str) -> Result<Claims, ApiError> {
+        let header = decode_header(token).map_err(provider_error)?;
+        // The keys are public, a token signed with one as a shared secret proves nothing
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(ApiError::OAuthError("ID token isn't signed with a public key".to_owned()));
+        }
+        let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or_else(|| ApiError::OAuthError("ID token is signed with an unknown key".to_owned()))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(provider_error)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        decode::<Claims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(provider_error)
+    }
+}
+
+/// The configured providers by name
+#[derive(Debug, Clone, Default)]
+pub struct Providers {
+    providers: HashMap<String, Arc<Provider>>,
+}
+
+impl Providers {
+    pub fn new(providers: Vec<Provider>) -> Self {
+        Providers {
+            providers: providers
+                .into_iter()
+                .map(|p| (p.config.name.clone(), Arc::new(p)))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Provider>, ApiError> {
+        self.providers
+            .get(name)
+            .cloned()
+            .ok_or(ApiError::UnknownProvider)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.providers.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+/// The providers named in `OAUTH_PROVIDERS`, separated by commas. A provider `google` is
+/// configured by `OAUTH_GOOGLE_ISSUER`, `OAUTH_GOOGLE_CLIENT_ID`, `OAUTH_GOOGLE_CLIENT_SECRET`
+/// and optionally `OAUTH_GOOGLE_SCOPES`.
+pub fn from_env() -> Providers {
+    let var = |name: &str| std::env::var(name).ok();
+    let names = var("OAUTH_PROVIDERS").unwrap_or_default();
+    let providers = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let setting = |key: &str| {
+                let var_name = format!("OAUTH_{}_{key}", name.to_uppercase());
+                var(&var_name).unwrap_or_else(|| panic!("{var_name} is not set"))
+            };
+            Provider::new(ProviderConfig {
+                name: name.to_owned(),
+                issuer: setting("ISSUER"),
+                client_id: setting("CLIENT_ID"),
+                client_secret: setting("CLIENT_SECRET"),
+                scopes: var(&format!("OAUTH_{}_SCOPES", name.to_uppercase()))
+                    .unwrap_or_else(|| DEFAULT_SCOPES.to_owned())
+                    .split_whitespace()
+                    .map(str::to_owned)
+                    .collect(),
+                redirect_uri: format!("{CALLBACK_URL}/{name}/callback"),
+            })
+        })
+        .collect();
+    Providers::new(providers)
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// The S256 PKCE challenge of a verifier
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn provider_error(e: impl Display) -> ApiError {
+    ApiError::OAuthError(e.to_string())
+}
@@ -0,0 +1,67 @@
+use rand::Rng;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Shown by authenticator apps next to the account
+const ISSUER: &str = "Ichibankuji";
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+const BACKUP_CODES: usize = 10;
+
+fn totp(secret: &str, account: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned()).to_bytes().ok()?;
+    // The checked constructor refuses accounts with a `:`, which emails may contain
+    Some(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECONDS,
+        secret,
+        Some(ISSUER.to_owned()),
+        account.to_owned(),
+    ))
+}
+
+/// A new base32 encoded secret of 160 bits
+pub fn generate_secret() -> String {
+    let bytes = rand::thread_rng().gen::<[u8; 20]>();
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code
+pub fn provisioning_uri(secret: &str, account: &str) -> Option<String> {
+    totp(secret, account).map(|totp| totp.get_url())
+}
+
+/// The time step `code` belongs to, if it is the code of the step at `now` or of the
+/// step before or after it.
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<i64> {
+    let totp = totp(secret, "")?;
+    let code = code.trim();
+    let step = now / STEP_SECONDS;
+    [step.saturating_sub(1), step, step + 1]
+        .into_iter()
+        .find(|step| totp.check(code, step * STEP_SECONDS))
+        .map(|step| step as i64)
+}
+
+/// Single-use codes for when the authenticator is lost, formatted like `a1b2c-d3e4f`
+pub fn generate_backup_codes() -> Vec<String> {
+    const CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    (0..BACKUP_CODES)
+        .map(|_| {
+            let code = (0..10)
+                .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+                .collect::<String>();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Backup codes are compared without case, spaces or dashes
+pub fn normalize_backup_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
@@ -0,0 +1,58 @@
+use std::{convert::Infallible, str::FromStr};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequest, RequestParts},
+    http::{
+        header::{AUTHORIZATION, COOKIE},
+        HeaderMap,
+    },
+};
+use uuid::Uuid;
+
+const SESSION_COOKIE: &str = "session_id";
+
+/// Who a request says it comes from. An `Authorization: Bearer` access token is used
+/// over the session cookie when a request has both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Session(Uuid),
+    Token(String),
+    None,
+}
+
+impl Credentials {
+    pub fn is_token(&self) -> bool {
+        matches!(self, Credentials::Token(_))
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        if let Some(token) = token {
+            return Credentials::Token(token);
+        }
+
+        let session = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .and_then(|(_, value)| Uuid::from_str(value).ok());
+        session.map_or(Credentials::None, Credentials::Session)
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Credentials {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(Credentials::from_headers(req.headers()))
+    }
+}
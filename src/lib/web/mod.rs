@@ -1,16 +1,32 @@
-use std::str::FromStr;
+use std::{ops::RangeInclusive, str::FromStr};
 
 use crate::{
+    catalogue::CatalogueFormat,
     error::ApiError,
-    models::{self, AddressData, Category, Listing, Product, User},
+    models::{
+        self, Amount, AuditEvent, Category, EntityType, Listing, ListingStatus, ListingType,
+        LogData, Permission, Product, ProfileUpdate, PurchaseLimit, Role, Tag, TagKind, User,
+        UserProfile, UserSecrets, UserStatus,
+    },
 };
 use bcrypt::{hash, DEFAULT_COST};
 use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::Value;
 use uuid::Uuid;
+use validation::{check_email, check_password, check_username, Validator};
 
+pub mod auth;
+pub mod rate_limit;
+pub mod request;
 pub mod routes;
+pub mod validation;
+
+/// Access tokens expire after this many days unless the user picks another time
+const DEFAULT_TOKEN_DAYS: i64 = 30;
+const TOKEN_DAYS: RangeInclusive<i64> = 1..=365;
+const TOKEN_NAME_LENGTH: RangeInclusive<usize> = 1..=64;
+/// How many boxes one call can clone from a template
+const CLONE_COUNT: RangeInclusive<u32> = 1..=100;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Id {
@@ -18,17 +34,43 @@ pub struct Id {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct IdAndReqId {
+pub struct DeleteProduct {
+    pub id: String,
+    pub box_id: String,
+}
+
+impl TryFrom<DeleteProduct> for (Uuid, Uuid) {
+    type Error = ApiError;
+    fn try_from(value: DeleteProduct) -> Result<Self, Self::Error> {
+        Ok((parse_id(&value.id)?, parse_id(&value.box_id)?))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RemovePrize {
     pub id: String,
-    pub req_id: ReqIdStr,
+    pub product_ids: Vec<String>,
 }
 
-impl From<IdAndReqId> for (Uuid, ReqId) {
-    fn from(value: IdAndReqId) -> Self {
-        (Uuid::from_str(&value.id).unwrap(), value.req_id.into())
+impl TryFrom<RemovePrize> for (Uuid, Vec<Uuid>) {
+    type Error = ApiError;
+    fn try_from(value: RemovePrize) -> Result<Self, Self::Error> {
+        Ok((
+            parse_id(&value.id)?,
+            value
+                .product_ids
+                .iter()
+                .map(|id| parse_id(id))
+                .collect::<Result<_, _>>()?,
+        ))
     }
 }
 
+/// Parse an id sent by a client, a malformed one is an `InvalidId`
+pub fn parse_id(id: &str) -> Result<Uuid, ApiError> {
+    Uuid::from_str(id).map_err(|_| ApiError::InvalidId)
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Register {
     pub email: String,
@@ -40,34 +82,62 @@ pub struct Register {
 pub struct SignIn {
     pub email: String,
     pub password: String,
+    /// Authenticator or backup code, needed by users with two-factor authentication
+    #[serde(default)]
+    pub code: Option<String>,
 }
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ReqId {
-    pub id: Uuid,
+pub struct TwoFactorCode {
+    pub code: String,
+}
+/// Where a provider sends the user back to, with a `code` or an `error`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthCallback {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
 }
 
+/// A token from a mailed link
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ReqIdStr {
-    pub id: String,
+pub struct TokenReq {
+    pub token: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct DeleteListing {
-    pub listing_id: String,
-    pub req_id: ReqIdStr,
+pub struct PasswordResetRequest {
+    pub email: String,
 }
 
-impl From<DeleteListing> for (Uuid, ReqId) {
-    fn from(value: DeleteListing) -> Self {
-        (
-            Uuid::from_str(&value.listing_id).unwrap(),
-            value.req_id.into(),
-        )
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PasswordResetConfirm {
+    pub token: String,
+    pub password: String,
+}
+
+impl TryFrom<PasswordResetConfirm> for (String, String) {
+    type Error = ApiError;
+    fn try_from(reset: PasswordResetConfirm) -> Result<Self, Self::Error> {
+        Validator::new()
+            .check("password", check_password(&reset.password, &[]))
+            .finish()?;
+        Ok((reset.token, hash(reset.password, DEFAULT_COST)?))
     }
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReqId {
+    pub id: Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeleteListing {
+    pub listing_id: String,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ReqListing {
-    pub req_id: String,
     pub image: String,
     pub title: String,
     pub tty: String,
@@ -75,18 +145,458 @@ pub struct ReqListing {
     pub category_id: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingSort {
+    #[default]
+    Newest,
+    Price,
+    PriceDesc,
+    Remaining,
+    Popularity,
+}
+
+impl ListingSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingSort::Newest => "newest",
+            ListingSort::Price => "price",
+            ListingSort::PriceDesc => "price_desc",
+            ListingSort::Remaining => "remaining",
+            ListingSort::Popularity => "popularity",
+        }
+    }
+}
+
+/// Query string of the listing endpoints. Only live listings are returned unless
+/// another `status` is asked for, which needs `edit_catalogue`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ListingQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+    pub tty: Option<ListingType>,
+    pub category_id: Option<Uuid>,
+    pub status: Option<ListingStatus>,
+    pub min_price: Option<u32>,
+    pub max_price: Option<u32>,
+    pub has_stock: Option<bool>,
+    /// Comma separated tag slugs, listings have to carry every one of them.
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub sort: ListingSort,
+}
+
+/// Subscribes to one box, or to every box of a listing.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BoxStreamQuery {
+    pub listing_id: Option<Uuid>,
+    pub box_id: Option<Uuid>,
+}
+
+/// Filters of the admin log stream. `action` matches any part of the action, ignoring case.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LogStreamQuery {
+    pub user_id: Option<Uuid>,
+    pub action: Option<String>,
+    /// How many of the latest logs are sent before the live ones, 50 by default.
+    pub replay: Option<u32>,
+}
+
+impl LogStreamQuery {
+    pub fn matches(&self, log: &LogData) -> bool {
+        let user_matches = match self.user_id {
+            Some(id) => log.user_id == id,
+            None => true,
+        };
+        let action_matches = match &self.action {
+            Some(action) => log.action.to_lowercase().contains(&action.to_lowercase()),
+            None => true,
+        };
+        user_matches && action_matches
+    }
+}
+
+/// Filters of `/admin/get/logs`, newest logs first. `from` and `to` bound `created_at`
+/// and `action` matches any part of the action, ignoring case.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LogQuery {
+    pub user_id: Option<Uuid>,
+    pub event: Option<AuditEvent>,
+    pub entity_type: Option<EntityType>,
+    pub entity_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListingStatusUpdate {
+    pub listing_id: String,
+    pub status: ListingStatus,
+}
+
+impl TryFrom<ListingStatusUpdate> for (Uuid, ListingStatus) {
+    type Error = ApiError;
+    fn try_from(value: ListingStatusUpdate) -> Result<Self, Self::Error> {
+        Ok((parse_id(&value.listing_id)?, value.status))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CategoryData {
     pub name: String,
+    pub slug: Option<String>,
+    pub parent_id: Option<String>,
 }
 
-impl From<CategoryData> for Category {
-    fn from(c: CategoryData) -> Self {
-        Self {
+/// Turn a category name into a URL slug, "Anime Figures!" becomes "anime-figures".
+pub fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+impl TryFrom<CategoryData> for Category {
+    type Error = ApiError;
+    fn try_from(c: CategoryData) -> Result<Self, Self::Error> {
+        let id = Uuid::new_v4();
+        let slug = match c.slug.as_deref().map(slugify).unwrap_or_else(|| slugify(&c.name)) {
+            slug if slug.is_empty() => id.to_string()[..8].to_owned(),
+            slug => slug,
+        };
+        Ok(Category {
             created_at: Utc::now().naive_utc(),
+            id,
+            name: c.name,
+            parent_id: c.parent_id.as_deref().map(parse_id).transpose()?,
+            slug,
+            position: 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TagData {
+    pub name: String,
+    pub kind: TagKind,
+    pub slug: Option<String>,
+}
+
+impl From<TagData> for Tag {
+    fn from(t: TagData) -> Self {
+        let id = Uuid::new_v4();
+        let slug = match t.slug.as_deref().map(slugify).unwrap_or_else(|| slugify(&t.name)) {
+            slug if slug.is_empty() => id.to_string()[..8].to_owned(),
+            slug => slug,
+        };
+        Tag {
+            id,
+            name: t.name,
+            slug,
+            kind: t.kind.as_str().to_owned(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TagQuery {
+    pub kind: Option<TagKind>,
+}
+
+/// Replaces the tags of a listing or a product.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TagAssignment {
+    pub id: String,
+    pub tag_ids: Vec<String>,
+}
+
+impl TryFrom<TagAssignment> for (Uuid, Vec<Uuid>) {
+    type Error = ApiError;
+    fn try_from(t: TagAssignment) -> Result<Self, Self::Error> {
+        Ok((
+            parse_id(&t.id)?,
+            t.tag_ids.iter().map(|id| parse_id(id)).collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoleData {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+    #[serde(default)]
+    pub require_two_factor: bool,
+}
+
+impl From<RoleData> for Role {
+    fn from(r: RoleData) -> Self {
+        Role {
             id: Uuid::new_v4(),
+            name: r.name,
+            permissions: r.permissions,
+            require_two_factor: r.require_two_factor,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// Makes the permissions of a role apply only to users with two-factor authentication.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoleTwoFactor {
+    pub id: String,
+    pub required: bool,
+}
+
+impl TryFrom<RoleTwoFactor> for (Uuid, bool) {
+    type Error = ApiError;
+    fn try_from(r: RoleTwoFactor) -> Result<Self, Self::Error> {
+        Ok((parse_id(&r.id)?, r.required))
+    }
+}
+
+/// Replaces the roles of a user.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RoleAssignment {
+    pub user_id: String,
+    pub role_ids: Vec<String>,
+}
+
+impl TryFrom<RoleAssignment> for (Uuid, Vec<Uuid>) {
+    type Error = ApiError;
+    fn try_from(r: RoleAssignment) -> Result<Self, Self::Error> {
+        Ok((
+            parse_id(&r.user_id)?,
+            r.role_ids.iter().map(|id| parse_id(id)).collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PointsGrant {
+    pub user_id: String,
+    pub points: u32,
+}
+
+impl TryFrom<PointsGrant> for Amount {
+    type Error = ApiError;
+    fn try_from(p: PointsGrant) -> Result<Self, Self::Error> {
+        Ok(Amount {
+            user_id: parse_id(&p.user_id)?,
+            points: p.points,
+        })
+    }
+}
+
+/// Filters of `/admin/get/users`, newest users first. `q` matches any part of the
+/// email or username, ignoring case, or the whole id.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UserQuery {
+    pub q: Option<String>,
+    pub status: Option<UserStatus>,
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// Suspends, bans or reinstates a user. `until` only applies to suspensions.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserStatusUpdate {
+    pub user_id: String,
+    pub status: UserStatus,
+    pub until: Option<NaiveDateTime>,
+    pub reason: Option<String>,
+}
+
+impl TryFrom<UserStatusUpdate> for (Uuid, UserStatus, Option<NaiveDateTime>, Option<String>) {
+    type Error = ApiError;
+    fn try_from(u: UserStatusUpdate) -> Result<Self, Self::Error> {
+        let until = match u.status {
+            UserStatus::Suspended => u.until,
+            UserStatus::Active | UserStatus::Banned => None,
+        };
+        Ok((parse_id(&u.user_id)?, u.status, until, u.reason))
+    }
+}
+
+/// A new personal access token, which expires after `expires_in_days`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccessTokenCreation {
+    pub name: String,
+    pub scopes: Vec<Permission>,
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+}
+
+impl TryFrom<AccessTokenCreation> for (String, Vec<Permission>, i64) {
+    type Error = ApiError;
+    fn try_from(t: AccessTokenCreation) -> Result<Self, Self::Error> {
+        let name = t.name.trim().to_owned();
+        let days = t.expires_in_days.unwrap_or(DEFAULT_TOKEN_DAYS);
+        Validator::new()
+            .check(
+                "name",
+                (!TOKEN_NAME_LENGTH.contains(&name.chars().count())).then(|| {
+                    format!(
+                        "Name has to be {} to {} characters long.",
+                        TOKEN_NAME_LENGTH.start(),
+                        TOKEN_NAME_LENGTH.end()
+                    )
+                }),
+            )
+            .check(
+                "expires_in_days",
+                (!TOKEN_DAYS.contains(&days)).then(|| {
+                    format!(
+                        "Tokens expire after {} to {} days.",
+                        TOKEN_DAYS.start(),
+                        TOKEN_DAYS.end()
+                    )
+                }),
+            )
+            .finish()?;
+        Ok((name, t.scopes, days))
+    }
+}
+
+/// Edits the profile of a user, fields which aren't set are kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserUpdate {
+    pub user_id: String,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub address: Option<String>,
+}
+
+impl TryFrom<UserUpdate> for (Uuid, ProfileUpdate) {
+    type Error = ApiError;
+    fn try_from(u: UserUpdate) -> Result<Self, Self::Error> {
+        let username = u.username.map(|name| name.trim().to_owned());
+        let email = u.email.map(|email| email.trim().to_lowercase());
+        let mut validator = Validator::new();
+        if let Some(username) = &username {
+            validator.check("username", check_username(username));
+        }
+        if let Some(email) = &email {
+            validator.check("email", check_email(email));
+        }
+        validator.finish()?;
+        Ok((
+            parse_id(&u.user_id)?,
+            ProfileUpdate {
+                username,
+                email,
+                address: u.address,
+            },
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OrderStatusUpdate {
+    pub id: String,
+    pub status: String,
+}
+
+impl TryFrom<OrderStatusUpdate> for (Uuid, String) {
+    type Error = ApiError;
+    fn try_from(o: OrderStatusUpdate) -> Result<Self, Self::Error> {
+        Ok((parse_id(&o.id)?, o.status))
+    }
+}
+
+/// Replaces the name, slug and parent of a category. Without a `parent_id` the
+/// category moves to the top level, without a `slug` the stored one is kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CategoryUpdate {
+    pub id: String,
+    pub name: String,
+    pub slug: Option<String>,
+    pub parent_id: Option<String>,
+}
+
+impl TryFrom<CategoryUpdate> for Category {
+    type Error = ApiError;
+    fn try_from(c: CategoryUpdate) -> Result<Self, Self::Error> {
+        let keep_slug = c.slug.is_none();
+        let mut category: Category = CategoryData {
             name: c.name,
+            slug: c.slug,
+            parent_id: c.parent_id,
+        }
+        .try_into()?;
+        category.id = parse_id(&c.id)?;
+        // An empty slug tells `update_category` to keep the stored one
+        if keep_slug {
+            category.slug = String::new();
         }
+        Ok(category)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CategoryOrder {
+    pub parent_id: Option<String>,
+    /// Every child of `parent_id`, in the new order.
+    pub ids: Vec<String>,
+}
+
+impl TryFrom<CategoryOrder> for (Option<Uuid>, Vec<Uuid>) {
+    type Error = ApiError;
+    fn try_from(c: CategoryOrder) -> Result<Self, Self::Error> {
+        Ok((
+            c.parent_id.as_deref().map(parse_id).transpose()?,
+            c.ids.iter().map(|id| parse_id(id)).collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+/// Sets the purchase limit of either a listing or a box. Limits left out are not enforced.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PurchaseLimitData {
+    pub listing_id: Option<String>,
+    pub box_id: Option<String>,
+    pub max_per_box: Option<u32>,
+    pub max_per_day: Option<u32>,
+    pub cooldown_seconds: Option<u32>,
+}
+
+impl TryFrom<PurchaseLimitData> for PurchaseLimit {
+    type Error = ApiError;
+    fn try_from(l: PurchaseLimitData) -> Result<Self, Self::Error> {
+        Ok(PurchaseLimit {
+            id: Uuid::new_v4(),
+            listing_id: l.listing_id.as_deref().map(parse_id).transpose()?,
+            box_id: l.box_id.as_deref().map(parse_id).transpose()?,
+            max_per_box: l.max_per_box,
+            max_per_day: l.max_per_day,
+            cooldown_seconds: l.cooldown_seconds,
+            created_at: Utc::now().naive_utc(),
+        })
+    }
+}
+
+/// Turns the queue mode of a box on with the given claim length, or off with `None`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BoxQueueSettings {
+    pub box_id: String,
+    pub claim_seconds: Option<u32>,
+}
+
+impl TryFrom<BoxQueueSettings> for (Uuid, Option<u32>) {
+    type Error = ApiError;
+    fn try_from(q: BoxQueueSettings) -> Result<Self, Self::Error> {
+        Ok((parse_id(&q.box_id)?, q.claim_seconds))
     }
 }
 
@@ -97,6 +607,8 @@ pub struct ProductData {
     pub level: u32,
     pub amount: i32,
     pub image: String,
+    #[serde(default)]
+    pub price: Option<u32>,
 }
 
 impl From<ProductData> for Product {
@@ -114,30 +626,27 @@ impl From<ProductData> for Product {
             status: false,
             created_at: Utc::now().naive_utc(),
             image: p.image,
+            price: p.price,
         }
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProductCreation {
-    pub req_id: ReqIdStr,
     pub product_data: Vec<ProductData>,
     pub box_id: String,
 }
 
-impl From<ProductCreation> for (Vec<Product>, ReqId, Uuid) {
-    fn from(data: ProductCreation) -> Self {
+impl TryFrom<ProductCreation> for (Vec<Product>, Uuid) {
+    type Error = ApiError;
+    fn try_from(data: ProductCreation) -> Result<Self, Self::Error> {
         let mut p_vec = vec![];
         for prod in &data.product_data {
             let prod: Product = prod.clone().into();
             p_vec.push(prod);
         }
 
-        (
-            p_vec,
-            data.req_id.into(),
-            Uuid::from_str(&data.box_id).unwrap(),
-        )
+        Ok((p_vec, parse_id(&data.box_id)?))
     }
 }
 
@@ -147,13 +656,66 @@ pub struct BoxData {
     pub original_price: u32,
     pub listing_id: String,
     pub products: Vec<ProductData>,
+    #[serde(default)]
+    pub claim_seconds: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BoxCreation {
-    req_id: ReqIdStr,
     box_data: BoxData,
 }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoxTemplateCreation {
+    pub box_id: String,
+    pub name: String,
+}
+
+impl TryFrom<BoxTemplateCreation> for (Uuid, String) {
+    type Error = ApiError;
+    fn try_from(data: BoxTemplateCreation) -> Result<Self, Self::Error> {
+        Ok((parse_id(&data.box_id)?, data.name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneBoxes {
+    pub template_id: String,
+    pub listing_id: String,
+    pub count: u32,
+}
+
+impl TryFrom<CloneBoxes> for (Uuid, Uuid, u32) {
+    type Error = ApiError;
+    fn try_from(data: CloneBoxes) -> Result<Self, Self::Error> {
+        Validator::new()
+            .check(
+                "count",
+                (!CLONE_COUNT.contains(&data.count)).then(|| {
+                    format!(
+                        "Count has to be {} to {}.",
+                        CLONE_COUNT.start(),
+                        CLONE_COUNT.end()
+                    )
+                }),
+            )
+            .finish()?;
+        Ok((
+            parse_id(&data.template_id)?,
+            parse_id(&data.listing_id)?,
+            data.count,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogueImport {
+    pub format: CatalogueFormat,
+    pub data: String,
+    /// Without `commit` the import only runs as a dry run.
+    #[serde(default)]
+    pub commit: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageData {
     pub path: String,
@@ -166,18 +728,20 @@ pub struct IdReq {
     pub id: String,
 }
 
-impl From<BoxCreation> for (models::Box, Vec<Product>, ReqId) {
-    fn from(data: BoxCreation) -> Self {
+impl TryFrom<BoxCreation> for (models::Box, Vec<Product>) {
+    type Error = ApiError;
+    fn try_from(data: BoxCreation) -> Result<Self, Self::Error> {
         let mut p_vec = vec![];
         let bx = models::Box {
             original_price: data.box_data.original_price,
             id: Uuid::new_v4(),
             price: data.box_data.price,
-            listing_id: Uuid::from_str(&data.box_data.listing_id).unwrap(),
+            listing_id: parse_id(&data.box_data.listing_id)?,
             created_at: Utc::now().naive_utc(),
             products: vec![],
             total: 0,
             available_products: 0,
+            claim_seconds: data.box_data.claim_seconds,
         };
 
         for prod in &data.box_data.products {
@@ -185,22 +749,15 @@ impl From<BoxCreation> for (models::Box, Vec<Product>, ReqId) {
             p_vec.push(prod);
         }
 
-        (bx, p_vec, data.req_id.into())
+        Ok((bx, p_vec))
     }
 }
-impl From<ReqIdStr> for ReqId {
-    fn from(value: ReqIdStr) -> Self {
-        Self {
-            id: Uuid::from_str(&value.id).unwrap(),
-        }
-    }
-}
-impl From<ReqListing> for Listing {
-    fn from(list: ReqListing) -> Self {
-        println!("{:?}", list.category_id);
-        match list.category_id {
+impl TryFrom<ReqListing> for Listing {
+    type Error = ApiError;
+    fn try_from(list: ReqListing) -> Result<Self, Self::Error> {
+        Ok(match list.category_id {
             Some(id) => {
-                return Self {
+                Self {
                     image: list.image,
                     boxes: vec![],
                     id: Uuid::new_v4(),
@@ -209,7 +766,9 @@ impl From<ReqListing> for Listing {
                     box_count: 0,
                     tty: list.tty,
                     description: list.description,
-                    category_id: Some(Uuid::from_str(&id).unwrap()),
+                    category_id: Some(parse_id(&id)?),
+                    status: ListingStatus::Live.as_str().to_owned(),
+                    tags: vec![],
                 }
             }
             None => Self {
@@ -222,50 +781,47 @@ impl From<ReqListing> for Listing {
                 tty: list.tty,
                 description: list.description,
                 category_id: None,
+                status: ListingStatus::Live.as_str().to_owned(),
+                tags: vec![],
             },
-        }
+        })
     }
 }
 
-impl From<ReqListing> for ReqId {
-    fn from(value: ReqListing) -> Self {
-        Self {
-            id: Uuid::from_str(&value.req_id).unwrap(),
-        }
-    }
-}
 impl TryFrom<Register> for User {
     type Error = ApiError;
     fn try_from(user: Register) -> Result<Self, Self::Error> {
+        let username = user.username.trim().to_owned();
+        let email = user.email.trim().to_lowercase();
+        Validator::new()
+            .check("email", check_email(&email))
+            .check("username", check_username(&username))
+            .check("password", check_password(&user.password, &[&email, &username]))
+            .finish()?;
         let hash_pass = hash(user.password, DEFAULT_COST)?;
         let created_at = Utc::now().naive_utc();
         Ok(Self {
-            private_key: Uuid::new_v4(),
-            username: user.username,
-            email: user.email,
-            id: Uuid::new_v4(),
-            password: hash_pass,
-            created_at,
-            owned_products: vec![],
-            points: 0,
-            is_superuser: false,
-            orders: vec![],
-            address: None,
+            profile: UserProfile {
+                username,
+                email,
+                id: Uuid::new_v4(),
+                created_at,
+                owned_products: vec![],
+                points: 0,
+                is_superuser: false,
+                orders: vec![],
+                address: None,
+            },
+            secrets: UserSecrets {
+                password: hash_pass,
+                private_key: Uuid::new_v4(),
+            },
         })
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AddressDataReq {
-    pub user_id: String,
     pub address: String,
 }
 
-impl From<AddressDataReq> for AddressData {
-    fn from(value: AddressDataReq) -> Self {
-        Self {
-            user_id: Uuid::from_str(&value.user_id).unwrap(),
-            address: value.address,
-        }
-    }
-}
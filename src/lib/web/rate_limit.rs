@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::Request,
+    response::{IntoResponse, Response},
+};
+use hyper::body::HttpBody;
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::{error::ApiError, web::auth::Credentials};
+
+/// Keys with attempts are forgotten once there are this many and their attempts are old
+const MAX_KEYS: usize = 10_000;
+/// The limited routes take small JSON bodies, larger ones are refused before being read
+const MAX_BODY_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Account(String),
+}
+
+/// Counts attempts per IP and per account in a sliding window
+#[derive(Debug)]
+struct Limiter {
+    per_ip: usize,
+    per_account: usize,
+    window: Duration,
+    attempts: Mutex<HashMap<Key, VecDeque<Instant>>>,
+}
+
+impl Limiter {
+    /// Record an attempt, or give how long to wait if a key is over its limit. Refused
+    /// attempts aren't counted.
+    fn attempt(&self, ip: Option<IpAddr>, account: Option<String>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() > MAX_KEYS {
+            attempts.retain(|_, times| times.back().is_some_and(|t| now - *t < self.window));
+        }
+        let keys = [
+            ip.map(|ip| (Key::Ip(ip), self.per_ip)),
+            account.map(|account| (Key::Account(account), self.per_account)),
+        ];
+
+        let mut wait = Duration::ZERO;
+        for (key, limit) in keys.iter().flatten() {
+            if let Some(times) = attempts.get_mut(key) {
+                while times.front().is_some_and(|t| now - *t >= self.window) {
+                    times.pop_front();
+                }
+                if times.len() >= *limit {
+                    wait = wait.max(self.window - (now - times[0]));
+                }
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (key, _) in keys.into_iter().flatten() {
+            attempts.entry(key).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
+
+/// The account a request is for, taken from the `email` of its JSON body
+#[derive(Deserialize)]
+struct AccountKey {
+    email: String,
+}
+
+/// Read the whole body, refusing it once it's over `MAX_BODY_BYTES`
+async fn read_body(mut body: Body) -> Result<Vec<u8>, ApiError> {
+    if body.size_hint().lower() > MAX_BODY_BYTES as u64 {
+        return Err(ApiError::BodyTooLarge);
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| ApiError::InvalidBody)?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(ApiError::BodyTooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Limits how often a route can be called from one IP and for one account. The account
+/// is the `email` of the JSON body, or else the session or access token of the request.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(per_ip: usize, per_account: usize, window: Duration) -> Self {
+        RateLimitLayer {
+            limiter: Arc::new(Limiter {
+                per_ip,
+                per_account,
+                window,
+                attempts: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // The inner service was polled ready, the clone takes its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let ip = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            let (parts, body) = req.into_parts();
+            let bytes = match read_body(body).await {
+                Ok(bytes) => bytes,
+                Err(e) => return Ok(e.into_response()),
+            };
+            let account = match serde_json::from_slice::<AccountKey>(&bytes) {
+                Ok(key) => Some(key.email.trim().to_lowercase()),
+                Err(_) => match Credentials::from_headers(&parts.headers) {
+                    Credentials::Session(key) => Some(key.to_string()),
+                    Credentials::Token(token) => Some(token),
+                    Credentials::None => None,
+                },
+            };
+
+            if let Err(wait) = limiter.attempt(ip, account) {
+                let retry_at = chrono::Utc::now().naive_utc()
+                    + chrono::Duration::from_std(wait).unwrap_or_else(|_| chrono::Duration::zero());
+                return Ok(ApiError::TooManyAttempts(retry_at).into_response());
+            }
+            inner
+                .call(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        })
+    }
+}
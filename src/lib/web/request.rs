@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Where the request being handled came from, audit logs written while handling it
+/// carry this along.
+#[derive(Debug, Clone)]
+pub struct RequestMeta {
+    pub id: Uuid,
+    pub ip: Option<String>,
+}
+
+tokio::task_local! {
+    static REQUEST: RequestMeta;
+}
+
+/// The request handled by the current task, `None` outside of a request.
+pub fn current() -> Option<RequestMeta> {
+    REQUEST.try_with(|meta| meta.clone()).ok()
+}
+
+/// Middleware giving every request an id and remembering the address it came from.
+/// A valid `x-request-id` header is kept, and the id is sent back in the same header.
+pub async fn track_request<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| Uuid::parse_str(h).ok())
+        .unwrap_or_else(Uuid::new_v4);
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+    let mut response = REQUEST.scope(RequestMeta { id, ip }, next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
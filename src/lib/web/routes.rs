@@ -2,26 +2,37 @@ use std::{io, str::FromStr, sync::Arc};
 
 use axum::{
     body::StreamBody,
-    extract::{Multipart, Path},
-    http::{header::COOKIE, HeaderMap, StatusCode},
-    response::IntoResponse,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Multipart, Path, Query,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
     Extension, Json, TypedHeader,
 };
 use headers::ContentType;
 use tower_cookies::{Cookie, Cookies};
-use tungstenite::{Message, WebSocket};
 use uuid::Uuid;
-use tower_http::cors::{Any, CorsLayer};
 
 use crate::{
-    database::{actions::DatabaseHand, Database},
+    catalogue::{self, CatalogueFormat},
+    database::actions::{DatabaseHand, Pool, OAUTH_STATE_MINUTES},
     error::ApiError,
-    models::{self, ImageLink, Listing, Product, ResponseUser, ServerStatus, User, Amount, LogData, Category},
-    web::{ImageData, ReqId},
+    mail::{Mail, FRONTEND_URL},
+    models::{
+        self, AccessToken, AddressData, BackupCodes, BoxEvent, BoxTemplate, Category, CategoryNode,
+        ImageLink, ImportReport, Listing, ListingStatus, ListingSummary, ListingType, LogData,
+        NewAccessToken, Order, Page, Permission, PrizeRemoval, Product, PurchaseLimit, QueueStatus,
+        ResponseUser, Role, SearchResult, ServerStatus, Tag, TokenKind, TwoFactorSetup, User,
+        UserDetail, UserSummary, POINT_GRANT_LIMIT,
+    },
+    web::{auth::Credentials, parse_id, ImageData, ReqId},
     State,
 };
+use chrono::Utc;
 use tokio::fs::File as AsyncFile;
 use tokio::io::BufWriter as AsyncBufWriter;
+use tokio::sync::broadcast;
 
 use axum::body::Bytes;
 use axum::BoxError;
@@ -30,8 +41,13 @@ use futures::{Stream, TryStreamExt};
 use tokio_util::io::{ReaderStream, StreamReader};
 
 use super::{
-    AddressDataReq, BoxCreation, DeleteListing, Id, IdAndReqId, IdReq, ProductCreation, Register,
-    ReqListing, SignIn, CategoryData,
+    AccessTokenCreation, AddressDataReq, BoxCreation, BoxQueueSettings, BoxStreamQuery,
+    BoxTemplateCreation, CatalogueImport, CategoryData, CategoryOrder, CategoryUpdate, CloneBoxes,
+    DeleteListing, DeleteProduct, Id, IdReq, ListingQuery, ListingStatusUpdate, LogQuery,
+    LogStreamQuery, OAuthCallback, OrderStatusUpdate, PasswordResetConfirm, PasswordResetRequest,
+    PointsGrant, ProductCreation, PurchaseLimitData, Register, RemovePrize, ReqListing,
+    RoleAssignment, RoleData, RoleTwoFactor, SearchQuery, SignIn, TagAssignment, TagData, TagQuery,
+    TokenReq, TwoFactorCode, UserQuery, UserStatusUpdate, UserUpdate,
 };
 
 pub async fn register_user(
@@ -42,13 +58,255 @@ pub async fn register_user(
     let pool = data.database.pool.clone();
     let user_data: User = user.0.clone().try_into()?;
     let response = DatabaseHand::create_user(&pool, &user_data).await?;
-    let private_key = DatabaseHand::get_private_key(&pool, &user_data.id)
+    let private_key = DatabaseHand::get_private_key(&pool, &user_data.profile.id)
         .await?
         .to_string();
-    cookies.add(Cookie::new("session_id", private_key));
+    cookies.add(session_cookie(private_key));
+    // The account exists either way, the mail can be sent again
+    if let Err(e) = send_verification(&data, &response).await {
+        eprintln!("Failed to send the verification mail to {}: {e}", response.email);
+    }
     Ok(Json(response))
 }
 
+// The session cookie is sent to every route, whichever route set it
+fn session_cookie(private_key: String) -> Cookie<'static> {
+    Cookie::build("session_id", private_key)
+        .path("/")
+        .http_only(true)
+        .finish()
+}
+
+async fn send_verification(data: &State, user: &ResponseUser) -> Result<(), ApiError> {
+    let pool = data.database.pool.clone();
+    let token = DatabaseHand::create_user_token(&pool, (user.id, TokenKind::VerifyEmail)).await?;
+    data.mailer
+        .send(Mail::email_verification(&user.email, &token))
+        .await
+}
+
+pub async fn verify_email(
+    Extension(data): Extension<Arc<State>>,
+    token: Json<TokenReq>,
+) -> Result<Json<ResponseUser>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = DatabaseHand::verify_email(&pool, &token.0.token).await?;
+    Ok(Json(user))
+}
+
+pub async fn resend_verification(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<ServerStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    if !user.email_verified {
+        send_verification(&data, &user).await?;
+    }
+    Ok(ServerStatus {
+        status: true,
+        message: "Verification mail sent".to_string(),
+    }
+    .into())
+}
+
+// Answers the same whether or not the email belongs to a user. The token and the mail
+// are made after answering, so neither the response nor its timing tells them apart.
+pub async fn request_password_reset(
+    Extension(data): Extension<Arc<State>>,
+    reset: Json<PasswordResetRequest>,
+) -> Result<Json<ServerStatus>, ApiError> {
+    let email = reset.0.email;
+    tokio::spawn(async move {
+        let pool = data.database.pool.clone();
+        let sent = match DatabaseHand::request_password_reset(&pool, &email).await {
+            Ok(Some((email, token))) => {
+                data.mailer.send(Mail::password_reset(&email, &token)).await
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            eprintln!("Failed to send the password reset mail to {email}: {e}");
+        }
+    });
+    Ok(ServerStatus {
+        status: true,
+        message: "If the email belongs to an account, a reset link has been sent".to_string(),
+    }
+    .into())
+}
+
+pub async fn reset_password(
+    Extension(data): Extension<Arc<State>>,
+    reset: Json<PasswordResetConfirm>,
+) -> Result<Json<ServerStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    DatabaseHand::reset_password(&pool, reset.0.try_into()?).await?;
+    Ok(ServerStatus {
+        status: true,
+        message: "Password changed, sign in with the new password".to_string(),
+    }
+    .into())
+}
+
+// Start two-factor enrolment, the secret is confirmed with `confirm_two_factor`
+pub async fn enroll_two_factor(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<TwoFactorSetup>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = signed_in_user(&pool, &credentials).await?;
+    let setup = DatabaseHand::start_two_factor(&pool, &user.id).await?;
+    Ok(Json(setup))
+}
+
+// Other sessions are signed out, this one goes on with a new cookie
+pub async fn confirm_two_factor(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    cookies: Cookies,
+    code: Json<TwoFactorCode>,
+) -> Result<Json<BackupCodes>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = signed_in_user(&pool, &credentials).await?;
+    let (codes, private_key) =
+        DatabaseHand::enable_two_factor(&pool, (user.id, code.0.code)).await?;
+    cookies.add(session_cookie(private_key.to_string()));
+    Ok(Json(codes))
+}
+
+pub async fn disable_two_factor(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    code: Json<TwoFactorCode>,
+) -> Result<Json<ServerStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = signed_in_user(&pool, &credentials).await?;
+    DatabaseHand::disable_two_factor(&pool, (user.id, code.0.code)).await?;
+    Ok(ServerStatus {
+        status: true,
+        message: "Two-factor authentication disabled".to_string(),
+    }
+    .into())
+}
+
+pub async fn regenerate_backup_codes(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    code: Json<TwoFactorCode>,
+) -> Result<Json<BackupCodes>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = signed_in_user(&pool, &credentials).await?;
+    let codes = DatabaseHand::regenerate_backup_codes(&pool, (user.id, code.0.code)).await?;
+    Ok(Json(codes))
+}
+
+// The token is only shown in this answer
+pub async fn create_access_token(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    token: Json<AccessTokenCreation>,
+) -> Result<Json<NewAccessToken>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = signed_in_user(&pool, &credentials).await?;
+    let (name, scopes, days) = token.0.try_into()?;
+    let token = DatabaseHand::create_access_token(&pool, (user.id, name, scopes, days)).await?;
+    Ok(Json(token))
+}
+
+pub async fn get_access_tokens(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<Vec<AccessToken>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let tokens = DatabaseHand::get_access_tokens(&pool, &user.id).await?;
+    Ok(Json(tokens))
+}
+
+pub async fn revoke_access_token(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    token: Json<Id>,
+) -> Result<Json<ServerStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let id = Uuid::from_str(&token.id).map_err(|_| ApiError::InvalidId)?;
+    DatabaseHand::revoke_access_token(&pool, (id, ReqId { id: user.id }, user.permissions)).await?;
+    Ok(ServerStatus {
+        status: true,
+        message: "Access token revoked".to_string(),
+    }
+    .into())
+}
+
+pub async fn get_oauth_providers(
+    Extension(data): Extension<Arc<State>>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    Ok(Json(data.oauth.names()))
+}
+
+/// Holds the state of a provider sign-in in the browser which started it
+const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+// Send the user to the provider to sign in
+pub async fn oauth_login(
+    Extension(data): Extension<Arc<State>>,
+    Path(provider): Path<String>,
+    cookies: Cookies,
+) -> Result<Redirect, ApiError> {
+    let pool = data.database.pool.clone();
+    let provider = data.oauth.get(&provider)?;
+    let (url, request) = provider.authorize().await?;
+    // The callback only takes the state back from the browser which was sent away
+    cookies.add(
+        Cookie::build(OAUTH_STATE_COOKIE, request.state.clone())
+            .path("/auth/oauth")
+            .http_only(true)
+            .max_age(time::Duration::minutes(OAUTH_STATE_MINUTES))
+            .finish(),
+    );
+    DatabaseHand::create_oauth_state(&pool, (provider.name().to_owned(), request)).await?;
+    Ok(Redirect::to(&url))
+}
+
+// The provider sends the user back here, signed in they go on to the frontend
+pub async fn oauth_callback(
+    Extension(data): Extension<Arc<State>>,
+    Path(provider): Path<String>,
+    Query(callback): Query<OAuthCallback>,
+    cookies: Cookies,
+) -> Result<Redirect, ApiError> {
+    let pool = data.database.pool.clone();
+    let provider = data.oauth.get(&provider)?;
+    let started = cookies
+        .get(OAUTH_STATE_COOKIE)
+        .map(|c| c.value().to_owned());
+    cookies.remove(
+        Cookie::build(OAUTH_STATE_COOKIE, "")
+            .path("/auth/oauth")
+            .finish(),
+    );
+    if started.as_deref() != Some(callback.state.as_str()) {
+        return Err(ApiError::InvalidToken);
+    }
+    let request =
+        DatabaseHand::consume_oauth_state(&pool, (provider.name().to_owned(), callback.state))
+            .await?;
+    let code = match (callback.code, callback.error) {
+        (Some(code), None) => code,
+        (_, error) => return Err(ApiError::OAuthError(error.unwrap_or_default())),
+    };
+    let identity = provider.exchange(&code, &request).await?;
+    let user = DatabaseHand::oauth_sign_in(&pool, &identity).await?;
+    let private_key = DatabaseHand::get_private_key(&pool, &user.id)
+        .await?
+        .to_string();
+    cookies.add(session_cookie(private_key));
+    Ok(Redirect::to(FRONTEND_URL))
+}
+
 pub async fn sign_in_user(
     Extension(data): Extension<Arc<State>>,
     user: Json<SignIn>,
@@ -60,52 +318,82 @@ pub async fn sign_in_user(
     let private_key = DatabaseHand::get_private_key(&pool, &response.id)
         .await?
         .to_string();
-    cookies.add(Cookie::new("session_id", private_key));
+    cookies.add(session_cookie(private_key));
     Ok(Json(response))
 }
 pub async fn get_all_users(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
 ) -> Result<Json<Vec<ResponseUser>>, ApiError> {
     let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ViewUsers).await?;
     let users = DatabaseHand::get_users(&pool).await?;
     Ok(Json(users))
 }
 pub async fn auth(
     Extension(data): Extension<Arc<State>>,
-    headers: HeaderMap,
+    credentials: Credentials,
 ) -> Result<Json<ResponseUser>, ApiError> {
     let pool = data.database.pool.clone();
-    match headers.get(COOKIE) {
-        Some(data) => {
-            let cookie = data.to_str().unwrap().split('=').collect::<Vec<_>>()[1];
-            let key: String = cookie.to_string();
-            let user =
-                DatabaseHand::get_user_from_private_key(&pool, &Uuid::from_str(&key).unwrap())
-                    .await?;
-            Ok(Json(user))
-        }
-        None => Err(ApiError::NoSessionCookieFound),
-    }
+    let user = session_user(&pool, &credentials).await?;
+    Ok(Json(user))
 }
 
 pub async fn get_listings(
     Extension(data): Extension<Arc<State>>,
-) -> Result<Json<Vec<Listing>>, ApiError> {
+    Query(query): Query<ListingQuery>,
+    credentials: Credentials,
+) -> Result<Json<Page<ListingSummary>>, ApiError> {
     let pool = data.database.pool.clone();
-    let listings = DatabaseHand::get_listing(&pool).await?;
+    check_listing_status(&pool, &credentials, &query).await?;
+    let listings = DatabaseHand::get_listings_page(&pool, &query).await?;
     Ok(Json(listings))
 }
 
+// Draft and archived listings are only shown to catalogue editors
+async fn check_listing_status(
+    pool: &Pool,
+    credentials: &Credentials,
+    query: &ListingQuery,
+) -> Result<(), ApiError> {
+    if query.status.is_some_and(|status| status != ListingStatus::Live) {
+        require_permission(pool, credentials, Permission::EditCatalogue).await?;
+    }
+    Ok(())
+}
+
+pub async fn search(
+    Extension(data): Extension<Arc<State>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Page<SearchResult>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let results = DatabaseHand::search_listings(&pool, &query).await?;
+    Ok(Json(results))
+}
+
+pub async fn update_listing_status(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    status_data: Json<ListingStatusUpdate>,
+) -> Result<Json<Listing>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (listing_id, status) = status_data.0.try_into()?;
+    let listing = DatabaseHand::update_listing_status(&pool, (listing_id, status, req_id)).await?;
+    Ok(Json(listing))
+}
+
 pub async fn create_listing(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     mut form: Multipart,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
     let mut req_list = ReqListing {
         tty: String::new(),
         title: String::new(),
         image: String::new(),
-        req_id: String::new(),
         description: String::new(),
         category_id: None,
     };
@@ -118,10 +406,6 @@ pub async fn create_listing(
                     let value = f.text().await?;
                     req_list.title = value;
                 }
-                "req_id" => {
-                    let value = f.text().await?;
-                    req_list.req_id = value;
-                }
                 "tty" => {
                     let value = f.text().await?;
                     req_list.tty = value;
@@ -139,14 +423,14 @@ pub async fn create_listing(
                     Some("image/png") => {
                         let id = uuid::Uuid::new_v4().to_string();
                         file_name.push_str(&id);
-                        ext.push_str(&"PNG".to_string());
+                        ext.push_str("PNG");
 
                         stream_to_file(&format!("{id}.png"), f).await.unwrap();
                     }
                     Some("image/jpeg") => {
                         let id = uuid::Uuid::new_v4().to_string();
                         file_name.push_str(&id);
-                        ext.push_str(&"JPG".to_string());
+                        ext.push_str("JPG");
                         println!("{}", id);
                         stream_to_file(&format!("{id}.jpg"), f).await.unwrap();
                     }
@@ -157,8 +441,9 @@ pub async fn create_listing(
         }
     }
 
-    let listing: Listing = req_list.clone().into();
-    let req_id: ReqId = req_list.into();
+    let tty = ListingType::from_str(&req_list.tty).map_err(|_| ApiError::UnknownListingType)?;
+    req_list.tty = tty.as_str().to_owned();
+    let listing: Listing = req_list.try_into()?;
     let image_data = ImageData {
         path: file_name,
         ext,
@@ -175,7 +460,7 @@ where
     E: Into<BoxError>,
 {
     async {
-        let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let body_with_io_error = stream.map_err(|err| io::Error::other(err));
         let body_reader = StreamReader::new(body_with_io_error);
         futures::pin_mut!(body_reader);
 
@@ -192,20 +477,22 @@ where
 
 pub async fn generate_link(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     mut form: Multipart,
 ) -> Result<Json<ImageLink>, ApiError> {
+    require_permission(&data.database.pool, &credentials, Permission::EditCatalogue).await?;
     let id = uuid::Uuid::new_v4();
     let mut img = ImageData {
         path: String::new(),
-        id: id.clone(),
+        id,
         ext: String::new(),
     };
 
     let mut file_name = String::from("database/images/");
     while let Some(f) = form.next_field().await.unwrap() {
         if let Some(name) = f.name() {
-            match name {
-                "file" => match f.content_type() {
+            if name == "file" {
+                match f.content_type() {
                     Some("image/png") => {
                         file_name.push_str(&id.to_string());
                         img.ext = "PNG".to_string();
@@ -217,8 +504,7 @@ pub async fn generate_link(
                         stream_to_file(&format!("{id}.jpg"), f).await.unwrap();
                     }
                     _ => (),
-                },
-                _ => (),
+                }
             }
         }
     }
@@ -230,25 +516,84 @@ pub async fn generate_link(
 }
 pub async fn create_box(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     box_data: Json<BoxCreation>,
 ) -> Result<Json<Vec<models::Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let box_data = box_data.0.into();
-    let bx = DatabaseHand::create_box(&pool, box_data).await?;
-    match DatabaseHand::check_listing_tty(&pool, &bx[0].listing_id)
-        .await?
-        .as_str()
-    {
-        "ICH" => {
-            let lis = DatabaseHand::get_listing_ich(&pool).await?;
-            Ok(Json(lis))
-        }
-        "HEX" => {
-            let lis = DatabaseHand::get_listing_hex(&pool).await?;
-            Ok(Json(lis))
-        }
-        _ => Err(ApiError::NotSuperuser),
-    }
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (bx, products) = box_data.0.try_into()?;
+    let bx = DatabaseHand::create_box(&pool, (bx, products, req_id)).await?;
+    let tty = DatabaseHand::check_listing_tty(&pool, &bx[0].listing_id).await?;
+    let lis = DatabaseHand::get_listings_by_type(&pool, tty).await?;
+    Ok(Json(lis))
+}
+
+pub async fn create_box_template(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    template_data: Json<BoxTemplateCreation>,
+) -> Result<Json<BoxTemplate>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (box_id, name) = template_data.0.try_into()?;
+    let template = DatabaseHand::create_box_template(&pool, (box_id, name, req_id)).await?;
+    Ok(Json(template))
+}
+
+pub async fn get_box_templates(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<Vec<BoxTemplate>>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::EditCatalogue).await?;
+    let templates = DatabaseHand::get_box_templates(&pool).await?;
+    Ok(Json(templates))
+}
+
+pub async fn clone_boxes(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    clone_data: Json<CloneBoxes>,
+) -> Result<Json<Vec<models::Box>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (template_id, listing_id, count) = clone_data.0.try_into()?;
+    let boxes =
+        DatabaseHand::clone_boxes_from_template(&pool, (template_id, listing_id, count, req_id))
+            .await?;
+    Ok(Json(boxes))
+}
+
+pub async fn import_catalogue(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    import_data: Json<CatalogueImport>,
+) -> Result<Json<ImportReport>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let CatalogueImport {
+        format,
+        data,
+        commit,
+    } = import_data.0;
+    let report = catalogue::import(&pool, format, &data, commit, req_id).await?;
+    Ok(Json(report))
+}
+
+pub async fn export_catalogue(
+    Extension(data): Extension<Arc<State>>,
+    Path(format): Path<String>,
+    credentials: Credentials,
+) -> Result<impl IntoResponse, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::EditCatalogue).await?;
+    let format = CatalogueFormat::from_str(&format).map_err(|_| ApiError::UnknownFormat)?;
+    let body = catalogue::export(&pool, format).await?;
+    let content_type = match format {
+        CatalogueFormat::Csv => mime::TEXT_CSV,
+        CatalogueFormat::Json => mime::APPLICATION_JSON,
+    };
+    Ok((TypedHeader(ContentType::from(content_type)), body))
 }
 
 // A test route which says Hello World
@@ -258,7 +603,7 @@ pub async fn hello_world() -> Result<Json<String>, ApiError> {
 
 pub async fn get_random_listings(
     Extension(data): Extension<Arc<State>>,
-) -> Result<Json<Vec<models::Listing>>, ApiError> {
+) -> Result<Json<Vec<ListingSummary>>, ApiError> {
     let pool = data.database.pool.clone();
     let listings = DatabaseHand::get_random_listings(&pool).await?;
     Ok(Json(listings))
@@ -277,38 +622,71 @@ pub async fn get_boxes(
 
 pub async fn delete_listing(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     listing_data: Json<DeleteListing>,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let listings = DatabaseHand::delete_listing(&pool, listing_data.0.clone().into()).await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let listing_id = parse_id(&listing_data.listing_id)?;
+    let listings = DatabaseHand::delete_listing(&pool, (listing_id, req_id)).await?;
     Ok(Json(listings))
 }
 
 pub async fn delete_single_product(
     Extension(data): Extension<Arc<State>>,
-    product_data: Json<IdAndReqId>,
+    credentials: Credentials,
+    product_data: Json<DeleteProduct>,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let products = DatabaseHand::delete_product(&pool, product_data.0.clone().into()).await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (product_id, box_id) = product_data.0.try_into()?;
+    let products = DatabaseHand::delete_product(&pool, (product_id, box_id, req_id)).await?;
     Ok(Json(products))
 }
 
-pub async fn get_listing_hex(
+pub async fn preview_prize_removal(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    product_data: Json<Id>,
+) -> Result<Json<PrizeRemoval>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let product_id = parse_id(&product_data.id)?;
+    let removal = DatabaseHand::preview_prize_removal(&pool, (product_id, req_id)).await?;
+    Ok(Json(removal))
+}
+
+pub async fn remove_prize_from_listing(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    prize_data: Json<RemovePrize>,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let listings = DatabaseHand::get_listing_hex(&pool).await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (product_id, product_ids) = prize_data.0.try_into()?;
+    let listings =
+        DatabaseHand::remove_prize_from_listing(&pool, (product_id, product_ids, req_id)).await?;
     Ok(Json(listings))
 }
 
-pub async fn get_listing_ich(
+pub async fn get_listings_by_type(
     Extension(data): Extension<Arc<State>>,
-) -> Result<Json<Vec<Listing>>, ApiError> {
+    Path(tty): Path<String>,
+    Query(mut query): Query<ListingQuery>,
+    credentials: Credentials,
+) -> Result<Json<Page<ListingSummary>>, ApiError> {
     let pool = data.database.pool.clone();
-    let listings = DatabaseHand::get_listing_ich(&pool).await?;
+    check_listing_status(&pool, &credentials, &query).await?;
+    let tty = ListingType::from_str(&tty).map_err(|_| ApiError::UnknownListingType)?;
+    query.tty = Some(tty);
+    let listings = DatabaseHand::get_listings_page(&pool, &query).await?;
     Ok(Json(listings))
 }
 
+pub async fn get_listing_types() -> Json<Vec<ListingType>> {
+    Json(ListingType::ALL.to_vec())
+}
+
 pub async fn send_server_status() -> Result<Json<ServerStatus>, ApiError> {
     let status = ServerStatus {
         status: true,
@@ -319,22 +697,25 @@ pub async fn send_server_status() -> Result<Json<ServerStatus>, ApiError> {
 
 pub async fn add_product_to_box(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     product_data: Json<ProductCreation>,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let product_data: (Vec<Product>, ReqId, Uuid) = product_data.0.into();
-    let listing =
-        DatabaseHand::add_product_to_box(&pool, (product_data.1, product_data.2, product_data.0))
-            .await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (products, box_id) = product_data.0.try_into()?;
+    let listing = DatabaseHand::add_product_to_box(&pool, (req_id, box_id, products)).await?;
     Ok(Json(listing))
 }
 
 pub async fn delete_box(
     Extension(data): Extension<Arc<State>>,
-    box_data: Json<IdAndReqId>,
+    credentials: Credentials,
+    box_data: Json<Id>,
 ) -> Result<Json<Vec<Listing>>, ApiError> {
     let pool = data.database.pool.clone();
-    let _ = DatabaseHand::delete_box(&pool, box_data.0.clone().into()).await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let box_id = parse_id(&box_data.id)?;
+    let _ = DatabaseHand::delete_box(&pool, (box_id, req_id)).await?;
     Ok(Json(DatabaseHand::get_listing(&pool).await?))
 }
 pub async fn get_image(
@@ -347,7 +728,7 @@ pub async fn get_image(
         "{:?}",
         DatabaseHand::get_image_ext(&pool, &Uuid::from_str(&id).unwrap()).await
     );
-    let mut rp = "database/images/".to_string();
+    let rp;
     let (file, raw_path) =
         match DatabaseHand::get_image_ext(&pool, &Uuid::from_str(&id).unwrap()).await {
             Ok(img) => {
@@ -403,11 +784,11 @@ pub async fn get_image(
     match raw_path.split('.').collect::<Vec<_>>()[1] {
         "png" => {
             let he = TypedHeader(ContentType::from(mime::IMAGE_PNG));
-            return Ok((he, body));
+            Ok((he, body))
         }
         "jpg" => {
             let he = TypedHeader(ContentType::from(mime::IMAGE_JPEG));
-            return Ok((he, body));
+            Ok((he, body))
         }
         _ => {
             dbg!("ERROR HIT");
@@ -426,7 +807,7 @@ pub async fn get_listing_from_id(
             let listing = DatabaseHand::get_listing_from_id(&pool, &i).await?;
             Ok(Json(listing))
         }
-        Err(_) => return Err(ApiError::InvalidId),
+        Err(_) => Err(ApiError::InvalidId),
     }
 }
 
@@ -442,21 +823,208 @@ pub async fn get_product(
 
 pub async fn buy_box(
     Extension(data): Extension<Arc<State>>,
-    box_data: Json<IdAndReqId>,
+    credentials: Credentials,
+    box_data: Json<Id>,
 ) -> Result<Json<Product>, ApiError> {
     let pool = data.database.pool.clone();
-    Ok(Json(
-        DatabaseHand::buy_box(&pool, box_data.0.clone().into()).await?,
-    ))
+    let user = session_user(&pool, &credentials).await?;
+    let box_id = parse_id(&box_data.id)?;
+    let product = DatabaseHand::buy_box(&pool, (box_id, ReqId { id: user.id })).await?;
+    publish_draw(&data, &product).await;
+    Ok(Json(product))
+}
+
+// Tell the subscribers of the box what was won and what is left. The purchase
+// already went through, so a failure here is not returned to the buyer.
+async fn publish_draw(data: &State, product: &Product) {
+    let pool = data.database.pool.clone();
+    if let Ok(mut stock) = DatabaseHand::get_box_stock(&pool, &[product.box_id]).await {
+        if let Some(stock) = stock.pop() {
+            data.events.publish_box(BoxEvent::Draw {
+                stock,
+                prize: product.title.clone(),
+                level: product.level,
+                message: "A prize just won!".to_owned(),
+                created_at: Utc::now().naive_utc(),
+            });
+        }
+    }
+}
+
+pub async fn box_stream(
+    Extension(data): Extension<Arc<State>>,
+    Query(query): Query<BoxStreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, ApiError> {
+    let pool = data.database.pool.clone();
+    let box_ids = match (query.box_id, query.listing_id) {
+        (Some(box_id), _) => vec![box_id],
+        (None, Some(listing_id)) => DatabaseHand::get_boxes_of_listing(&pool, &listing_id)
+            .await?
+            .into_iter()
+            .map(|b| b.id)
+            .collect(),
+        (None, None) => return Err(ApiError::InvalidId),
+    };
+    // Subscribe before taking the snapshot so no draw falls in between
+    let events = data.events.subscribe_boxes();
+    let stock = DatabaseHand::get_box_stock(&pool, &box_ids).await?;
+    if stock.is_empty() && query.box_id.is_some() {
+        return Err(ApiError::InvalidId);
+    }
+    Ok(ws.on_upgrade(move |socket| stream_box_events(socket, events, stock, query)))
+}
+
+async fn stream_box_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<BoxEvent>,
+    stock: Vec<models::BoxStock>,
+    query: BoxStreamQuery,
+) {
+    for stock in stock {
+        if send_json(&mut socket, &BoxEvent::Stock(stock)).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let stock = event.stock();
+                    let wanted = match query.box_id {
+                        Some(box_id) => stock.box_id == box_id,
+                        None => Some(stock.listing_id) == query.listing_id,
+                    };
+                    if wanted && send_json(&mut socket, &event).await.is_err() {
+                        return;
+                    }
+                }
+                // A slow client skips the draws it missed, the next one carries the full stock
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+async fn send_json<T: serde::Serialize>(
+    socket: &mut WebSocket,
+    value: &T,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(value).unwrap_or_default();
+    socket.send(Message::Text(text)).await
+}
+
+pub async fn set_purchase_limit(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    limit_data: Json<PurchaseLimitData>,
+) -> Result<Json<PurchaseLimit>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let limit = limit_data.0.try_into()?;
+    let limit = DatabaseHand::set_purchase_limit(&pool, (limit, req_id)).await?;
+    Ok(Json(limit))
+}
+
+pub async fn delete_purchase_limit(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    limit_data: Json<Id>,
+) -> Result<Json<Vec<PurchaseLimit>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let limit_id = parse_id(&limit_data.id)?;
+    let limits = DatabaseHand::delete_purchase_limit(&pool, (limit_id, req_id)).await?;
+    Ok(Json(limits))
+}
+
+pub async fn get_purchase_limits(
+    Extension(data): Extension<Arc<State>>,
+) -> Result<Json<Vec<PurchaseLimit>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let limits = DatabaseHand::get_purchase_limits(&pool).await?;
+    Ok(Json(limits))
+}
+
+pub async fn set_box_queue(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    queue_data: Json<BoxQueueSettings>,
+) -> Result<Json<Vec<models::Box>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (box_id, claim_seconds) = queue_data.0.try_into()?;
+    let boxes = DatabaseHand::set_box_queue(&pool, (box_id, claim_seconds, req_id)).await?;
+    Ok(Json(boxes))
+}
+
+pub async fn join_queue(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    queue_data: Json<Id>,
+) -> Result<Json<QueueStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let box_id = parse_id(&queue_data.id)?;
+    let status = DatabaseHand::join_queue(&pool, (box_id, ReqId { id: user.id })).await?;
+    Ok(Json(status))
+}
+
+pub async fn leave_queue(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    queue_data: Json<Id>,
+) -> Result<Json<QueueStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let box_id = parse_id(&queue_data.id)?;
+    let status = DatabaseHand::leave_queue(&pool, (box_id, ReqId { id: user.id })).await?;
+    Ok(Json(status))
+}
+
+pub async fn get_queue_status(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    queue_data: Json<Id>,
+) -> Result<Json<QueueStatus>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let box_id = parse_id(&queue_data.id)?;
+    let status = DatabaseHand::get_queue_status(&pool, (box_id, ReqId { id: user.id })).await?;
+    Ok(Json(status))
+}
+
+pub async fn buy_product(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    product_data: Json<Id>,
+) -> Result<Json<Product>, ApiError> {
+    let pool = data.database.pool.clone();
+    let user = session_user(&pool, &credentials).await?;
+    let product_id = parse_id(&product_data.id)?;
+    let product = DatabaseHand::buy_product(&pool, (product_id, ReqId { id: user.id })).await?;
+    publish_draw(&data, &product).await;
+    Ok(Json(product))
 }
 
 // update address
 pub async fn update_address(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     address_data: Json<AddressDataReq>,
 ) -> Result<Json<ResponseUser>, ApiError> {
     let pool = data.database.pool.clone();
-    let u = DatabaseHand::update_address(&pool, address_data.0.clone().into()).await?;
+    let user = session_user(&pool, &credentials).await?;
+    let address = AddressData {
+        user_id: user.id,
+        address: address_data.0.address,
+    };
+    let u = DatabaseHand::update_address(&pool, address).await?;
     Ok(Json(u))
 }
 
@@ -464,7 +1032,7 @@ pub async fn logout(
     Extension(_): Extension<Arc<State>>,
     cookies: Cookies,
 ) -> Result<Json<ServerStatus>, ApiError> {
-    cookies.remove(Cookie::named("session"));
+    cookies.remove(Cookie::build("session_id", "").path("/").finish());
     Ok(ServerStatus {
         status: true,
         message: "Logged out".to_string(),
@@ -474,31 +1042,93 @@ pub async fn logout(
 
 pub async fn add_points(
     Extension(data): Extension<Arc<State>>,
-    points_data: Json<Amount>,
+    credentials: Credentials,
+    points_data: Json<PointsGrant>,
 ) -> Result<Json<ResponseUser>, ApiError> {
     let pool = data.database.pool.clone();
-    let u = DatabaseHand::add_coins(&pool, &points_data).await?;
+    let permission = match points_data.points > POINT_GRANT_LIMIT {
+        true => Permission::GrantAnyPoints,
+        false => Permission::GrantPoints,
+    };
+    let user = session_user(&pool, &credentials).await?;
+    if !user.permissions.contains(&permission)
+        && !user.permissions.contains(&Permission::GrantAnyPoints)
+    {
+        return Err(ApiError::MissingPermission(permission));
+    }
+    let req_id = ReqId { id: user.id };
+    let amount = points_data.0.try_into()?;
+    let u = DatabaseHand::add_coins(&pool, (amount, req_id)).await?;
     Ok(Json(u))
 }
 
 pub async fn get_logs(
     Extension(data): Extension<Arc<State>>,
-) -> Result<Json<Vec<LogData>>, ApiError> {
+    Query(query): Query<LogQuery>,
+    credentials: Credentials,
+) -> Result<Json<Page<LogData>>, ApiError> {
     let pool = data.database.pool.clone();
-    let logs = DatabaseHand::get_logs(&pool).await?;
+    require_permission(&pool, &credentials, Permission::ViewLogs).await?;
+    let logs = DatabaseHand::get_logs(&pool, &query).await?;
     Ok(Json(logs))
 }
 
 pub async fn create_category(
     Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
     category_data: Json<CategoryData>,
 ) -> Result<Json<Category>, ApiError> {
     let pool = data.database.pool.clone();
-    let category_data: Category = category_data.0.clone().into();
-    let category = DatabaseHand::create_category(&pool, &category_data).await?;
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let category = category_data.0.try_into()?;
+    let category = DatabaseHand::create_category(&pool, (category, req_id)).await?;
+    Ok(Json(category))
+}
+
+pub async fn update_category(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    category_data: Json<CategoryUpdate>,
+) -> Result<Json<Category>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let category = category_data.0.try_into()?;
+    let category = DatabaseHand::update_category(&pool, (category, req_id)).await?;
     Ok(Json(category))
 }
 
+pub async fn delete_category(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    category_data: Json<Id>,
+) -> Result<Json<Vec<Category>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let category_id = parse_id(&category_data.id)?;
+    let categories = DatabaseHand::delete_category(&pool, (category_id, req_id)).await?;
+    Ok(Json(categories))
+}
+
+pub async fn reorder_categories(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    order_data: Json<CategoryOrder>,
+) -> Result<Json<Vec<Category>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (parent_id, ids) = order_data.0.try_into()?;
+    let categories = DatabaseHand::reorder_categories(&pool, (parent_id, ids, req_id)).await?;
+    Ok(Json(categories))
+}
+
+pub async fn get_category_tree(
+    Extension(data): Extension<Arc<State>>,
+) -> Result<Json<Vec<CategoryNode>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let tree = DatabaseHand::get_category_tree(&pool).await?;
+    Ok(Json(tree))
+}
+
 pub async fn get_categories(
     Extension(data): Extension<Arc<State>>,
 ) -> Result<Json<Vec<Category>>, ApiError> {
@@ -508,29 +1138,306 @@ pub async fn get_categories(
 }
 
 
-// Websocket route which shows realtime logs axum can be used to create websocket routes as well.
-// This route will be used to send logs to the client and make it compatible with the axum
-// websocket route.
-
-// pub async fn ws_route(
-//     Extension(data): Extension<Arc<State>>,
-//     // The `ws` extractor extracts the `WebSocket` from the request.
-//     // import the `ws` extractor from `axum::extract::ws`
-//     ws: WebSocket,
-// ) -> Result<impl IntoResponse, ApiError> {
-//     let (mut sender, mut receiver) = ws.split();
-//     let mut stream = tokio::stream::StreamExt::fuse(tokio::stream::iter(vec![
-//         Ok(Message::text("Hello")),
-//         Ok(Message::text("World")),
-//     ]));
-//     while let Some(message) = stream.next().await {
-//         sender.send(message?).await?;
-//     }
-//     while let Some(message) = receiver.next().await {
-//         let message = message?;
-//         if message.is_close() {
-//             break;
-//         }
-//     }
-//     Ok("Hello")
-// }
+pub async fn create_tag(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    tag_data: Json<TagData>,
+) -> Result<Json<Tag>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let tag = DatabaseHand::create_tag(&pool, (tag_data.0.into(), req_id)).await?;
+    Ok(Json(tag))
+}
+
+pub async fn delete_tag(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    tag_data: Json<Id>,
+) -> Result<Json<Vec<Tag>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let tag_id = parse_id(&tag_data.id)?;
+    let tags = DatabaseHand::delete_tag(&pool, (tag_id, req_id)).await?;
+    Ok(Json(tags))
+}
+
+pub async fn get_tags(
+    Extension(data): Extension<Arc<State>>,
+    Query(query): Query<TagQuery>,
+) -> Result<Json<Vec<Tag>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let tags = DatabaseHand::get_tags(&pool, query.kind).await?;
+    Ok(Json(tags))
+}
+
+pub async fn tag_listing(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    tag_data: Json<TagAssignment>,
+) -> Result<Json<Listing>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (listing_id, tag_ids) = tag_data.0.try_into()?;
+    let listing = DatabaseHand::set_listing_tags(&pool, (listing_id, tag_ids, req_id)).await?;
+    Ok(Json(listing))
+}
+
+pub async fn tag_product(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    tag_data: Json<TagAssignment>,
+) -> Result<Json<Vec<Tag>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::EditCatalogue).await?;
+    let (product_id, tag_ids) = tag_data.0.try_into()?;
+    let tags = DatabaseHand::set_product_tags(&pool, (product_id, tag_ids, req_id)).await?;
+    Ok(Json(tags))
+}
+
+// Websocket route which shows realtime logs to superusers. The latest logs are
+// replayed first, then every new log is pushed as soon as it is committed.
+pub async fn log_stream(
+    Extension(data): Extension<Arc<State>>,
+    Query(query): Query<LogStreamQuery>,
+    credentials: Credentials,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ViewLogs).await?;
+    // Subscribe before replaying so no log falls in between
+    let events = data.events.subscribe_logs();
+    let replay = query.replay.unwrap_or(50).min(1000);
+    let logs = DatabaseHand::get_recent_logs(&pool, &query, replay).await?;
+    Ok(ws.on_upgrade(move |socket| stream_logs(socket, events, logs, query)))
+}
+
+async fn stream_logs(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<LogData>,
+    replayed: Vec<LogData>,
+    query: LogStreamQuery,
+) {
+    for log in &replayed {
+        if send_json(&mut socket, log).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            log = events.recv() => match log {
+                Ok(log) => {
+                    // Logs committed while replaying were already sent
+                    let sent = replayed.iter().any(|r| r.id == log.id);
+                    if !sent && query.matches(&log) && send_json(&mut socket, &log).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+// The user signed in with the session cookie
+pub async fn get_orders(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<Vec<Order>>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ManageOrders).await?;
+    let orders = DatabaseHand::get_all_orders(&pool).await?;
+    Ok(Json(orders))
+}
+
+pub async fn update_order(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    order_data: Json<OrderStatusUpdate>,
+) -> Result<Json<Order>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::ManageOrders).await?;
+    let (order_id, status) = order_data.0.try_into()?;
+    let order = DatabaseHand::update_order_status(&pool, (order_id, status, req_id)).await?;
+    Ok(Json(order))
+}
+
+pub async fn get_roles(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<Vec<Role>>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ManageRoles).await?;
+    let roles = DatabaseHand::get_roles(&pool).await?;
+    Ok(Json(roles))
+}
+
+pub async fn create_role(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    role_data: Json<RoleData>,
+) -> Result<Json<Role>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::ManageRoles).await?;
+    let role = DatabaseHand::create_role(&pool, (role_data.0.into(), req_id)).await?;
+    Ok(Json(role))
+}
+
+pub async fn delete_role(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    role_data: Json<Id>,
+) -> Result<Json<Vec<Role>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::ManageRoles).await?;
+    let role_id = parse_id(&role_data.id)?;
+    let roles = DatabaseHand::delete_role(&pool, (role_id, req_id)).await?;
+    Ok(Json(roles))
+}
+
+pub async fn set_role_two_factor(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    role_data: Json<RoleTwoFactor>,
+) -> Result<Json<Role>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::ManageRoles).await?;
+    let (role_id, required) = role_data.0.try_into()?;
+    let role = DatabaseHand::set_role_two_factor(&pool, (role_id, required, req_id)).await?;
+    Ok(Json(role))
+}
+
+pub async fn set_user_roles(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    role_data: Json<RoleAssignment>,
+) -> Result<Json<Vec<Role>>, ApiError> {
+    let pool = data.database.pool.clone();
+    let req_id = acting_user(&pool, &credentials, Permission::ManageRoles).await?;
+    let (user_id, role_ids) = role_data.0.try_into()?;
+    let roles = DatabaseHand::set_user_roles(&pool, (user_id, role_ids, req_id)).await?;
+    Ok(Json(roles))
+}
+
+pub async fn search_users(
+    Extension(data): Extension<Arc<State>>,
+    Query(query): Query<UserQuery>,
+    credentials: Credentials,
+) -> Result<Json<Page<UserSummary>>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ViewUsers).await?;
+    let users = DatabaseHand::search_users(&pool, &query).await?;
+    Ok(Json(users))
+}
+
+pub async fn get_user_detail(
+    Path(id): Path<String>,
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<UserDetail>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ViewUsers).await?;
+    let id = Uuid::from_str(&id).map_err(|_| ApiError::InvalidId)?;
+    let user = DatabaseHand::get_user_detail(&pool, &id).await?;
+    Ok(Json(user))
+}
+
+pub async fn get_user_access_tokens(
+    Path(id): Path<String>,
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<Vec<AccessToken>>, ApiError> {
+    let pool = data.database.pool.clone();
+    require_permission(&pool, &credentials, Permission::ViewUsers).await?;
+    let id = Uuid::from_str(&id).map_err(|_| ApiError::InvalidId)?;
+    let tokens = DatabaseHand::get_access_tokens(&pool, &id).await?;
+    Ok(Json(tokens))
+}
+
+pub async fn set_user_status(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    status_data: Json<UserStatusUpdate>,
+) -> Result<Json<UserSummary>, ApiError> {
+    let pool = data.database.pool.clone();
+    let actor = require_permission(&pool, &credentials, Permission::ManageUsers).await?;
+    let (user_id, status, until, reason) = status_data.0.try_into()?;
+    let req_id = ReqId { id: actor.id };
+    let status_data = (user_id, status, until, reason, req_id, actor.permissions);
+    let user = DatabaseHand::set_user_status(&pool, status_data).await?;
+    Ok(Json(user))
+}
+
+pub async fn update_user(
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+    user_data: Json<UserUpdate>,
+) -> Result<Json<UserSummary>, ApiError> {
+    let pool = data.database.pool.clone();
+    let actor = require_permission(&pool, &credentials, Permission::ManageUsers).await?;
+    let (user_id, update) = user_data.0.try_into()?;
+    let req_id = ReqId { id: actor.id };
+    let user_data = (user_id, update, req_id, actor.permissions);
+    let user = DatabaseHand::update_user(&pool, user_data).await?;
+    Ok(Json(user))
+}
+
+// Read-only: the response is what `/auth/verify` would give the user, no session is
+// handed out.
+pub async fn view_as_user(
+    Path(id): Path<String>,
+    Extension(data): Extension<Arc<State>>,
+    credentials: Credentials,
+) -> Result<Json<ResponseUser>, ApiError> {
+    let pool = data.database.pool.clone();
+    let viewer = require_permission(&pool, &credentials, Permission::ViewUsers).await?;
+    let id = parse_id(&id)?;
+    let user = DatabaseHand::view_as_user(&pool, (id, viewer.id)).await?;
+    Ok(Json(user))
+}
+
+// Routes check the permission of the session's or access token's user
+async fn require_permission(
+    pool: &Pool,
+    credentials: &Credentials,
+    permission: Permission,
+) -> Result<ResponseUser, ApiError> {
+    let user = session_user(pool, credentials).await?;
+    match user.permissions.contains(&permission) {
+        true => Ok(user),
+        false => Err(ApiError::MissingPermission(permission)),
+    }
+}
+
+// Admin mutations are logged as the user whose permission was checked
+async fn acting_user(
+    pool: &Pool,
+    credentials: &Credentials,
+    permission: Permission,
+) -> Result<ReqId, ApiError> {
+    let user = require_permission(pool, credentials, permission).await?;
+    Ok(ReqId { id: user.id })
+}
+
+async fn session_user(pool: &Pool, credentials: &Credentials) -> Result<ResponseUser, ApiError> {
+    match credentials {
+        Credentials::Session(key) => DatabaseHand::get_user_from_private_key(pool, key)
+            .await
+            .map_err(|_| ApiError::NoSessionCookieFound),
+        Credentials::Token(token) => DatabaseHand::get_user_from_access_token(pool, token).await,
+        Credentials::None => Err(ApiError::NoSessionCookieFound),
+    }
+}
+
+// Two-factor settings and new access tokens need a session, so a leaked access token
+// can't be turned into lasting access
+async fn signed_in_user(pool: &Pool, credentials: &Credentials) -> Result<ResponseUser, ApiError> {
+    match credentials.is_token() {
+        true => Err(ApiError::SessionRequired),
+        false => session_user(pool, credentials).await,
+    }
+}
@@ -0,0 +1,101 @@
+use std::ops::RangeInclusive;
+
+use crate::error::{ApiError, FieldError};
+
+pub const USERNAME_LENGTH: RangeInclusive<usize> = 3..=32;
+/// bcrypt ignores everything after 72 bytes
+pub const PASSWORD_LENGTH: RangeInclusive<usize> = 8..=72;
+
+/// Collects the problems with the fields of a request, so they are all reported at once.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    /// Record `problem` for `field`, if there is one
+    pub fn check(&mut self, field: &str, problem: Option<String>) -> &mut Self {
+        if let Some(message) = problem {
+            self.errors.push(FieldError {
+                field: field.to_owned(),
+                message,
+            });
+        }
+        self
+    }
+
+    pub fn finish(&mut self) -> Result<(), ApiError> {
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(ApiError::Validation(std::mem::take(&mut self.errors))),
+        }
+    }
+}
+
+// The checks below give what is wrong with the value, `None` if nothing is
+
+pub fn check_email(email: &str) -> Option<String> {
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return Some("Email needs an @.".to_owned()),
+    };
+    let valid = email.len() <= 254
+        && !local.is_empty()
+        && local.len() <= 64
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && !domain.contains('@')
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    match valid {
+        true => None,
+        false => Some("Email is not a valid address.".to_owned()),
+    }
+}
+
+pub fn check_username(username: &str) -> Option<String> {
+    if !USERNAME_LENGTH.contains(&username.chars().count()) {
+        return Some(format!(
+            "Username has to be {} to {} characters long.",
+            USERNAME_LENGTH.start(),
+            USERNAME_LENGTH.end()
+        ));
+    }
+    match username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        true => None,
+        false => Some("Username can only have letters, digits, _, . and -.".to_owned()),
+    }
+}
+
+/// `personal` are the user's email and username, which the password can't be
+pub fn check_password(password: &str, personal: &[&str]) -> Option<String> {
+    if !PASSWORD_LENGTH.contains(&password.len()) {
+        return Some(format!(
+            "Password has to be {} to {} characters long.",
+            PASSWORD_LENGTH.start(),
+            PASSWORD_LENGTH.end()
+        ));
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit())
+    {
+        return Some("Password needs at least one letter and one digit.".to_owned());
+    }
+    match personal
+        .iter()
+        .any(|p| !p.is_empty() && p.eq_ignore_ascii_case(password))
+    {
+        true => Some("Password can't be your email or username.".to_owned()),
+        false => None,
+    }
+}
@@ -0,0 +1,118 @@
+//! Loading listings must run a fixed number of queries no matter how many
+//! listings, boxes and products there are. The test needs `DATABASE_URL` to
+//! point at a migrated database and is skipped otherwise. Everything it
+//! inserts is rolled back.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use api::database::actions::DatabaseHand;
+use chrono::Utc;
+use sqlx::{Connection, PgConnection};
+use uuid::Uuid;
+
+/// Counts the statements sqlx logs under the `sqlx::query` target.
+struct QueryCounter {
+    queries: AtomicUsize,
+}
+
+impl log::Log for QueryCounter {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == "sqlx::query"
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static COUNTER: QueryCounter = QueryCounter {
+    queries: AtomicUsize::new(0),
+};
+
+async fn seed_listing(conn: &mut PgConnection, boxes: usize, products: usize) -> Uuid {
+    let now = Utc::now().naive_utc();
+    let listing_id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO listing (id, title, created_at, tty, description) VALUES ($1, 'Test', $2, 'ICH', '')",
+    )
+    .bind(listing_id)
+    .bind(now)
+    .execute(&mut *conn)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO images (path, for_id, extension) VALUES ('database/images/test', $1, 'PNG')")
+        .bind(listing_id)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+    for _ in 0..boxes {
+        let box_id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO box (id, price, original_price, listing_id, created_at) VALUES ($1, 100, 100, $2, $3)",
+        )
+        .bind(box_id)
+        .bind(listing_id)
+        .bind(now)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        for level in 0..products {
+            sqlx::query(
+                "INSERT INTO products (box_id, title, id, description, level, status, created_at, amount, image, ini_amount)
+                 VALUES ($1, 'Prize', $2, '', $3, false, $4, 1, '', 1)",
+            )
+            .bind(box_id)
+            .bind(Uuid::new_v4())
+            .bind(level as i32)
+            .bind(now)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        }
+    }
+    listing_id
+}
+
+#[tokio::test]
+async fn loading_listings_runs_a_fixed_number_of_queries() {
+    let url = match std::env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return;
+        }
+    };
+    log::set_logger(&COUNTER).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let mut conn = PgConnection::connect(&url).await.unwrap();
+    let mut tx = conn.begin().await.unwrap();
+
+    let mut queries = vec![];
+    for listings in [1, 10] {
+        let mut ids = vec![];
+        for _ in 0..listings {
+            ids.push(seed_listing(&mut tx, 3, 4).await);
+        }
+
+        COUNTER.queries.store(0, Ordering::SeqCst);
+        let loaded = DatabaseHand::load_listings(&mut tx, &ids).await.unwrap();
+        queries.push(COUNTER.queries.load(Ordering::SeqCst));
+
+        assert_eq!(loaded.iter().map(|l| l.id).collect::<Vec<_>>(), ids);
+        for listing in &loaded {
+            assert_eq!(listing.box_count, 3);
+            assert!(listing.image.ends_with(&listing.id.to_string()));
+            assert!(listing.boxes.iter().all(|b| b.products.len() == 4));
+        }
+    }
+
+    assert_eq!(queries[0], queries[1]);
+    // The listings, their images, boxes, products and tags
+    assert_eq!(queries[0], 5, "loading listings ran {} queries", queries[0]);
+    tx.rollback().await.unwrap();
+}
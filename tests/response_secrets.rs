@@ -0,0 +1,456 @@
+//! No response of the API may carry a password hash, a session key or any other
+//! secret. Every type a route answers with is serialised with sample values and its
+//! keys are checked against the names secrets are stored under. The test doesn't
+//! need a database.
+
+use api::{
+    error::ApiError,
+    models::{
+        AccessToken, AuditEvent, BackupCodes, Box, BoxEvent, BoxStock, BoxTemplate, Category,
+        CategoryNode, ImageLink, ImportReport, LedgerEntry, LedgerReason, Listing,
+        ListingSummary, LogData, NewAccessToken, Order, OwnedPrize, Page, Permission,
+        PrizeRemoval, Product, PurchaseLimit, QueueStatus, ResponseUser, Role, RowError,
+        SearchResult, ServerStatus, Tag, TemplateProduct, TierStock, TwoFactorSetup, User,
+        UserDetail, UserProfile, UserSecrets, UserStatus, UserSummary,
+    },
+};
+use axum::response::IntoResponse;
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Names of the columns and fields secrets are kept in
+const SECRET_KEYS: [&str; 10] = [
+    "password",
+    "private_key",
+    "password_hash",
+    "token_hash",
+    "code_hash",
+    "totp_secret",
+    "state_hash",
+    "verifier",
+    "nonce",
+    "client_secret",
+];
+
+const PASSWORD_HASH: &str = "$2b$12$secretsecretsecretsecretsecretsecretsecretsecretsecre";
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+/// Every key of `value` and the objects nested in it
+fn keys(value: &Value, found: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                found.push(key.clone());
+                keys(value, found);
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|v| keys(v, found)),
+        _ => {}
+    }
+}
+
+fn assert_no_secrets(name: &str, value: Value) {
+    let mut found = vec![];
+    keys(&value, &mut found);
+    for key in found {
+        assert!(
+            !SECRET_KEYS.contains(&key.as_str()),
+            "{name} has the secret field {key}: {value}"
+        );
+    }
+}
+
+fn check<T: Serialize>(name: &str, response: &T) {
+    assert_no_secrets(name, serde_json::to_value(response).unwrap());
+}
+
+fn user() -> User {
+    User {
+        profile: UserProfile {
+            username: "someone".to_owned(),
+            email: "someone@example.com".to_owned(),
+            id: Uuid::new_v4(),
+            created_at: now(),
+            owned_products: vec![product()],
+            points: 100,
+            is_superuser: false,
+            orders: vec![order()],
+            address: Some("1 Street".to_owned()),
+        },
+        secrets: UserSecrets {
+            password: PASSWORD_HASH.to_owned(),
+            private_key: Uuid::new_v4(),
+        },
+    }
+}
+
+fn product() -> Product {
+    Product {
+        id: Uuid::new_v4(),
+        box_id: Uuid::new_v4(),
+        title: "Figure".to_owned(),
+        description: "A figure".to_owned(),
+        level: 0,
+        status: true,
+        created_at: now(),
+        amount: 1,
+        available: 1,
+        image: "figure.png".to_owned(),
+        ini_amount: 1,
+        price: Some(10),
+    }
+}
+
+fn order() -> Order {
+    Order {
+        id: Uuid::new_v4(),
+        user_id: Uuid::new_v4(),
+        product_id: Uuid::new_v4(),
+        created_at: now(),
+        status: "pending".to_owned(),
+        product_name: "Figure".to_owned(),
+    }
+}
+
+fn tag() -> Tag {
+    Tag {
+        id: Uuid::new_v4(),
+        name: "Franchise".to_owned(),
+        slug: "franchise".to_owned(),
+        kind: "franchise".to_owned(),
+        created_at: now(),
+    }
+}
+
+fn listing() -> Listing {
+    Listing {
+        image: "listing.png".to_owned(),
+        boxes: vec![a_box()],
+        id: Uuid::new_v4(),
+        title: "Listing".to_owned(),
+        description: "A listing".to_owned(),
+        category_id: Some(Uuid::new_v4()),
+        created_at: now(),
+        box_count: 1,
+        tty: "ICH".to_owned(),
+        status: "live".to_owned(),
+        tags: vec![tag()],
+    }
+}
+
+fn a_box() -> Box {
+    Box {
+        id: Uuid::new_v4(),
+        price: 10,
+        original_price: 12,
+        listing_id: Uuid::new_v4(),
+        created_at: now(),
+        products: vec![product()],
+        total: 1,
+        available_products: 1,
+        claim_seconds: Some(60),
+    }
+}
+
+fn listing_summary() -> ListingSummary {
+    ListingSummary {
+        id: Uuid::new_v4(),
+        title: "Listing".to_owned(),
+        image: "listing.png".to_owned(),
+        tty: "ICH".to_owned(),
+        category_id: None,
+        created_at: now(),
+        status: "live".to_owned(),
+        box_count: 1,
+        min_price: Some(10),
+        max_price: Some(10),
+        total_tickets: 1,
+        remaining_tickets: 1,
+        top_prize_image: Some("figure.png".to_owned()),
+    }
+}
+
+fn category() -> Category {
+    Category {
+        id: Uuid::new_v4(),
+        name: "Figures".to_owned(),
+        created_at: now(),
+        parent_id: None,
+        slug: "figures".to_owned(),
+        position: 0,
+    }
+}
+
+fn role() -> Role {
+    Role {
+        id: Uuid::new_v4(),
+        name: "support".to_owned(),
+        permissions: vec![Permission::ViewUsers],
+        require_two_factor: true,
+        created_at: now(),
+    }
+}
+
+fn user_summary() -> UserSummary {
+    UserSummary {
+        id: Uuid::new_v4(),
+        username: "someone".to_owned(),
+        email: "someone@example.com".to_owned(),
+        email_verified: true,
+        created_at: now(),
+        points: 100,
+        address: None,
+        status: UserStatus::Suspended,
+        suspended_until: Some(now()),
+        status_reason: Some("Chargebacks".to_owned()),
+    }
+}
+
+fn access_token() -> AccessToken {
+    AccessToken {
+        id: Uuid::new_v4(),
+        user_id: Uuid::new_v4(),
+        name: "ci".to_owned(),
+        scopes: vec![Permission::ViewUsers],
+        prefix: "ichi_abcd".to_owned(),
+        expires_at: now(),
+        last_used_at: Some(now()),
+        created_at: now(),
+    }
+}
+
+fn box_stock() -> BoxStock {
+    BoxStock {
+        listing_id: Uuid::new_v4(),
+        box_id: Uuid::new_v4(),
+        remaining: 1,
+        total: 2,
+        tiers: vec![TierStock {
+            level: 0,
+            remaining: 1,
+            total: 2,
+        }],
+    }
+}
+
+fn purchase_limit() -> PurchaseLimit {
+    PurchaseLimit {
+        id: Uuid::new_v4(),
+        listing_id: Some(Uuid::new_v4()),
+        box_id: None,
+        max_per_box: Some(5),
+        max_per_day: Some(2),
+        cooldown_seconds: Some(30),
+        created_at: now(),
+    }
+}
+
+fn box_template() -> BoxTemplate {
+    BoxTemplate {
+        id: Uuid::new_v4(),
+        name: "Template".to_owned(),
+        price: 10,
+        original_price: 12,
+        created_at: now(),
+        products: vec![TemplateProduct {
+            id: Uuid::new_v4(),
+            template_id: Uuid::new_v4(),
+            title: "Figure".to_owned(),
+            description: "A figure".to_owned(),
+            level: 0,
+            amount: 1,
+            image: "figure.png".to_owned(),
+        }],
+    }
+}
+
+#[test]
+fn users_are_sent_without_their_secrets() {
+    let user = user();
+    let secrets = user.secrets.clone();
+    let response: ResponseUser = user.profile.into();
+    let json = serde_json::to_string(&response).unwrap();
+    assert!(!json.contains(PASSWORD_HASH));
+    assert!(!json.contains(&secrets.private_key.to_string()));
+    assert!(!format!("{secrets:?}").contains(PASSWORD_HASH));
+
+    check("ResponseUser", &response);
+    check("Vec<ResponseUser>", &vec![response.clone()]);
+    let event = AuditEvent::UserRegistered;
+    let log = LogData::new(response.id, event, Some(response.id), "Registered".to_owned())
+        .before(&response)
+        .after(&response);
+    assert!(!serde_json::to_string(&log).unwrap().contains(PASSWORD_HASH));
+    check("Page<LogData>", &Page { items: vec![log], next_cursor: None });
+}
+
+#[test]
+fn admin_responses_have_no_secrets() {
+    check("UserSummary", &user_summary());
+    check(
+        "Page<UserSummary>",
+        &Page { items: vec![user_summary()], next_cursor: Some("next".to_owned()) },
+    );
+    check(
+        "UserDetail",
+        &UserDetail {
+            user: user_summary(),
+            roles: vec![role()],
+            orders: vec![order()],
+            prizes: vec![OwnedPrize {
+                id: Uuid::new_v4(),
+                product_id: Uuid::new_v4(),
+                title: "Figure".to_owned(),
+                level: 0,
+                image: "figure.png".to_owned(),
+                bought_at: now(),
+            }],
+            ledger: vec![LedgerEntry::new(Uuid::new_v4(), 10, 110, LedgerReason::Grant)],
+        },
+    );
+    check("Role", &role());
+    check("Vec<Role>", &vec![role()]);
+    check(
+        "ImportReport",
+        &ImportReport {
+            committed: false,
+            listings: 1,
+            boxes: 1,
+            products: 1,
+            errors: vec![RowError {
+                row: 1,
+                field: "price".to_owned(),
+                message: "Missing".to_owned(),
+            }],
+        },
+    );
+}
+
+#[test]
+fn account_security_responses_have_no_stored_secrets() {
+    check("Vec<AccessToken>", &vec![access_token()]);
+    check(
+        "NewAccessToken",
+        &NewAccessToken {
+            token: "ichi_token".to_owned(),
+            access_token: access_token(),
+        },
+    );
+    check(
+        "TwoFactorSetup",
+        &TwoFactorSetup {
+            secret: "JBSWY3DPEHPK3PXP".to_owned(),
+            otpauth_uri: "otpauth://totp/ichibankuji:someone?secret=JBSWY3DPEHPK3PXP".to_owned(),
+        },
+    );
+    check("BackupCodes", &BackupCodes { codes: vec!["abcd-efgh".to_owned()] });
+    check(
+        "ServerStatus",
+        &ServerStatus {
+            status: true,
+            message: "Done".to_owned(),
+        },
+    );
+    check("Vec<String>", &vec!["google".to_owned()]);
+}
+
+#[test]
+fn catalogue_responses_have_no_secrets() {
+    check("Listing", &listing());
+    check("Vec<Listing>", &vec![listing()]);
+    check("Vec<Box>", &vec![a_box()]);
+    check("Product", &product());
+    check("Vec<ListingSummary>", &vec![listing_summary()]);
+    check(
+        "Page<ListingSummary>",
+        &Page { items: vec![listing_summary()], next_cursor: None },
+    );
+    check(
+        "Page<SearchResult>",
+        &Page {
+            items: vec![SearchResult {
+                listing: listing_summary(),
+                rank: 0.5,
+                snippet: "<b>Figure</b>".to_owned(),
+            }],
+            next_cursor: None,
+        },
+    );
+    check("Category", &category());
+    check("Vec<Category>", &vec![category()]);
+    check(
+        "Vec<CategoryNode>",
+        &vec![CategoryNode {
+            id: Uuid::new_v4(),
+            name: "Figures".to_owned(),
+            slug: "figures".to_owned(),
+            position: 0,
+            listing_count: 1,
+            children: vec![],
+        }],
+    );
+    check("Vec<Tag>", &vec![tag()]);
+    check("BoxTemplate", &box_template());
+    check("Vec<BoxTemplate>", &vec![box_template()]);
+    check("PurchaseLimit", &purchase_limit());
+    check("Vec<PurchaseLimit>", &vec![purchase_limit()]);
+    check(
+        "PrizeRemoval",
+        &PrizeRemoval {
+            listing_id: Uuid::new_v4(),
+            title: "Listing".to_owned(),
+            product_ids: vec![Uuid::new_v4()],
+        },
+    );
+    check("ImageLink", &ImageLink { link: "figure.png".to_owned() });
+}
+
+#[test]
+fn draw_responses_have_no_secrets() {
+    check("Order", &order());
+    check("Vec<Order>", &vec![order()]);
+    check(
+        "QueueStatus",
+        &QueueStatus {
+            box_id: Uuid::new_v4(),
+            claim_seconds: 60,
+            length: 2,
+            position: Some(0),
+            claim_expires_at: Some(now()),
+        },
+    );
+    check("BoxEvent::Stock", &BoxEvent::Stock(box_stock()));
+    check(
+        "BoxEvent::Draw",
+        &BoxEvent::Draw {
+            stock: box_stock(),
+            prize: "Figure".to_owned(),
+            level: 0,
+            message: "someone drew Figure".to_owned(),
+            created_at: now(),
+        },
+    );
+}
+
+#[tokio::test]
+async fn error_bodies_have_no_secrets() {
+    let errors = [
+        ApiError::InvalidCredentials,
+        ApiError::InvalidAccessToken,
+        ApiError::TwoFactorRequired,
+        ApiError::OAuthError("Token endpoint failed".to_owned()),
+        ApiError::TooManyAttempts(now()),
+    ];
+    for error in errors {
+        let name = format!("{error:?}");
+        let body = hyper::body::to_bytes(error.into_response().into_body())
+            .await
+            .unwrap();
+        assert_no_secrets(&name, serde_json::from_slice(&body).unwrap());
+    }
+}